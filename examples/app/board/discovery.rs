@@ -0,0 +1,228 @@
+//! # STM32F3 Discovery
+//!
+//! | signal | pin |
+//! | - | - |
+//! | spi (rm3100) | SCK: PC10, MISO: PC11, MOSI: PC12 (SPI3) |
+//! | CS (rm3100) | PA2 |
+//! | DRDY (rm3100) | PA0 (EXTI0) |
+//! | trigger input | PC1 (EXTI1) |
+//! | trigger output | PA1 |
+//! | LED | PE13 (LD10, south red) |
+//! | USB | PA11/PA12 |
+//!
+//! 8MHz HSE from the ST-LINK, sysclk 48MHz
+//!
+//! with feature `low-power` the SPI3 clock is gated between transfers,
+//! with feature `spi-dma` SPI3 is served by DMA2 (channel 1 RX, channel 2 TX)
+use cortex_m::asm;
+use stm32f3xx_hal::{
+    gpio::{
+        gpioa::{PA0, PA1, PA2, PA11, PA12},
+        gpioc::{PC1, PC10, PC11, PC12},
+        gpioe::PE13,
+        Alternate, Input, Output, PushPull,
+    },
+    pac::{Peripherals, SPI3, TIM2, TIM3},
+    prelude::*,
+    rcc::{BusTimerClock, Enable, Reset},
+    spi::Spi,
+    usb::Peripheral,
+};
+use rm3100::power::Mcu;
+use super::{Board, Parts};
+#[cfg(feature = "low-power")]
+use super::GatedSpi;
+#[cfg(feature = "spi-dma")]
+use {super::SpiDma, stm32f3xx_hal::pac::DMA2};
+
+type AF6 = Alternate<PushPull, 6>;
+type AF14 = Alternate<PushPull, 14>;
+type Spi3 = Spi<SPI3, (PC10<AF6>, PC11<AF6>, PC12<AF6>), u8>;
+
+/// switch the SPI3 bus clock
+///
+/// APB1ENR is not written elsewhere after init, SPI users hold the sensor lock
+#[cfg(feature = "low-power")]
+fn spi3_clock(on: bool) {
+    let rcc = unsafe {&*stm32f3xx_hal::pac::RCC::ptr()};
+    rcc.apb1enr.modify(|_, w| w.spi3en().bit(on));
+}
+
+/// SPI3 over DMA2, interrupt on RX complete or error
+#[cfg(feature = "spi-dma")]
+pub struct Spi3Dma {
+    dma: DMA2,
+}
+
+#[cfg(feature = "spi-dma")]
+impl SpiDma for Spi3Dma {
+    unsafe fn start(&mut self, buffer: &mut [u8]) {
+        #[cfg(feature = "low-power")]
+        spi3_clock(true);
+        let spi = &*SPI3::ptr();
+        let dr = &spi.dr as *const _ as u32;
+        let address = buffer.as_mut_ptr() as u32;
+        let len = buffer.len() as u16;
+        // RX first so no byte is missed, TX requests start clocking
+        spi.cr2.modify(|_, w| w.rxdmaen().set_bit());
+        let rx = &self.dma.ch1;
+        rx.par.write(|w| w.pa().bits(dr));
+        rx.mar.write(|w| w.ma().bits(address));
+        rx.ndtr.write(|w| w.ndt().bits(len));
+        rx.cr.write(|w| w
+            .dir().from_peripheral().minc().enabled()
+            .psize().bits8().msize().bits8().pl().high()
+            .tcie().enabled().teie().enabled()
+            .en().enabled());
+        let tx = &self.dma.ch2;
+        tx.par.write(|w| w.pa().bits(dr));
+        tx.mar.write(|w| w.ma().bits(address));
+        tx.ndtr.write(|w| w.ndt().bits(len));
+        tx.cr.write(|w| w
+            .dir().from_memory().minc().enabled()
+            .psize().bits8().msize().bits8().pl().medium()
+            .en().enabled());
+        spi.cr2.modify(|_, w| w.txdmaen().set_bit());
+    }
+
+    fn is_done(&self) -> bool {
+        let isr = self.dma.isr.read();
+        isr.tcif1().is_complete() || isr.teif1().is_error() || isr.teif2().is_error()
+    }
+
+    fn finish(&mut self) -> bool {
+        let isr = self.dma.isr.read();
+        let ok = isr.tcif1().is_complete() && !isr.teif1().is_error() && !isr.teif2().is_error();
+        self.dma.ch1.cr.modify(|_, w| w.en().disabled());
+        self.dma.ch2.cr.modify(|_, w| w.en().disabled());
+        self.dma.ifcr.write(|w| w.cgif1().clear().cgif2().clear());
+        let spi = unsafe {&*SPI3::ptr()};
+        spi.cr2.modify(|_, w| w.rxdmaen().clear_bit().txdmaen().clear_bit());
+        #[cfg(feature = "low-power")]
+        spi3_clock(false);
+        ok
+    }
+}
+
+pub struct Discovery;
+
+impl Board for Discovery {
+    const TICK_RATE: u32 = 48_000_000;
+    /// rough datasheet figures at 48MHz from flash, the peripherals in use clocked
+    const MCU: Mcu = Mcu {run_ua: 24_000, sleep_ua: 12_000};
+
+    #[cfg(not(feature = "low-power"))]
+    type Spi = Spi3;
+    #[cfg(feature = "low-power")]
+    type Spi = GatedSpi<Spi3>;
+    type Cs = PA2<Output<PushPull>>;
+    type Drdy = PA0<Input>;
+    type TriggerIn = PC1<Input>;
+    type TriggerOut = PA1<Output<PushPull>>;
+    type Led = PE13<Output<PushPull>>;
+    type Usb = Peripheral<PA11<AF14>, PA12<AF14>>;
+    #[cfg(feature = "spi-dma")]
+    type Dma = Spi3Dma;
+
+    fn init(dp: Peripherals) -> Parts<Self> {
+        let mut flash = dp.FLASH.constrain();
+        let mut rcc = dp.RCC.constrain();
+        let mut syscfg = dp.SYSCFG.constrain(&mut rcc.apb2);
+        let mut gpioc = dp.GPIOC.split(&mut rcc.ahb);
+        let mut gpioa = dp.GPIOA.split(&mut rcc.ahb);
+        let mut gpioe = dp.GPIOE.split(&mut rcc.ahb);
+        let clocks = rcc
+            .cfgr
+            .use_hse(8.MHz())
+            .sysclk(48.MHz())
+            .pclk1(24.MHz())
+            .pclk2(24.MHz())
+            .freeze(&mut flash.acr);
+        assert!(clocks.usbclk_valid());
+        assert_eq!(clocks.sysclk().0, Self::TICK_RATE);
+
+        // config spi
+        let sck = gpioc
+            .pc10
+            .into_af_push_pull(&mut gpioc.moder, &mut gpioc.otyper, &mut gpioc.afrh);
+        let miso = gpioc
+            .pc11
+            .into_af_push_pull(&mut gpioc.moder, &mut gpioc.otyper, &mut gpioc.afrh);
+        let mosi = gpioc
+            .pc12
+            .into_af_push_pull(&mut gpioc.moder, &mut gpioc.otyper, &mut gpioc.afrh);
+        let mut cs = gpioa
+            .pa2
+            .into_push_pull_output(&mut gpioa.moder, &mut gpioa.otyper);
+        cs.set_high().ok();
+        let spi = Spi::new(dp.SPI3, (sck, miso, mosi), 1.MHz(), clocks, &mut rcc.apb1);
+        #[cfg(feature = "low-power")]
+        let spi = GatedSpi::new(spi, spi3_clock);
+
+        // DRDY(PA0) as EXTI0, trigger input(PC1) as EXTI1
+        let drdy = gpioa
+            .pa0
+            .into_pull_down_input(&mut gpioa.moder, &mut gpioa.pupdr);
+        syscfg.select_exti_interrupt_source(&drdy);
+        let trigger_input = gpioc
+            .pc1
+            .into_pull_down_input(&mut gpioc.moder, &mut gpioc.pupdr);
+        syscfg.select_exti_interrupt_source(&trigger_input);
+
+        let mut trigger_output = gpioa
+            .pa1
+            .into_push_pull_output(&mut gpioa.moder, &mut gpioa.otyper);
+        trigger_output.set_low().ok();
+
+        let mut led = gpioe
+            .pe13
+            .into_push_pull_output(&mut gpioe.moder, &mut gpioe.otyper);
+        led.set_low().ok(); // Turn off
+
+        <TIM2 as Enable>::enable(&mut rcc.apb1);
+        <TIM2 as Reset>::reset(&mut rcc.apb1);
+        <TIM3 as Enable>::enable(&mut rcc.apb1);
+        <TIM3 as Reset>::reset(&mut rcc.apb1);
+        #[cfg(feature = "spi-dma")]
+        <DMA2 as Enable>::enable(&mut rcc.ahb);
+
+        // F3 Discovery board has a pull-up resistor on the D+ line.
+        // Pull the D+ pin down to send a RESET condition to the USB bus.
+        // This forced reset is needed only for development, without it host
+        // will not reset your device when you upload new firmware.
+        let mut usb_dp = gpioa
+            .pa12
+            .into_push_pull_output(&mut gpioa.moder, &mut gpioa.otyper);
+        usb_dp.set_low().ok();
+        asm::delay(clocks.sysclk().0 / 100);
+
+        let usb_dm = gpioa
+            .pa11
+            .into_af_push_pull(&mut gpioa.moder, &mut gpioa.otyper, &mut gpioa.afrh);
+        let usb_dp = usb_dp.into_af_push_pull(&mut gpioa.moder, &mut gpioa.otyper, &mut gpioa.afrh);
+        let usb = Peripheral {
+            usb: dp.USB,
+            pin_dm: usb_dm,
+            pin_dp: usb_dp,
+        };
+
+        Parts {
+            spi,
+            cs,
+            drdy,
+            trigger_input,
+            trigger_output,
+            led,
+            usb,
+            exti: dp.EXTI,
+            tim2: dp.TIM2,
+            tim2_clock: <TIM2 as BusTimerClock>::timer_clock(&clocks).0,
+            tim3: dp.TIM3,
+            tim3_clock: <TIM3 as BusTimerClock>::timer_clock(&clocks).0,
+            iwdg: dp.IWDG,
+            dbgmcu: dp.DBGMCU,
+            #[cfg(feature = "spi-dma")]
+            dma: Spi3Dma {dma: dp.DMA2},
+        }
+    }
+}
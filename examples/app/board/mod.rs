@@ -0,0 +1,137 @@
+//! # board support
+//!
+//! everything that depends on the board (pins, clocks, USB quirks) lives in
+//! one module implementing `Board`, the app only sees `Parts`.
+//!
+//! to add a board: write a module like `discovery`, implement `Board` and
+//! point `Current` at it. The app binds EXTI0 to DRDY and EXTI1 to the trigger
+//! input, so they must sit on pin 0 and pin 1 of some port; TIM2, TIM3 and the
+//! USB interrupts are the same on every STM32F3. With feature `spi-dma` it
+//! binds DMA2_CH1 to the end of `SpiDma` transfers (SPI3 RX on STM32F3)
+use embedded_hal::{
+    blocking::spi::{Transfer, Write},
+    digital::v2::{InputPin, OutputPin},
+};
+use rm3100::power::Mcu;
+use stm32f3xx_hal::pac::{Peripherals, DBGMCU, EXTI, IWDG, TIM2, TIM3};
+
+mod discovery;
+
+/// board the app is built for
+pub type Current = discovery::Discovery;
+
+pub trait Board: Sized {
+    /// sysclk(Hz), also the rate of DWT timestamps
+    const TICK_RATE: u32;
+    /// MCU currents at that clock, for `rm3100::power` estimates
+    const MCU: Mcu;
+
+    /// SPI connected to the RM3100, `GatedSpi` with feature `low-power`
+    type Spi;
+    /// RM3100 chip select
+    type Cs: OutputPin;
+    /// RM3100 DRDY, on pin 0 (EXTI0)
+    type Drdy;
+    /// trigger input, on pin 1 (EXTI1)
+    type TriggerIn: InputPin;
+    type TriggerOut: OutputPin;
+    /// status LED
+    type Led: OutputPin;
+    /// usb peripheral with its pins, for `UsbBus::new`
+    type Usb;
+    /// DMA on `Spi`
+    #[cfg(feature = "spi-dma")]
+    type Dma: SpiDma;
+
+    /// ## set up clocks and pins
+    ///
+    /// called once from init, interrupts are still disabled
+    fn init(device: Peripherals) -> Parts<Self>;
+}
+
+/// peripherals handed to the app, configured but idle
+pub struct Parts<B: Board> {
+    pub spi: B::Spi,
+    /// high
+    pub cs: B::Cs,
+    /// EXTI source selected, edge and enable left to the app
+    pub drdy: B::Drdy,
+    /// EXTI source selected, edge and enable left to the app
+    pub trigger_input: B::TriggerIn,
+    /// low
+    pub trigger_output: B::TriggerOut,
+    /// off
+    pub led: B::Led,
+    /// ready for `UsbBus::new`, host already saw a reset
+    pub usb: B::Usb,
+    pub exti: EXTI,
+    /// clock enabled and reset
+    pub tim2: TIM2,
+    /// TIM2 input clock(Hz)
+    pub tim2_clock: u32,
+    /// clock enabled and reset
+    pub tim3: TIM3,
+    /// TIM3 input clock(Hz)
+    pub tim3_clock: u32,
+    pub iwdg: IWDG,
+    pub dbgmcu: DBGMCU,
+    /// channels idle
+    #[cfg(feature = "spi-dma")]
+    pub dma: B::Dma,
+}
+
+/// ## in-place DMA transfers on the sensor SPI
+///
+/// the SPI is owned by the driver, only use it while the driver is not
+#[allow(dead_code)]
+pub trait SpiDma {
+    /// ## start clocking `buffer` out, the reply is written back into it
+    ///
+    /// # Safety
+    /// buffer must stay in place and untouched until `finish`
+    unsafe fn start(&mut self, buffer: &mut [u8]);
+    /// ## transfer complete or failed
+    fn is_done(&self) -> bool;
+    /// ## stop the channels and clear flags, false if the transfer failed
+    fn finish(&mut self) -> bool;
+}
+
+/// ## SPI with its peripheral clock only on during transfers
+///
+/// `clock` switches the bus clock of the peripheral, transfers are blocking
+/// so the last byte is in before the clock stops
+#[allow(dead_code)]
+pub struct GatedSpi<S> {
+    spi: S,
+    clock: fn(bool),
+}
+
+#[allow(dead_code)]
+impl<S> GatedSpi<S> {
+    pub fn new(spi: S, clock: fn(bool)) -> Self {
+        clock(false);
+        GatedSpi {spi, clock}
+    }
+}
+
+impl<S: Transfer<u8>> Transfer<u8> for GatedSpi<S> {
+    type Error = S::Error;
+
+    fn transfer<'w>(&mut self, words: &'w mut [u8]) -> Result<&'w [u8], Self::Error> {
+        (self.clock)(true);
+        let result = self.spi.transfer(words);
+        (self.clock)(false);
+        result
+    }
+}
+
+impl<S: Write<u8>> Write<u8> for GatedSpi<S> {
+    type Error = S::Error;
+
+    fn write(&mut self, words: &[u8]) -> Result<(), Self::Error> {
+        (self.clock)(true);
+        let result = self.spi.write(words);
+        (self.clock)(false);
+        result
+    }
+}
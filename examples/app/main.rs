@@ -0,0 +1,803 @@
+/*
+    # RM3100 Embedded Server
+
+    ## Notes
+    1. Trigger is responded in approximately 5us
+    2. one measure takes approximately 2.5ms (cc = 200)
+
+    ## Board
+    pins, clocks and USB setup come from `board::Current` (STM32F3 Discovery,
+    see board/discovery.rs for its pin map)
+    ### DRDY (rm3100): bind EXTI0, rise
+    ### trigger output: mode set by output config, pulse width timed by TIM3
+    ### trigger input: bind EXTI1, edge set by trigger config, default rise
+
+    ## Internal trigger
+    TIM2 update interrupt starts single measurements at a host requested rate,
+    refused if faster than one measurement (estimated from cycle count and axes)
+
+    ## Timestamps
+    trigger and DRDY edges are stamped with the DWT cycle counter
+    (sysclk, 48MHz on the discovery board, wraps every ~89s) at interrupt entry,
+    uptime is counted by SysTick in 100ms steps
+
+    ## Statistics
+    samples read, samples dropped (buffer full, newest sample is dropped),
+    trigger input edges and timer expiries, triggers ignored while measuring,
+    times the host stopped taking data (or a reply was dropped), min/max trigger to DRDY latency
+    of single measurements (ticks)
+
+    ## Health
+    every second the sensor is checked (REVID, config readback, DRDY overdue
+    after a single measurement), a new fault re-initializes it once with the last
+    written config and drops the running burst; samples read while faulty are
+    discarded. The independent watchdog (5 SysTick periods) is fed from SysTick only while
+    the USB task still runs, so a hang at any priority resets the MCU
+
+    ## Low power
+    feature `low-power` is for battery deployments: from power-up the sensor
+    measures x/y/z continuously at `LOW_POWER_RATE` into a larger buffer that is
+    kept until a host connects and reads or streams it, the SPI clock is gated
+    between transfers (see `board::GatedSpi`) and SysTick runs slower.
+    the core sleeps in WFI between interrupts; Stop mode is not used, it would
+    stop the PLL behind USB and timestamps as well as SysTick.
+    GET_POWER (console `power`) estimates the average current of the running
+    configuration, see `rm3100::power`
+
+    ## DMA reads
+    with feature `spi-dma` DRDY only stamps the tick, selects the sensor and
+    starts a 10 byte DMA transfer of MX..MZ, the DMA complete interrupt decodes
+    the packet and stores the sample, so DRDY returns in a few us instead of
+    blocking for the whole transfer. Any other sensor access first waits for a
+    running transfer (see `DmaSensor`)
+
+    ## Server
+    request handling, sampling bookkeeping (buffer, stats, health, trigger and
+    output state) and the TX queue are `rm3100::server`, shared with the host
+    emulator; the tasks below add the timers, pins and USB around it.
+    samples pass the filter chosen by SET_FILTER (see `rm3100::filter`, none
+    by default) before the buffer, a decimating filter buffers only its
+    outputs; it starts over when config or axes change
+
+    ## Priorities
+    sampling tasks (DRDY, trigger input, timer, output pulse, uptime) run at priority 2,
+    USB is serviced at priority 1 from USB_LP (USB_HP forwards to it),
+    idle sleeps in WFI
+
+    ## USB transmission
+    replies and stream frames are queued whole in a TX queue, drained whenever
+    the host takes data, nothing blocks on the host.
+    requests are only read while the queue has room for a reply, a host that
+    stops reading sees its requests NAKed.
+    samples not yet streamed when the host stops reading are handled by `TX_POLICY`.
+    disconnect or DTR drop clears the queue, stops streaming and drops partial requests
+
+    ## Text console
+    the line `console`, sent between frames, switches to a text console for terminals (see `rm3100::console`),
+    `binary` or any frame sync byte switches back, so does a disconnect
+
+    ## USB interface
+    CDC-ACM by default, vendor class bulk interface with feature `vendor-usb`
+    (see `rm3100::vendor`), same protocol on both
+    ### Endpoints:
+    - write address: 0x2 (vendor-usb: 0x1)
+    - read address: 0x82 (vendor-usb: 0x81)
+    ### protocal:
+    framed, see `rm3100::protocol` for frame layout
+    command                 request         response
+    0x01 GET_INFO           -               version(1), firmware major/minor/patch(3),
+                                            tick rate(u32, Hz), revid(1), buffer size(u16),
+                                            uptime(u64, ms), config(8), axes bitmask(1)
+    0x02 GET_CONFIG         -               config(8)
+    0x03 SET_CONFIG         config(8)       applied config(8)
+    0x04 GET_AXES           -               axes bitmask(1)
+    0x05 SET_AXES           axes bitmask(1) axes bitmask(1)
+    0x06 READ_SAMPLES       max count(1)    count(1), count * sample(20)
+    0x07 GET_OVERFLOW       -               0 if not overflow(1)
+    0x08 CLEAR_OVERFLOW     -               -
+    0x09 CLEAR_BUFFER       -               -
+    0x0A START_STREAM       -               -
+    0x0B STOP_STREAM        -               -
+    0x0C GET_TRIGGER        -               trigger config(5), armed(1)
+    0x0D SET_TRIGGER        trigger config(5) trigger config(5), armed(1)
+    0x0E ARM_TRIGGER        -               -
+    0x0F DISARM_TRIGGER     -               -
+    0x10 START_TIMER        rate(u32, mHz)  actual rate(u32, mHz)
+    0x11 STOP_TIMER         -               -
+    0x12 GET_TIMER          -               rate(u32, mHz), 0 if stopped
+    0x13 GET_OUTPUT         -               output config(5)
+    0x14 SET_OUTPUT         output config(5) output config(5)
+    0x15 PULSE_OUTPUT       -               - (software mode only)
+    0x16 GET_STATS          -               samples, dropped, triggers, ignored, usb stalls,
+                                            latency min, latency max(u32 each)
+    0x17 CLEAR_STATS        -               -
+    0x18 GET_HEALTH         -               fault(1, 0 none/1 disconnected/2 config mismatch/
+                                            3 drdy timeout), faults(u32), recoveries(u32),
+                                            watchdog reset(1)
+    0x19 GET_POWER          -               sensor, mcu(u32 each, uA), average current estimate
+    0x1B READ_REGISTERS     address(1), count(1) count register values, reading
+                                            the measurement registers clears DRDY
+    0x1C GET_FILTER         -               filter config(21)
+    0x1D SET_FILTER         filter config(21) filter config(21), the filter starts over
+    0x40 STREAM_DATA        (device pushes) sequence(u32), count(1), count * sample(20)
+    sample: mag x/y/z(i32 each), trigger tick(u32), drdy tick(u32)
+    trigger config: edge(1, 0 rise/1 fall/2 both), measurements per trigger(1),
+        accept every Kth edge(u16), gate(1, continuous while input high)
+    output config: mode(1, 0 high while measuring/1 pulse every Nth sample/2 software),
+        N(u16), pulse width(u16, us)
+    config: ccx, ccy, ccz(u16 each), TMRC(1), CMM DRDM bits(1)
+    filter config: kind(1, 0 none/1 moving average/2 EMA/3 biquad/4 median/5 CIC),
+        parameters(20, see `rm3100::protocol::FILTER_LEN`)
+    errors are reported in status with empty payload
+
+*/
+// #![deny(unsafe_code)]
+#![deny(warnings)]
+#![no_main]
+#![no_std]
+
+use panic_halt as _;
+
+mod board;
+
+#[rtic::app(device = stm32f3xx_hal::pac)]
+mod app {
+    use stm32f3xx_hal::{
+        // self as hal,
+        gpio::Edge,
+        usb::UsbBus,
+        prelude::*,
+        pac::{self, Interrupt, EXTI, TIM2, TIM3},
+        time::duration::Milliseconds,
+        watchdog::IndependentWatchDog,
+    };
+    use embedded_hal::watchdog::{Watchdog, WatchdogEnable};
+    use crate::board::{self, Board};
+    use usb_device::{prelude::*, class_prelude::UsbBusAllocator, UsbError};
+    #[cfg(not(feature = "vendor-usb"))]
+    use usbd_serial::{SerialPort, USB_CLASS_CDC};
+    #[cfg(feature = "vendor-usb")]
+    use rm3100::vendor::VendorClass;
+    use cortex_m::{asm, peripheral::{syst::SystClkSource, DWT}};
+    use rm3100::protocol::{self, FrameDecoder};
+    use rm3100::server::{self, Hardware, Server, Settings, Stream, TxPolicy, TxQueue};
+    use rm3100::trigger::{self, Level, TriggerConfig};
+    use rm3100::console::{self, ConsoleCommand, LineBuffer};
+    use rtic::mutex::prelude::*;
+    use core::fmt::Write;
+    #[cfg(feature = "spi-dma")]
+    use {crate::board::SpiDma, rm3100::packet::Packet, core::ops::{Deref, DerefMut}};
+
+    #[cfg(not(feature = "low-power"))]
+    const BUFFER_SIZE: usize = server::BUFFER_SIZE;
+    #[cfg(feature = "low-power")]
+    const BUFFER_SIZE: usize = server::LOW_POWER_BUFFER_SIZE;
+    /// sysclk, also the rate of DWT cycle counter used for timestamps
+    const TICK_RATE: u32 = <board::Current as Board>::TICK_RATE;
+    /// SysTick period(ms) for uptime
+    #[cfg(not(feature = "low-power"))]
+    const UPTIME_STEP_MS: u32 = server::UPTIME_STEP_MS;
+    /// fewer wake-ups, 24 bit SysTick reload still fits
+    #[cfg(feature = "low-power")]
+    const UPTIME_STEP_MS: u32 = server::LOW_POWER_UPTIME_STEP_MS;
+    /// a few SysTick periods
+    const WATCHDOG_MS: u32 = 5 * UPTIME_STEP_MS;
+    /// longest sample line printed by the console
+    const SAMPLE_TEXT_LEN: usize = 80;
+    const TX_POLICY: TxPolicy = TxPolicy::Retain;
+
+    type SPI = <board::Current as Board>::Spi;
+    type CS = <board::Current as Board>::Cs;
+    type DRDY = <board::Current as Board>::Drdy;
+    type TRIIN = <board::Current as Board>::TriggerIn;
+    type TRIOUT = <board::Current as Board>::TriggerOut;
+    #[cfg(not(feature = "spi-dma"))]
+    type SENSOR = rm3100::RM3100<SPI, CS>;
+    #[cfg(feature = "spi-dma")]
+    type SENSOR = DmaSensor;
+    #[cfg(feature = "spi-dma")]
+    type DMA = <board::Current as Board>::Dma;
+    type LED = <board::Current as Board>::Led;
+    type USBPERIPHERAL = <board::Current as Board>::Usb;
+    type USBBUS = UsbBus<USBPERIPHERAL>;
+    type USBBUSALLOCATOR = UsbBusAllocator<USBBUS>;
+    #[cfg(not(feature = "vendor-usb"))]
+    type SERIAL<'a> = SerialPort<'a, USBBUS>;
+    #[cfg(feature = "vendor-usb")]
+    type SERIAL<'a> = VendorClass<'a, USBBUS>;
+    type USBDEV<'a> = UsbDevice<'a, USBBUS>;
+    type SERVER = Server<BUFFER_SIZE>;
+
+    /// internal trigger source: TIM2 update interrupt
+    /// 
+    /// TIM2 is 32 bit, so no prescaler is needed
+    pub struct PeriodicTimer {
+        tim: TIM2,
+        /// timer input clock(Hz)
+        clock: u32,
+        /// rate in mHz, 0 if stopped
+        rate: u32,
+    }
+
+    impl PeriodicTimer {
+        /// start at rate(mHz), return actual rate(mHz)
+        /// 
+        /// period is rounded to whole timer clock cycles,
+        /// None if it does not fit in 32 bit
+        fn start(&mut self, rate: u32) -> Option<u32> {
+            let clock_mhz = self.clock as u64 * 1000;
+            let period = (clock_mhz + rate as u64 / 2) / rate as u64;
+            let period = u32::try_from(period).ok().filter(|period| *period > 0)?;
+            self.stop();
+            self.tim.psc.write(|w| w.psc().bits(0));
+            self.tim.arr.write(|w| w.arr().bits(period - 1));
+            // load registers and restart count, drop the update flag it raises
+            self.tim.egr.write(|w| w.ug().update());
+            self.tim.sr.modify(|_, w| w.uif().clear());
+            self.tim.dier.modify(|_, w| w.uie().enabled());
+            self.tim.cr1.modify(|_, w| w.cen().enabled());
+            self.rate = ((clock_mhz + period as u64 / 2) / period as u64) as u32;
+            Some(self.rate)
+        }
+
+        fn stop(&mut self) {
+            self.tim.cr1.modify(|_, w| w.cen().disabled());
+            self.tim.dier.modify(|_, w| w.uie().disabled());
+            self.rate = 0;
+        }
+
+        fn clear_interrupt(&mut self) {
+            self.tim.sr.modify(|_, w| w.uif().clear());
+        }
+    }
+
+    /// trigger output pin, pulse width timed by TIM3 in one pulse mode
+    pub struct TriggerOutput {
+        pin: TRIOUT,
+        /// counts 1us ticks
+        tim: TIM3,
+    }
+
+    impl TriggerOutput {
+        fn drive(&mut self, level: Level, width_us: u16) {
+            match level {
+                Level::Keep => {},
+                Level::High => {self.pin.set_high().ok();},
+                Level::Low => {self.pin.set_low().ok();},
+                Level::Pulse => {
+                    // count 1..=width, update after width ticks ends the pulse
+                    self.pin.set_high().ok();
+                    self.tim.arr.write(|w| w.arr().bits(width_us));
+                    self.tim.cnt.write(|w| w.cnt().bits(1));
+                    self.tim.cr1.modify(|_, w| w.cen().enabled());
+                },
+            }
+        }
+
+        /// TIM3 update, pulse over
+        fn end_pulse(&mut self) {
+            self.tim.sr.modify(|_, w| w.uif().clear());
+            self.pin.set_low().ok();
+        }
+    }
+
+    /// timers and trigger pins driven by the server
+    pub struct Io {
+        exti: EXTI,
+        trigger_input: TRIIN,
+        timer: PeriodicTimer,
+        trigger_output: TriggerOutput,
+    }
+
+    impl Hardware for Io {
+        fn now(&mut self) -> u32 {DWT::cycle_count()}
+
+        fn drive(&mut self, level: Level, width_us: u16) {
+            self.trigger_output.drive(level, width_us);
+        }
+
+        fn start_timer(&mut self, rate: u32) -> Option<u32> {self.timer.start(rate)}
+
+        fn stop_timer(&mut self) {
+            self.timer.stop();
+        }
+
+        fn timer_rate(&self) -> u32 {self.timer.rate}
+
+        fn set_input_edge(&mut self, edge: trigger::Edge) {
+            self.trigger_input.trigger_on_edge(&mut self.exti, input_edge(edge));
+        }
+    }
+
+    /// sensor whose measurement registers are read by DMA
+    ///
+    /// a read runs from `start_read` to `finish_read`, any other access
+    /// (through `DerefMut`) waits for the transfer and keeps its result
+    #[cfg(feature = "spi-dma")]
+    pub struct DmaSensor {
+        sensor: rm3100::RM3100<SPI, CS>,
+        dma: DMA,
+        /// clocked in place, must not move while a read runs
+        packet: Packet<10>,
+        /// drdy tick of the running read
+        reading: Option<u32>,
+        /// x/y/z and drdy tick of a finished read
+        done: Option<([i32; 3], u32)>,
+    }
+
+    #[cfg(feature = "spi-dma")]
+    impl DmaSensor {
+        fn new(sensor: rm3100::RM3100<SPI, CS>, dma: DMA) -> Self {
+            DmaSensor {sensor, dma, packet: Packet::default(), reading: None, done: None}
+        }
+
+        fn start_read(&mut self, drdy_tick: u32) {
+            self.complete();
+            self.packet = self.sensor.begin_read_mag();
+            unsafe {self.dma.start(&mut self.packet.0);}
+            self.reading = Some(drdy_tick);
+        }
+
+        /// x/y/z and drdy tick, None if no read ran or it failed
+        fn finish_read(&mut self) -> Option<([i32; 3], u32)> {
+            self.complete();
+            self.done.take()
+        }
+
+        fn complete(&mut self) {
+            if let Some(drdy_tick) = self.reading.take() {
+                while !self.dma.is_done() {}
+                let ok = self.dma.finish();
+                let mag = self.sensor.end_read_mag(self.packet);
+                self.done = ok.then_some((mag, drdy_tick));
+            }
+        }
+    }
+
+    #[cfg(feature = "spi-dma")]
+    impl Deref for DmaSensor {
+        type Target = rm3100::RM3100<SPI, CS>;
+
+        fn deref(&self) -> &Self::Target {
+            &self.sensor
+        }
+    }
+
+    #[cfg(feature = "spi-dma")]
+    impl DerefMut for DmaSensor {
+        fn deref_mut(&mut self) -> &mut Self::Target {
+            self.complete();
+            &mut self.sensor
+        }
+    }
+
+    #[shared]
+    struct Shared{
+        server: SERVER,
+        sensor: SENSOR,
+        io: Io,
+        /// USB task ran since the watchdog was last fed
+        alive: bool,
+    }
+
+    #[local]
+    struct Local {
+        drdy: DRDY,
+        led: LED,
+        serial: SERIAL<'static>,
+        usb_dev: USBDEV<'static>,
+        decoder: FrameDecoder,
+        stream: Stream,
+        tx: TxQueue,
+        line: LineBuffer,
+        watchdog: IndependentWatchDog,
+    }
+
+    #[init(local = [usb_bus: Option<USBBUSALLOCATOR> = None])]
+    fn init(cx: init::Context) -> (Shared, Local, init::Monotonics) {
+        let parts = <board::Current as Board>::init(cx.device);
+        let mut core = cx.core;
+        let mut exti = parts.exti;
+
+        // reset cause, flags stay set until removed
+        let rcc = unsafe {&*pac::RCC::ptr()};
+        let watchdog_reset = rcc.csr.read().iwdgrstf().bit_is_set();
+        rcc.csr.modify(|_, w| w.rmvf().set_bit());
+        let mut watchdog = IndependentWatchDog::new(parts.iwdg);
+        watchdog.stop_on_debug(&parts.dbgmcu, true);
+        watchdog.start(Milliseconds(WATCHDOG_MS));
+
+        // start cycle counter for timestamps
+        core.DCB.enable_trace();
+        core.DWT.enable_cycle_counter();
+
+        // SysTick counts uptime
+        core.SYST.set_clock_source(SystClkSource::Core);
+        core.SYST.set_reload(TICK_RATE / 1000 * UPTIME_STEP_MS - 1);
+        core.SYST.clear_current();
+        core.SYST.enable_counter();
+        core.SYST.enable_interrupt();
+
+        // config rm3100
+        let mut sensor = rm3100::RM3100::new(parts.spi, parts.cs, rm3100::Config::default());
+        sensor
+            .set_cycle_count(200) 
+            .set_update_rate(rm3100::UpdateRate::Hz600) // max update rate
+            .set_drdm(rm3100::DRDM::Full); // this also set disable continuous mode
+        #[cfg(not(feature = "low-power"))]
+        let axes = rm3100::Axes::X;
+        // sample from power-up, the host may connect much later
+        #[cfg(feature = "low-power")]
+        let axes = {
+            sensor.set_update_rate(server::LOW_POWER_RATE);
+            rm3100::Axes::XYZ
+        };
+        let revid = sensor.read_revid();
+        #[cfg(feature = "spi-dma")]
+        #[allow(unused_mut)]
+        let mut sensor = DmaSensor::new(sensor, parts.dma);
+
+        // config DRDY as EXTI0(rise)
+        let mut drdy: DRDY = parts.drdy;
+        drdy.trigger_on_edge(&mut exti, Edge::Rising);
+        drdy.enable_interrupt(&mut exti);
+
+        // config triger_output, TIM3 one pulse mode with 1us ticks for pulses
+        let pin = parts.trigger_output;
+        let tim = parts.tim3;
+        let psc = parts.tim3_clock / 1_000_000 - 1;
+        tim.psc.write(|w| w.psc().bits(psc as u16));
+        tim.cr1.modify(|_, w| w.opm().enabled());
+        tim.egr.write(|w| w.ug().update());
+        tim.sr.modify(|_, w| w.uif().clear());
+        tim.dier.modify(|_, w| w.uie().enabled());
+        let trigger_output = TriggerOutput {pin, tim};
+
+        let led = parts.led;
+
+        let usb_bus: &'static USBBUSALLOCATOR = cx.local.usb_bus.insert(UsbBus::new(parts.usb));
+
+        #[cfg(not(feature = "vendor-usb"))]
+        let (serial, usb_dev) = {
+            let serial = SerialPort::new(usb_bus);
+            let usb_dev = UsbDeviceBuilder::new(usb_bus, UsbVidPid(0x16c0, 0x27dd))
+                .manufacturer("Fake company")
+                .product("Serial port")
+                .serial_number("TEST")
+                .device_class(USB_CLASS_CDC)
+                .build();
+            (serial, usb_dev)
+        };
+        // shared VID/PID for vendor class devices driven by libusb
+        #[cfg(feature = "vendor-usb")]
+        let (serial, usb_dev) = {
+            let serial = VendorClass::new(usb_bus);
+            let usb_dev = UsbDeviceBuilder::new(usb_bus, UsbVidPid(0x16c0, 0x05dc))
+                .manufacturer("Fake company")
+                .product("RM3100")
+                .serial_number("TEST")
+                .build();
+            (serial, usb_dev)
+        };
+        let decoder = FrameDecoder::new();
+        let stream = Stream::default();
+        let tx = TxQueue::new();
+        let line = LineBuffer::new();
+
+        // config trigger input as EXTI1, edge from default trigger config
+        let mut trigger_input: TRIIN = parts.trigger_input;
+        trigger_input.trigger_on_edge(&mut exti, input_edge(TriggerConfig::default().input_edge()));
+        trigger_input.enable_interrupt(&mut exti);
+
+        // config TIM2 as internal trigger source, stopped until requested
+        let timer = PeriodicTimer {
+            clock: parts.tim2_clock,
+            tim: parts.tim2,
+            rate: 0,
+        };
+        #[allow(unused_mut)]
+        let mut io = Io {exti, trigger_input, timer, trigger_output};
+
+        let settings = Settings {tick_rate: TICK_RATE, uptime_step_ms: UPTIME_STEP_MS, mcu: <board::Current as Board>::MCU};
+        #[allow(unused_mut)]
+        let mut server = SERVER::new(settings, axes, revid, watchdog_reset);
+        // x/y/z continuously at `LOW_POWER_RATE`
+        #[cfg(feature = "low-power")]
+        server.set_continuous(driver(&mut sensor), &mut io, true);
+
+        (Shared {server, sensor, io, alive: false}, Local {drdy, led, serial, usb_dev, decoder, stream, tx, line, watchdog}, init::Monotonics(),)
+    }
+
+    /// everything runs in interrupts
+    #[idle]
+    fn idle(_: idle::Context) -> ! {
+        loop {
+            asm::wfi();
+        }
+    }
+
+    /// answer requests, push samples while streaming
+    /// 
+    /// pended by USB events and by DRDY when a sample is buffered
+    #[task(
+        binds = USB_LP_CAN_RX0,
+        priority = 1,
+        local = [
+            led, serial, usb_dev, decoder, stream, tx, line,
+            connected: bool = false,
+            text: bool = false,
+            pending: usize = 0,
+        ],
+        shared = [server, sensor, io, alive]
+    )]
+    fn usb_lp(mut cx: usb_lp::Context) {
+        // let led = cx.local.led;
+        let serial = cx.local.serial;
+        let usb_dev = cx.local.usb_dev;
+        let decoder = cx.local.decoder;
+        let stream = cx.local.stream;
+        let tx = cx.local.tx;
+        let line = cx.local.line;
+        let text = cx.local.text;
+        // samples the console prints without streaming
+        let pending = cx.local.pending;
+        cx.shared.alive.lock(|_alive| {*_alive = true;});
+        // answered inside poll, without access to resources
+        #[cfg(feature = "vendor-usb")]
+        {
+            let info = (&mut cx.shared.server, &mut cx.shared.sensor)
+                .lock(|_server, _sensor| _server.device_info(driver(_sensor)));
+            serial.set_info(&info);
+        }
+        let polled = usb_dev.poll(&mut [serial]);
+        // host gone: nobody reads queued replies or stream
+        let connected = usb_dev.state() == UsbDeviceState::Configured && serial.dtr();
+        if !connected {
+            if *cx.local.connected {
+                tx.clear();
+                decoder.reset();
+                line.reset();
+                *text = false;
+                *stream = Stream::default();
+                *pending = 0;
+            }
+            *cx.local.connected = false;
+            return;
+        }
+        *cx.local.connected = true;
+        // leave requests to the host until a reply fits
+        if polled && tx.free() >= protocol::MAX_FRAME {
+            let mut buf = [0u8; 64];
+            let count = serial.read(&mut buf).unwrap_or(0);
+            // feed decoder, answer every complete frame
+            for byte in buf[0..count].iter() {
+                // frames start with a non-ascii sync byte
+                if *text && *byte == protocol::SYNC[0] {
+                    *text = false;
+                    line.reset();
+                }
+                if *text {
+                    console::echo(tx, *byte).ok();
+                    if let Some(result) = line.push(*byte) {
+                        match result.and_then(console::parse) {
+                            Ok(ConsoleCommand::Binary) => {*text = false;},
+                            Ok(command) => handle_console(&mut cx.shared, stream, pending, tx, command),
+                            Err(error) => {write!(tx, "error: {}\r\n", error).ok();},
+                        }
+                        if *text {tx.write_str("> ").ok();}
+                    }
+                    continue;
+                }
+                // frame bytes never reach the line, "console" inside a payload or CRC is no command
+                if !decoder.is_idle() || *byte == protocol::SYNC[0] {
+                    line.reset();
+                } else if let Some(Ok(console::ENTER)) = line.push(*byte) {
+                    *text = true;
+                    decoder.reset();
+                    *stream = Stream::default();
+                    *pending = 0;
+                    tx.write_str(console::HELP).ok();
+                    tx.write_str("> ").ok();
+                    continue;
+                }
+                if let Some(frame) = decoder.push(*byte) {
+                    (&mut cx.shared.server, &mut cx.shared.sensor, &mut cx.shared.io).lock(|_server, _sensor, _io| {
+                        _server.request(driver(_sensor), _io, stream, frame, tx);
+                    });
+                }
+            }
+        }
+        // print samples in the console
+        if *text && (stream.active || *pending > 0) {
+            let max = (tx.free() / SAMPLE_TEXT_LEN).min(protocol::MAX_SAMPLES);
+            let max = if stream.active {max} else {max.min(*pending)};
+            let (samples, cc) = (&mut cx.shared.server, &mut cx.shared.sensor)
+                .lock(|_server, _sensor| (_server.pop_samples(max), _sensor.get_cycle_count()));
+            for sample in samples.as_slice() {
+                console::write_sample(tx, sample, cc).ok();
+            }
+            *pending = pending.saturating_sub(samples.as_slice().len());
+        }
+        // push whatever arrived since last frame
+        if !*text {
+            cx.shared.server.lock(|_server| _server.push_stream(stream, tx, TX_POLICY));
+        }
+        let stalled = tx.flush(|data| match serial.write(data) {
+            Ok(len) => Some(len),
+            Err(UsbError::WouldBlock) => Some(0),
+            // not configured, dropped by next disconnect check
+            Err(_) => None,
+        });
+        if stalled {
+            cx.shared.server.lock(|_server| _server.count_stall());
+        }
+    }
+
+    /// only raised for double-buffered bulk/isochronous endpoints,
+    /// handled by the same poll as USB_LP
+    #[task(binds = USB_HP_CAN_TX, priority = 1)]
+    fn usb_hp(_: usb_hp::Context) {
+        rtic::pend(Interrupt::USB_LP_CAN_RX0);
+    }
+
+    /// ## execute one console command
+    /// 
+    /// output that does not fit the TX queue is cut
+    fn handle_console(
+        shared: &mut usb_lp::SharedResources, stream: &mut Stream, pending: &mut usize, tx: &mut TxQueue, command: ConsoleCommand
+    ) {
+        (&mut shared.server, &mut shared.sensor, &mut shared.io).lock(|_server, _sensor, _io| {
+            let sensor = driver(_sensor);
+            let result = match command {
+                ConsoleCommand::Help => tx.write_str(console::HELP),
+                // handled by caller
+                ConsoleCommand::Binary => Ok(()),
+                ConsoleCommand::Info => console::write_info(tx, &_server.device_info(sensor)),
+                ConsoleCommand::Stats => console::write_stats(tx, &_server.stats()),
+                ConsoleCommand::Power => console::write_power(tx, &_server.power_estimate(sensor, _io)),
+                ConsoleCommand::GetCycleCount => console::write_cc(tx, sensor.get_cycle_count()),
+                ConsoleCommand::SetCycleCount(cc) => {
+                    sensor.set_cycle_count_xyz(cc.x, cc.y, cc.z);
+                    _server.reset_filter();
+                    console::write_cc(tx, cc)
+                },
+                ConsoleCommand::GetRate => console::write_rate(tx, sensor.get_update_rate()),
+                ConsoleCommand::SetRate(rate) => {
+                    sensor.set_update_rate(rate);
+                    _server.reset_filter();
+                    console::write_rate(tx, rate)
+                },
+                ConsoleCommand::GetDrdm => console::write_drdm(tx, sensor.get_config().drdm),
+                ConsoleCommand::SetDrdm(drdm) => {
+                    sensor.set_drdm(drdm);
+                    _server.reset_filter();
+                    console::write_drdm(tx, drdm)
+                },
+                ConsoleCommand::GetAxes => console::write_axes(tx, _server.axes()),
+                ConsoleCommand::SetAxes(axes) => {
+                    _server.set_axes(axes);
+                    console::write_axes(tx, axes)
+                },
+                ConsoleCommand::Measure(axes) => {
+                    if let Some(axes) = axes {
+                        _server.set_axes(axes);
+                    }
+                    // same as a timer tick
+                    if _server.measure(sensor, _io) {
+                        *pending += 1;
+                        Ok(())
+                    } else {
+                        tx.write_str("error: busy\r\n")
+                    }
+                },
+                ConsoleCommand::Continuous(on) => {
+                    _server.set_continuous(sensor, _io, on);
+                    tx.write_str("ok\r\n")
+                },
+                ConsoleCommand::Stream(on) => {
+                    stream.active = on;
+                    tx.write_str("ok\r\n")
+                },
+                ConsoleCommand::Read => {
+                    let max = (tx.free() / SAMPLE_TEXT_LEN).min(protocol::MAX_SAMPLES);
+                    let samples = _server.pop_samples(max);
+                    let cc = sensor.get_cycle_count();
+                    samples.as_slice().iter().try_for_each(|sample| console::write_sample(tx, sample, cc))
+                },
+            };
+            // queue full, host reads nothing anyway
+            result.ok();
+        });
+    }
+
+    /// the driver behind SENSOR, a running DMA read is finished first
+    fn driver(sensor: &mut SENSOR) -> &mut rm3100::RM3100<SPI, CS> {
+        sensor
+    }
+
+    fn input_edge(edge: trigger::Edge) -> Edge {
+        match edge {
+            trigger::Edge::Rising => Edge::Rising,
+            trigger::Edge::Falling => Edge::Falling,
+            trigger::Edge::Both => Edge::RisingFalling,
+        }
+    }
+
+    /// DRDY: read the sample, or start reading it by DMA
+    #[task(binds = EXTI0, priority = 2, local = [drdy], shared = [server, sensor, io])]
+    fn read_result(cx: read_result::Context) {
+        let drdy_tick = DWT::cycle_count();
+        (cx.shared.server, cx.shared.sensor, cx.shared.io).lock(|_server, _sensor, _io| {
+            // TEST: delay after drdy trigger EXTI0
+            _server.on_drdy(_io);
+            #[cfg(not(feature = "spi-dma"))]
+            {
+                let mag = _sensor.read_mag();
+                _server.store_sample(_sensor, _io, mag, drdy_tick);
+                // let usb push it if streaming
+                rtic::pend(Interrupt::USB_LP_CAN_RX0);
+            }
+            #[cfg(feature = "spi-dma")]
+            _sensor.start_read(drdy_tick);
+        });
+        // clear EXTI0(drdy)
+        cx.local.drdy.clear_interrupt();
+    }
+
+    /// DMA read of a sample done
+    #[cfg(feature = "spi-dma")]
+    #[task(binds = DMA2_CH1, priority = 2, shared = [server, sensor, io])]
+    fn read_done(cx: read_done::Context) {
+        (cx.shared.server, cx.shared.sensor, cx.shared.io).lock(|_server, _sensor, _io| {
+            // already taken if another sensor access waited for it
+            if let Some((mag, drdy_tick)) = _sensor.finish_read() {
+                _server.store_sample(driver(_sensor), _io, mag, drdy_tick);
+                // let usb push it if streaming
+                rtic::pend(Interrupt::USB_LP_CAN_RX0);
+            }
+        });
+    }
+
+    /// trigger input edge
+    #[task(binds = EXTI1, priority = 2, shared = [server, sensor, io])]
+    fn start_measure(cx: start_measure::Context) {
+        let tick = DWT::cycle_count();
+        (cx.shared.server, cx.shared.sensor, cx.shared.io).lock(|_server, _sensor, _io| {
+            // clear EXTI1(trigger_input) first, edges arriving meanwhile are kept
+            _io.trigger_input.clear_interrupt();
+            let high = _io.trigger_input.is_high().unwrap_or(false);
+            // TEST: delay after trigger input
+            _server.on_edge(driver(_sensor), _io, high, tick);
+        });
+    }
+
+    /// internal trigger
+    #[task(binds = TIM2, priority = 2, shared = [server, sensor, io])]
+    fn timer_measure(cx: timer_measure::Context) {
+        let tick = DWT::cycle_count();
+        (cx.shared.server, cx.shared.sensor, cx.shared.io).lock(|_server, _sensor, _io| {
+            _io.timer.clear_interrupt();
+            _server.on_timer(driver(_sensor), _io, tick);
+        });
+    }
+
+    /// uptime, health checks and watchdog
+    #[task(binds = SysTick, priority = 2, local = [watchdog], shared = [server, sensor, io, alive])]
+    fn systick(mut cx: systick::Context) {
+        // a hung USB task stops feeding
+        if cx.shared.alive.lock(|_alive| core::mem::replace(_alive, false)) {
+            cx.local.watchdog.feed();
+        }
+        rtic::pend(Interrupt::USB_LP_CAN_RX0);
+        (cx.shared.server, cx.shared.sensor, cx.shared.io).lock(|_server, _sensor, _io| {
+            _server.on_systick(driver(_sensor), _io);
+        });
+    }
+
+    /// end of trigger output pulse
+    #[task(binds = TIM3, priority = 2, shared = [io])]
+    fn end_pulse(mut cx: end_pulse::Context) {
+        cx.shared.io.lock(|_io| _io.trigger_output.end_pulse());
+    }
+
+}
@@ -0,0 +1,461 @@
+/*
+    # RM3100 Gradiometer Server
+
+    several RM3100 on one SPI bus, each with its own CS and DRDY, measured
+    together on one trigger input edge. Every trigger starts a single x/y/z
+    measurement on all sensors back to back, the DRDY of each sensor stamps and
+    reads its sample, the frame is complete once all sensors reported
+    (see `rm3100::gradiometer`). An edge arriving before the frame is complete
+    drops it and sets the overflow flag
+
+    ## Board
+    STM32F3 Discovery, 8MHz HSE, sysclk 48MHz
+    ### spi (all sensors): SCK: PC10, MISO: PC11, MOSI: PC12 (SPI3)
+    ### sensor 0: CS PA2, DRDY PA0 (EXTI0, task `drdy0`)
+    ### sensor 1: CS PA3, DRDY PD2 (EXTI2, task `drdy1`)
+    ### trigger input: PC1 (EXTI1), rise
+    two sensors are wired. For more (up to `rm3100::gradiometer::MAX_SENSORS`)
+    raise `SENSORS`, add a CS and a DRDY pin to the arrays in `init` (DRDY on
+    its own EXTI line, e.g. PA4/EXTI4 for sensor 2) and a DRDY task bound to
+    that line, as `drdy0` and `drdy1`
+
+    ## Timestamps
+    trigger and DRDY edges are stamped with the DWT cycle counter (48MHz),
+    uptime is counted by SysTick in 100ms steps
+
+    ## USB
+    CDC-ACM, framed protocol of `rm3100::protocol`. Replies and frames are
+    queued whole in a `rm3100::server::TxQueue`, requests are only read while
+    a reply fits, frames wait in the frame buffer until one fits.
+    disconnect or DTR drop stops streaming, clears the queue and drops partial requests
+    command                 request         response
+    0x01 GET_INFO           -               device info, revid of sensor 0, frame buffer size
+    0x02 GET_CONFIG         -               config(8), same on all sensors
+    0x03 SET_CONFIG         config(8)       applied config(8)
+    0x04 GET_AXES           -               axes bitmask(1), always x/y/z
+    0x07 GET_OVERFLOW       -               0 if not overflow(1)
+    0x08 CLEAR_OVERFLOW     -               -
+    0x09 CLEAR_BUFFER       -               -
+    0x0A START_STREAM       -               -
+    0x0B STOP_STREAM        -               -
+    0x1A SET_GRADIENTS      on(1)           - (off after power-up)
+    0x41 FRAME_DATA         (device pushes) sequence(u32), frame, see `rm3100::protocol::FRAME_SENSOR_LEN`
+    gradients are per-axis differences of neighbouring sensors in nT
+    other commands are answered with status 1 (unknown command): there are no
+    samples, trigger config, timer, output, filter, statistics, health, power
+    or register access. Of the host tool `info`, `config get/set` and
+    `selftest` work, the selftest skips the checks answered with status 1;
+    `read`, `stream`, `filter` and `dump-registers` need the app
+*/
+#![deny(warnings)]
+#![no_main]
+#![no_std]
+
+use panic_halt as _;
+
+#[rtic::app(device = stm32f3xx_hal::pac)]
+mod app {
+    use core::cell::RefCell;
+    use cortex_m::{asm, peripheral::{syst::SystClkSource, DWT}};
+    use embedded_hal::blocking::spi::{Transfer, Write};
+    use rm3100::gradiometer::{Collector, Frame};
+    use rm3100::protocol::{self, Command, DeviceInfo, FrameDecoder, Response, StatusCode};
+    use rm3100::server::{self, TxQueue};
+    use rtic::mutex::prelude::*;
+    use stm32f3xx_hal::{
+        gpio::{
+            gpioa::{PA11, PA12},
+            gpioc::{PC1, PC10, PC11, PC12},
+            Alternate, Edge, Input, Output, PushPull, PXx,
+        },
+        pac::{Interrupt, SPI3},
+        prelude::*,
+        spi::{self, Spi},
+        usb::{Peripheral, UsbBus},
+    };
+    use usb_device::{prelude::*, class_prelude::UsbBusAllocator, UsbError};
+    use usbd_serial::{SerialPort, USB_CLASS_CDC};
+
+    const SENSORS: usize = 2;
+    // one DRDY task per sensor below, each bound to its own EXTI line
+    const _: () = assert!(SENSORS == 2, "wire CS, DRDY and a DRDY task for each sensor");
+    /// frame buffer size, one slot stays empty
+    const FRAMES: usize = 16;
+    const TICK_RATE: u32 = 48_000_000;
+    /// SysTick period(ms) for uptime
+    const UPTIME_STEP_MS: u32 = 100;
+
+    type AF6 = Alternate<PushPull, 6>;
+    type AF14 = Alternate<PushPull, 14>;
+    type SPI = Spi<SPI3, (PC10<AF6>, PC11<AF6>, PC12<AF6>), u8>;
+    type CS = PXx<Output<PushPull>>;
+    type DRDY = PXx<Input>;
+    type SENSOR = rm3100::RM3100<BusSpi, CS>;
+    type USBBUS = UsbBus<Peripheral<PA11<AF14>, PA12<AF14>>>;
+    type USBBUSALLOCATOR = UsbBusAllocator<USBBUS>;
+    type SERIAL<'a> = SerialPort<'a, USBBUS>;
+    type USBDEV<'a> = UsbDevice<'a, USBBUS>;
+    type BUFFER = rm3100::mincircularbuffer::MinCircularBuffer<Frame, FRAMES>;
+
+    /// handle to the SPI bus shared by all sensors
+    pub struct BusSpi(&'static RefCell<SPI>);
+
+    impl Transfer<u8> for BusSpi {
+        type Error = spi::Error;
+
+        fn transfer<'w>(&mut self, words: &'w mut [u8]) -> Result<&'w [u8], Self::Error> {
+            self.0.borrow_mut().transfer(words)
+        }
+    }
+
+    impl Write<u8> for BusSpi {
+        type Error = spi::Error;
+
+        fn write(&mut self, words: &[u8]) -> Result<(), Self::Error> {
+            self.0.borrow_mut().write(words)
+        }
+    }
+
+    /// all sensors, sharing one config
+    pub struct Sensors([SENSOR; SENSORS]);
+
+    // the bus is only reached through this resource, so never from two contexts
+    unsafe impl Send for Sensors {}
+
+    impl Sensors {
+        fn set_config(&mut self, config: rm3100::Config) -> rm3100::Config {
+            for sensor in self.0.iter_mut() {
+                sensor.set_config(config);
+            }
+            config
+        }
+
+        /// start all sensors back to back
+        fn start_single_measure(&mut self) {
+            for sensor in self.0.iter_mut() {
+                sensor.start_single_measure(true, true, true);
+            }
+        }
+    }
+
+    /// push-based streaming state, owned by usb handling
+    #[derive(Default)]
+    pub struct Stream {
+        active: bool,
+        /// sequence of next FRAME_DATA frame
+        sequence: u32,
+        gradients: bool,
+    }
+
+    #[shared]
+    struct Shared {
+        sensors: Sensors,
+        collector: Collector,
+        buffer: BUFFER,
+        overflow: bool,
+        uptime_ms: u64,
+    }
+
+    #[local]
+    struct Local {
+        drdy0: DRDY,
+        drdy1: DRDY,
+        trigger_input: PC1<Input>,
+        serial: SERIAL<'static>,
+        usb_dev: USBDEV<'static>,
+        decoder: FrameDecoder,
+        stream: Stream,
+        tx: TxQueue,
+        revid: u8,
+    }
+
+    #[init(local = [
+        usb_bus: Option<USBBUSALLOCATOR> = None,
+        bus: Option<RefCell<SPI>> = None,
+    ])]
+    fn init(cx: init::Context) -> (Shared, Local, init::Monotonics) {
+        let dp = cx.device;
+        let mut core = cx.core;
+        let mut flash = dp.FLASH.constrain();
+        let mut rcc = dp.RCC.constrain();
+        let mut syscfg = dp.SYSCFG.constrain(&mut rcc.apb2);
+        let mut exti = dp.EXTI;
+        let mut gpioa = dp.GPIOA.split(&mut rcc.ahb);
+        let mut gpioc = dp.GPIOC.split(&mut rcc.ahb);
+        let mut gpiod = dp.GPIOD.split(&mut rcc.ahb);
+        let clocks = rcc
+            .cfgr
+            .use_hse(8.MHz())
+            .sysclk(48.MHz())
+            .pclk1(24.MHz())
+            .pclk2(24.MHz())
+            .freeze(&mut flash.acr);
+        assert!(clocks.usbclk_valid());
+        assert_eq!(clocks.sysclk().0, TICK_RATE);
+
+        // start cycle counter for timestamps
+        core.DCB.enable_trace();
+        core.DWT.enable_cycle_counter();
+
+        // SysTick counts uptime
+        core.SYST.set_clock_source(SystClkSource::Core);
+        core.SYST.set_reload(TICK_RATE / 1000 * UPTIME_STEP_MS - 1);
+        core.SYST.clear_current();
+        core.SYST.enable_counter();
+        core.SYST.enable_interrupt();
+
+        // spi bus and sensors
+        let sck = gpioc.pc10.into_af_push_pull(&mut gpioc.moder, &mut gpioc.otyper, &mut gpioc.afrh);
+        let miso = gpioc.pc11.into_af_push_pull(&mut gpioc.moder, &mut gpioc.otyper, &mut gpioc.afrh);
+        let mosi = gpioc.pc12.into_af_push_pull(&mut gpioc.moder, &mut gpioc.otyper, &mut gpioc.afrh);
+        let spi = Spi::new(dp.SPI3, (sck, miso, mosi), 1.MHz(), clocks, &mut rcc.apb1);
+        let bus: &'static RefCell<SPI> = cx.local.bus.insert(RefCell::new(spi));
+        // pin map, index is the sensor
+        let cs: [CS; SENSORS] = [
+            gpioa.pa2.into_push_pull_output(&mut gpioa.moder, &mut gpioa.otyper).downgrade().downgrade(),
+            gpioa.pa3.into_push_pull_output(&mut gpioa.moder, &mut gpioa.otyper).downgrade().downgrade(),
+        ];
+        let mut drdy: [DRDY; SENSORS] = [
+            gpioa.pa0.into_pull_down_input(&mut gpioa.moder, &mut gpioa.pupdr).downgrade().downgrade(),
+            gpiod.pd2.into_pull_down_input(&mut gpiod.moder, &mut gpiod.pupdr).downgrade().downgrade(),
+        ];
+        let mut sensors = Sensors(cs.map(|cs| rm3100::RM3100::new(BusSpi(bus), cs, rm3100::Config::default())));
+        sensors.set_config(rm3100::Config::default());
+        let revid = sensors.0[0].read_revid();
+
+        // DRDY of each sensor and trigger input, rise
+        for pin in drdy.iter_mut() {
+            syscfg.select_exti_interrupt_source(pin);
+            pin.trigger_on_edge(&mut exti, Edge::Rising);
+            pin.enable_interrupt(&mut exti);
+        }
+        let [drdy0, drdy1] = drdy;
+        let mut trigger_input = gpioc.pc1.into_pull_down_input(&mut gpioc.moder, &mut gpioc.pupdr);
+        syscfg.select_exti_interrupt_source(&trigger_input);
+        trigger_input.trigger_on_edge(&mut exti, Edge::Rising);
+        trigger_input.enable_interrupt(&mut exti);
+
+        // F3 Discovery board has a pull-up resistor on the D+ line,
+        // pull it down so the host sees a reset after flashing
+        let mut usb_dp = gpioa.pa12.into_push_pull_output(&mut gpioa.moder, &mut gpioa.otyper);
+        usb_dp.set_low().ok();
+        asm::delay(clocks.sysclk().0 / 100);
+        let usb_dm = gpioa.pa11.into_af_push_pull(&mut gpioa.moder, &mut gpioa.otyper, &mut gpioa.afrh);
+        let usb_dp = usb_dp.into_af_push_pull(&mut gpioa.moder, &mut gpioa.otyper, &mut gpioa.afrh);
+        let usb = Peripheral {usb: dp.USB, pin_dm: usb_dm, pin_dp: usb_dp};
+        let usb_bus: &'static USBBUSALLOCATOR = cx.local.usb_bus.insert(UsbBus::new(usb));
+        let serial = SerialPort::new(usb_bus);
+        let usb_dev = UsbDeviceBuilder::new(usb_bus, UsbVidPid(0x16c0, 0x27dd))
+            .manufacturer("Fake company")
+            .product("RM3100 gradiometer")
+            .serial_number("TEST")
+            .device_class(USB_CLASS_CDC)
+            .build();
+
+        (
+            Shared {
+                sensors,
+                collector: Collector::new(SENSORS),
+                buffer: BUFFER::new(Frame::default()),
+                overflow: false,
+                uptime_ms: 0,
+            },
+            Local {
+                drdy0,
+                drdy1,
+                trigger_input,
+                serial,
+                usb_dev,
+                decoder: FrameDecoder::new(),
+                stream: Stream::default(),
+                tx: TxQueue::new(),
+                revid,
+            },
+            init::Monotonics(),
+        )
+    }
+
+    /// everything runs in interrupts
+    #[idle]
+    fn idle(_: idle::Context) -> ! {
+        loop {
+            asm::wfi();
+        }
+    }
+
+    /// answer requests, push frames while streaming
+    #[task(
+        binds = USB_LP_CAN_RX0,
+        priority = 1,
+        local = [serial, usb_dev, decoder, stream, tx, revid],
+        shared = [sensors, buffer, overflow, uptime_ms]
+    )]
+    fn usb_lp(mut cx: usb_lp::Context) {
+        let serial = cx.local.serial;
+        let usb_dev = cx.local.usb_dev;
+        let decoder = cx.local.decoder;
+        let stream = cx.local.stream;
+        let tx = cx.local.tx;
+        let polled = usb_dev.poll(&mut [serial]);
+        // host gone: nobody reads queued replies or stream
+        if usb_dev.state() != UsbDeviceState::Configured || !serial.dtr() {
+            stream.active = false;
+            tx.clear();
+            decoder.reset();
+            return;
+        }
+        // leave requests to the host until a reply fits
+        if polled && tx.free() >= protocol::MAX_FRAME {
+            let mut buf = [0u8; 64];
+            let count = serial.read(&mut buf).unwrap_or(0);
+            for byte in buf[0..count].iter() {
+                let (id, response) = match decoder.push(*byte) {
+                    None => continue,
+                    Some(Err(error)) => (error.command, Response::Error(error.status)),
+                    Some(Ok(frame)) => (
+                        frame.command,
+                        match Command::decode(&frame) {
+                            Ok(command) => handle_command(&mut cx.shared, stream, *cx.local.revid, command),
+                            Err(status) => Response::Error(status),
+                        },
+                    ),
+                };
+                queue(tx, &response, id);
+            }
+        }
+        // frames wait in the buffer until one fits
+        while stream.active && tx.free() >= protocol::MAX_FRAME {
+            let Some(frame) = cx.shared.buffer.lock(|_buffer| _buffer.pop()) else {break};
+            let cc = cx.shared.sensors.lock(|_sensors| _sensors.0[0].get_cycle_count());
+            let response = Response::Frame {
+                sequence: stream.sequence,
+                frame,
+                gradients: stream.gradients.then(|| frame.gradients(cc)),
+            };
+            stream.sequence = stream.sequence.wrapping_add(1);
+            queue(tx, &response, protocol::command::FRAME_DATA);
+        }
+        tx.flush(|data| match serial.write(data) {
+            Ok(len) => Some(len),
+            Err(UsbError::WouldBlock) => Some(0),
+            // not configured, dropped by next disconnect check
+            Err(_) => None,
+        });
+    }
+
+    #[task(binds = USB_HP_CAN_TX, priority = 1)]
+    fn usb_hp(_: usb_hp::Context) {
+        rtic::pend(Interrupt::USB_LP_CAN_RX0);
+    }
+
+    /// append the response frame, dropped if it does not fit
+    fn queue(tx: &mut TxQueue, response: &Response, command: u8) {
+        let mut outputbuf = [0u8; protocol::MAX_FRAME];
+        let outputlen = response.encode(command, &mut outputbuf).unwrap_or(0);
+        // several requests in one packet may still overrun the queue
+        tx.push(&outputbuf[..outputlen]);
+    }
+
+    fn handle_command(
+        shared: &mut usb_lp::SharedResources, stream: &mut Stream, revid: u8, command: Command
+    ) -> Response {
+        match command {
+            Command::GetInfo => Response::Info(DeviceInfo {
+                protocol_version: protocol::VERSION,
+                firmware_version: server::firmware_version(),
+                tick_rate: TICK_RATE,
+                revid,
+                buffer_size: (FRAMES - 1) as u16,
+                uptime_ms: shared.uptime_ms.lock(|_uptime| *_uptime),
+                config: shared.sensors.lock(|_sensors| _sensors.0[0].get_config()),
+                axes: rm3100::Axes::XYZ,
+            }),
+            Command::GetConfig => Response::Config(
+                shared.sensors.lock(|_sensors| _sensors.0[0].get_config())
+            ),
+            Command::SetConfig(config) => Response::Config(
+                shared.sensors.lock(|_sensors| _sensors.set_config(config))
+            ),
+            Command::GetAxes => Response::Axes(rm3100::Axes::XYZ),
+            Command::GetOverflow => Response::Overflow(shared.overflow.lock(|_of| *_of)),
+            Command::ClearOverflow => {
+                shared.overflow.lock(|_of| {*_of = false;});
+                Response::Done
+            },
+            Command::ClearBuffer => {
+                shared.buffer.lock(|_buffer| {_buffer.clear();});
+                Response::Done
+            },
+            Command::StartStream => {
+                *stream = Stream {active: true, sequence: 0, gradients: stream.gradients};
+                Response::Done
+            },
+            Command::StopStream => {
+                stream.active = false;
+                Response::Done
+            },
+            Command::SetGradients(on) => {
+                stream.gradients = on;
+                Response::Done
+            },
+            _ => Response::Error(StatusCode::UnknownCommand),
+        }
+    }
+
+    /// trigger input: start all sensors, begin a frame
+    #[task(binds = EXTI1, priority = 2, local = [trigger_input], shared = [sensors, collector, overflow])]
+    fn trigger(mut cx: trigger::Context) {
+        let tick = DWT::cycle_count();
+        cx.local.trigger_input.clear_interrupt();
+        cx.shared.sensors.lock(|_sensors| _sensors.start_single_measure());
+        if cx.shared.collector.lock(|_collector| _collector.start(tick)) {
+            cx.shared.overflow.lock(|_of| {*_of = true;});
+        }
+    }
+
+    #[task(binds = EXTI0, priority = 2, local = [drdy0], shared = [sensors, collector, buffer, overflow])]
+    fn drdy0(mut cx: drdy0::Context) {
+        let tick = DWT::cycle_count();
+        cx.local.drdy0.clear_interrupt();
+        let shared = &mut cx.shared;
+        read_sensor(0, tick, &mut shared.sensors, &mut shared.collector, &mut shared.buffer, &mut shared.overflow);
+    }
+
+    #[task(binds = EXTI2_TSC, priority = 2, local = [drdy1], shared = [sensors, collector, buffer, overflow])]
+    fn drdy1(mut cx: drdy1::Context) {
+        let tick = DWT::cycle_count();
+        cx.local.drdy1.clear_interrupt();
+        let shared = &mut cx.shared;
+        read_sensor(1, tick, &mut shared.sensors, &mut shared.collector, &mut shared.buffer, &mut shared.overflow);
+    }
+
+    /// read sensor `index`, buffer the frame once complete
+    fn read_sensor(
+        index: usize,
+        drdy_tick: u32,
+        sensors: &mut impl Mutex<T = Sensors>,
+        collector: &mut impl Mutex<T = Collector>,
+        buffer: &mut impl Mutex<T = BUFFER>,
+        overflow: &mut impl Mutex<T = bool>,
+    ) {
+        let mag = sensors.lock(|_sensors| _sensors.0[index].read_mag());
+        let Some(frame) = collector.lock(|_collector| _collector.on_sample(index, mag, drdy_tick)) else {
+            return;
+        };
+        (buffer, overflow).lock(|_buffer, _overflow| {
+            // keep buffered frames, drop the new one
+            if _buffer.is_full() {
+                *_overflow = true;
+            } else {
+                _buffer.push(frame);
+            }
+        });
+        rtic::pend(Interrupt::USB_LP_CAN_RX0);
+    }
+
+    /// uptime tick
+    #[task(binds = SysTick, priority = 2, shared = [uptime_ms])]
+    fn uptime(mut cx: uptime::Context) {
+        cx.shared.uptime_ms.lock(|_uptime| {*_uptime += UPTIME_STEP_MS as u64;});
+    }
+}
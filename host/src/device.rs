@@ -0,0 +1,439 @@
+//! typed client of the firmware protocol
+
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rm3100::protocol::{
+    command, Command, DeviceInfo, FrameDecoder, Health, Response, Sample, Stats, MAX_FRAME,
+};
+use rm3100::filter::FilterConfig;
+use rm3100::power::Estimate;
+use rm3100::{Axes, Config};
+use serialport::SerialPort;
+
+use crate::error::{Error, Result};
+
+/// default time to wait for a reply
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(1);
+/// longest a read of an opened serial port blocks
+const POLL: Duration = Duration::from_millis(10);
+
+/// ## one device running the app firmware
+///
+/// `P` is any byte stream to the firmware; reads must return within a short
+/// time (`TimedOut`/`WouldBlock` when nothing arrived), the reply timeout is
+/// kept by `Device`
+pub struct Device<P = Box<dyn SerialPort>> {
+    port: P,
+    decoder: FrameDecoder,
+    /// received bytes not fed to the decoder yet
+    rx: VecDeque<u8>,
+    timeout: Duration,
+}
+
+impl Device {
+    /// ## open the CDC serial port at `path`, e.g. /dev/ttyACM0
+    pub fn open(path: &str) -> Result<Self> {
+        let mut port = serialport::new(path, 115_200).timeout(POLL).open().map_err(|error| {
+            io::Error::new(io::Error::from(error.clone()).kind(), format!("{}: {}", path, error))
+        })?;
+        // firmware only answers while DTR is set, pseudo terminals
+        // (the emulator) have no modem lines
+        port.write_data_terminal_ready(true).ok();
+        port.clear(serialport::ClearBuffer::Input)?;
+        Ok(Device::new(port))
+    }
+}
+
+impl<P: Read + Write> Device<P> {
+    pub fn new(port: P) -> Self {
+        Device {port, decoder: FrameDecoder::new(), rx: VecDeque::new(), timeout: DEFAULT_TIMEOUT}
+    }
+
+    pub fn timeout(&self) -> Duration {self.timeout}
+
+    /// time to wait for a reply or the next stream frame
+    pub fn set_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn into_inner(self) -> P {self.port}
+
+    /// ## send command and wait for its reply
+    ///
+    /// stream frames and stale replies received meanwhile are dropped,
+    /// error status reported by the device is returned as `Error::Device`
+    pub fn request(&mut self, command: Command) -> Result<Response> {
+        let mut buf = [0u8; MAX_FRAME];
+        let len = command.encode(&mut buf).expect("MAX_FRAME fits every command");
+        self.port.write_all(&buf[..len])?;
+        self.port.flush()?;
+        let deadline = Instant::now() + self.timeout;
+        loop {
+            match self.receive(deadline)? {
+                (id, Ok(Response::Error(status))) if id == command.id() => return Err(Error::Device(status)),
+                (id, reply) if id == command.id() => return reply,
+                _ => {},
+            }
+        }
+    }
+
+    pub fn info(&mut self) -> Result<DeviceInfo> {
+        self.expect(Command::GetInfo, |reply| match reply {
+            Response::Info(info) => Some(info),
+            _ => None,
+        })
+    }
+
+    pub fn config(&mut self) -> Result<Config> {
+        self.expect(Command::GetConfig, config_of)
+    }
+
+    /// ## write sensor config, return the config applied
+    pub fn configure(&mut self, config: Config) -> Result<Config> {
+        self.expect(Command::SetConfig(config), config_of)
+    }
+
+    pub fn axes(&mut self) -> Result<Axes> {
+        self.expect(Command::GetAxes, axes_of)
+    }
+
+    pub fn set_axes(&mut self, axes: Axes) -> Result<Axes> {
+        self.expect(Command::SetAxes(axes), axes_of)
+    }
+
+    /// ## pop at most `max` buffered samples
+    pub fn read_samples(&mut self, max: u8) -> Result<Vec<Sample>> {
+        self.expect(Command::ReadSamples { max }, |reply| match reply {
+            Response::Samples(samples) => Some(samples.as_slice().to_vec()),
+            _ => None,
+        })
+    }
+
+    /// ## pop the oldest buffered sample
+    ///
+    /// wait up to the timeout for a sample to be measured
+    pub fn read_sample(&mut self) -> Result<Sample> {
+        let deadline = Instant::now() + self.timeout;
+        loop {
+            if let Some(sample) = self.read_samples(1)?.pop() {
+                return Ok(sample);
+            }
+            if Instant::now() >= deadline {
+                return Err(Error::Timeout);
+            }
+            thread::sleep(POLL);
+        }
+    }
+
+    /// ## samples were dropped because the buffer was full
+    pub fn overflow(&mut self) -> Result<bool> {
+        self.expect(Command::GetOverflow, |reply| match reply {
+            Response::Overflow(overflow) => Some(overflow),
+            _ => None,
+        })
+    }
+
+    pub fn clear_overflow(&mut self) -> Result<()> {
+        self.expect(Command::ClearOverflow, done)
+    }
+
+    pub fn clear_buffer(&mut self) -> Result<()> {
+        self.expect(Command::ClearBuffer, done)
+    }
+
+    pub fn stats(&mut self) -> Result<Stats> {
+        self.expect(Command::GetStats, |reply| match reply {
+            Response::Stats(stats) => Some(stats),
+            _ => None,
+        })
+    }
+
+    pub fn clear_stats(&mut self) -> Result<()> {
+        self.expect(Command::ClearStats, done)
+    }
+
+    pub fn health(&mut self) -> Result<Health> {
+        self.expect(Command::GetHealth, |reply| match reply {
+            Response::Health(health) => Some(health),
+            _ => None,
+        })
+    }
+
+    pub fn power(&mut self) -> Result<Estimate> {
+        self.expect(Command::GetPower, |reply| match reply {
+            Response::Power(power) => Some(power),
+            _ => None,
+        })
+    }
+
+    /// ## trigger measurements by the internal timer at `rate`(mHz)
+    ///
+    /// return the actual rate(mHz)
+    pub fn start_timer(&mut self, rate: u32) -> Result<u32> {
+        self.expect(Command::StartTimer { rate }, timer_of)
+    }
+
+    pub fn stop_timer(&mut self) -> Result<()> {
+        self.expect(Command::StopTimer, done)
+    }
+
+    /// timer rate(mHz), 0 if stopped
+    pub fn timer(&mut self) -> Result<u32> {
+        self.expect(Command::GetTimer, timer_of)
+    }
+
+    /// ## `count` sensor registers from `address` on
+    ///
+    /// reading the measurement registers clears DRDY, the sample is lost
+    pub fn read_registers(&mut self, address: u8, count: u8) -> Result<Vec<u8>> {
+        self.expect(Command::ReadRegisters { address, count }, |reply| match reply {
+            Response::Registers(registers) => Some(registers.as_slice().to_vec()),
+            _ => None,
+        })
+    }
+
+    pub fn filter(&mut self) -> Result<FilterConfig> {
+        self.expect(Command::GetFilter, filter_of)
+    }
+
+    /// ## replace the filter samples pass before the buffer, it starts over
+    pub fn set_filter(&mut self, filter: FilterConfig) -> Result<FilterConfig> {
+        self.expect(Command::SetFilter(filter), filter_of)
+    }
+
+    /// ## start streaming
+    ///
+    /// the stream yields samples until dropped, which stops streaming.
+    /// It never ends by itself, `Error::Timeout` is yielded while no
+    /// samples arrive and `Error::Gap` when stream frames were lost
+    pub fn stream(&mut self) -> Result<Stream<'_, P>> {
+        self.expect(Command::StartStream, done)?;
+        Ok(Stream {device: self, sequence: 0, samples: VecDeque::new()})
+    }
+
+    fn expect<T>(&mut self, command: Command, pick: impl FnOnce(Response) -> Option<T>) -> Result<T> {
+        let reply = self.request(command)?;
+        pick(reply).ok_or(Error::Unexpected { command: command.id() })
+    }
+
+    /// ## next frame, its command id and decoded response
+    ///
+    /// Err only if nothing could be received before `deadline`
+    fn receive(&mut self, deadline: Instant) -> Result<(u8, Result<Response>)> {
+        loop {
+            while let Some(byte) = self.rx.pop_front() {
+                match self.decoder.push(byte) {
+                    Some(Ok(frame)) => {
+                        let reply = Response::decode(&frame).map_err(Error::Decode);
+                        return Ok((frame.command, reply));
+                    },
+                    Some(Err(error)) => return Ok((error.command, Err(Error::Frame(error)))),
+                    None => {},
+                }
+            }
+            if Instant::now() >= deadline {
+                return Err(Error::Timeout);
+            }
+            let mut buf = [0u8; 256];
+            match self.port.read(&mut buf) {
+                Ok(0) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+                Ok(len) => self.rx.extend(&buf[..len]),
+                Err(error) if error.kind() == io::ErrorKind::WouldBlock => thread::sleep(Duration::from_millis(1)),
+                Err(error) if matches!(error.kind(), io::ErrorKind::TimedOut | io::ErrorKind::Interrupted) => {},
+                Err(error) => return Err(error.into()),
+            }
+        }
+    }
+}
+
+fn config_of(reply: Response) -> Option<Config> {
+    match reply {
+        Response::Config(config) => Some(config),
+        _ => None,
+    }
+}
+
+fn axes_of(reply: Response) -> Option<Axes> {
+    match reply {
+        Response::Axes(axes) => Some(axes),
+        _ => None,
+    }
+}
+
+fn filter_of(reply: Response) -> Option<FilterConfig> {
+    match reply {
+        Response::Filter(filter) => Some(filter),
+        _ => None,
+    }
+}
+
+fn timer_of(reply: Response) -> Option<u32> {
+    match reply {
+        Response::Timer { rate } => Some(rate),
+        _ => None,
+    }
+}
+
+fn done(reply: Response) -> Option<()> {
+    match reply {
+        Response::Done => Some(()),
+        _ => None,
+    }
+}
+
+/// ## streamed samples, see `Device::stream`
+pub struct Stream<'a, P: Read + Write> {
+    device: &'a mut Device<P>,
+    /// sequence of the next stream frame
+    sequence: u32,
+    samples: VecDeque<Sample>,
+}
+
+impl<P: Read + Write> Stream<'_, P> {
+    /// sequence the next stream frame should carry, frames received so far
+    pub fn sequence(&self) -> u32 {self.sequence}
+
+    /// sequence of the stream frame the last sample came from
+    pub fn frame(&self) -> u32 {self.sequence.wrapping_sub(1)}
+}
+
+impl<P: Read + Write> Iterator for Stream<'_, P> {
+    type Item = Result<Sample>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(sample) = self.samples.pop_front() {
+                return Some(Ok(sample));
+            }
+            let deadline = Instant::now() + self.device.timeout;
+            match self.device.receive(deadline) {
+                Ok((command::STREAM_DATA, Ok(Response::Stream { sequence, samples }))) => {
+                    self.samples.extend(samples.as_slice());
+                    let expected = mem::replace(&mut self.sequence, sequence.wrapping_add(1));
+                    if sequence != expected {
+                        return Some(Err(Error::Gap { expected, received: sequence }));
+                    }
+                },
+                Ok((command::STREAM_DATA, Err(error))) | Err(error) => return Some(Err(error)),
+                // late replies
+                Ok(_) => {},
+            }
+        }
+    }
+}
+
+impl<P: Read + Write> Drop for Stream<'_, P> {
+    fn drop(&mut self) {
+        // device gone, nothing to stop
+        let _ = self.device.request(Command::StopStream);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rm3100::protocol::{Samples, StatusCode};
+
+    /// replies queued in advance, everything written is kept
+    #[derive(Default)]
+    struct Script {
+        replies: VecDeque<u8>,
+        written: Vec<u8>,
+    }
+
+    impl Script {
+        fn reply(&mut self, command: u8, response: Response) -> &mut Self {
+            let mut buf = [0u8; MAX_FRAME];
+            let len = response.encode(command, &mut buf).unwrap();
+            self.replies.extend(&buf[..len]);
+            self
+        }
+    }
+
+    impl Read for Script {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.replies.is_empty() {
+                return Err(io::ErrorKind::TimedOut.into());
+            }
+            // a few bytes at a time, frames arrive split
+            let len = buf.len().min(self.replies.len()).min(5);
+            for (byte, reply) in buf.iter_mut().zip(self.replies.drain(..len)) {
+                *byte = reply;
+            }
+            Ok(len)
+        }
+    }
+
+    impl Write for Script {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.written.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {Ok(())}
+    }
+
+    fn stream_data(sequence: u32, mags: &[i32]) -> Response {
+        let mut samples = Samples::default();
+        for mag in mags {
+            samples.push(Sample { mag: [*mag; 3], ..Sample::default() });
+        }
+        Response::Stream { sequence, samples }
+    }
+
+    #[test]
+    fn replies_skip_stream_frames_and_noise() {
+        let mut script = Script::default();
+        script.replies.extend(&[0x00, 0xA5, 0x13]);
+        script.reply(command::STREAM_DATA, stream_data(9, &[1]))
+            .reply(command::GET_OVERFLOW, Response::Overflow(true))
+            .reply(command::CLEAR_BUFFER, Response::Done);
+        let mut device = Device::new(script);
+        assert!(device.overflow().unwrap());
+        device.clear_buffer().unwrap();
+
+        let mut expected = Vec::new();
+        for command in [Command::GetOverflow, Command::ClearBuffer] {
+            let mut buf = [0u8; MAX_FRAME];
+            let len = command.encode(&mut buf).unwrap();
+            expected.extend_from_slice(&buf[..len]);
+        }
+        assert_eq!(device.into_inner().written, expected);
+    }
+
+    #[test]
+    fn device_errors_and_timeouts() {
+        let mut script = Script::default();
+        script.reply(command::START_TIMER, Response::Error(StatusCode::RateTooHigh))
+            .reply(command::GET_INFO, Response::Done);
+        let mut device = Device::new(script);
+        device.set_timeout(Duration::from_millis(20));
+        assert!(matches!(device.start_timer(1_000_000), Err(Error::Device(StatusCode::RateTooHigh))));
+        assert!(matches!(device.info(), Err(Error::Decode(StatusCode::InvalidLength))));
+        assert!(matches!(device.config(), Err(Error::Timeout)));
+    }
+
+    #[test]
+    fn stream_reports_lost_frames() {
+        let mut script = Script::default();
+        script.reply(command::START_STREAM, Response::Done)
+            .reply(command::STREAM_DATA, stream_data(0, &[1, 2]))
+            .reply(command::STREAM_DATA, stream_data(3, &[3]))
+            .reply(command::STOP_STREAM, Response::Done);
+        let mut device = Device::new(script);
+        let mut stream = device.stream().unwrap();
+        let mut next = || stream.next().unwrap().map(|sample| sample.mag[0]);
+        assert_eq!(next().unwrap(), 1);
+        assert_eq!(next().unwrap(), 2);
+        assert!(matches!(next(), Err(Error::Gap { expected: 1, received: 3 })));
+        assert_eq!(next().unwrap(), 3);
+        assert_eq!((stream.sequence(), stream.frame()), (4, 3));
+        drop(stream);
+        assert!(device.into_inner().replies.is_empty());
+    }
+}
@@ -0,0 +1,236 @@
+//! the app firmware around `rm3100::server`, on emulated time
+//!
+//! follows `examples/app/main.rs`: interrupts become events at exact ticks
+//! (DRDY, timer, trigger input edges, SysTick), USB_LP becomes `service`.
+//! The text console and the trigger output pin are not emulated
+
+use std::cell::RefCell;
+use std::io::{self, Read, Write};
+use std::rc::Rc;
+
+use rm3100::power::Mcu;
+use rm3100::protocol::{self, FrameDecoder};
+use rm3100::server::{self, Hardware, Server, Settings, Stream, TxPolicy, TxQueue};
+use rm3100::trigger::{self, Level};
+use rm3100::vendor::PACKET_SIZE;
+use rm3100::{Axes, Config, RM3100};
+
+use super::sensor::{Chip, ChipSelect, Spi};
+use super::{Options, TICK_RATE};
+
+/// the discovery board
+const MCU: Mcu = Mcu {run_ua: 24_000, sleep_ua: 12_000};
+
+/// TIM2, period in whole ticks
+struct Timer {
+    /// mHz
+    rate: u32,
+    period: u64,
+    next: u64,
+}
+
+/// square wave on the trigger input
+struct Input {
+    /// ticks between edges
+    half_period: u64,
+    next: u64,
+    high: bool,
+}
+
+/// tick counter and TIM2
+struct Io {
+    /// tick of the event being handled
+    now: u64,
+    timer: Option<Timer>,
+}
+
+impl Hardware for Io {
+    fn now(&mut self) -> u32 {self.now as u32}
+
+    fn drive(&mut self, _level: Level, _width_us: u16) {}
+
+    fn start_timer(&mut self, rate: u32) -> Option<u32> {
+        let clock_mhz = TICK_RATE as u64 * 1000;
+        let period = (clock_mhz + rate as u64 / 2) / rate as u64;
+        let period = u32::try_from(period).ok().filter(|period| *period > 0)?;
+        let rate = ((clock_mhz + period as u64 / 2) / period as u64) as u32;
+        self.timer = Some(Timer {rate, period: period as u64, next: self.now + period as u64});
+        Some(rate)
+    }
+
+    fn stop_timer(&mut self) {
+        self.timer = None;
+    }
+
+    fn timer_rate(&self) -> u32 {self.timer.as_ref().map_or(0, |timer| timer.rate)}
+
+    /// both edges are generated, the trigger picks its own
+    fn set_input_edge(&mut self, _edge: trigger::Edge) {}
+}
+
+/// ## firmware state
+///
+/// `N` sample buffer slots
+pub struct Firmware<const N: usize> {
+    chip: Rc<RefCell<Chip>>,
+    sensor: RM3100<Spi, ChipSelect>,
+    server: Server<N>,
+    io: Io,
+    input: Option<Input>,
+    systick: u64,
+    uptime_step_ms: u32,
+    tx_policy: TxPolicy,
+    decoder: FrameDecoder,
+    stream: Stream,
+    tx: TxQueue,
+    connected: bool,
+}
+
+impl<const N: usize> Firmware<N> {
+    /// ## power-up
+    ///
+    /// all axes are measured, the app starts with x only; `low_power`
+    /// measures them continuously from here like the app's low-power build
+    pub fn new(options: &Options) -> Self {
+        let chip = Rc::new(RefCell::new(Chip::new(options.field_nt, options.noise_nt, TICK_RATE)));
+        let mut sensor = RM3100::new(Spi(chip.clone()), ChipSelect(chip.clone()), Config::default());
+        sensor.reinit();
+        let uptime_step_ms = if options.low_power {
+            sensor.set_update_rate(server::LOW_POWER_RATE);
+            server::LOW_POWER_UPTIME_STEP_MS
+        } else {
+            server::UPTIME_STEP_MS
+        };
+        let revid = sensor.read_revid();
+        let settings = Settings {tick_rate: TICK_RATE, uptime_step_ms, mcu: MCU};
+        let mut firmware = Firmware {
+            chip, sensor,
+            server: Server::new(settings, Axes::XYZ, revid, false),
+            io: Io {now: 0, timer: None},
+            input: None,
+            systick: ticks_us(uptime_step_ms as u64 * 1000),
+            uptime_step_ms,
+            tx_policy: options.tx_policy,
+            decoder: FrameDecoder::new(),
+            stream: Stream::default(),
+            tx: TxQueue::new(),
+            connected: false,
+        };
+        if options.low_power {
+            firmware.server.set_continuous(&mut firmware.sensor, &mut firmware.io, true);
+        }
+        firmware.set_trigger_rate(options.trigger_rate, 0);
+        firmware
+    }
+
+    /// ## square wave of `rate`(mHz) on the trigger input from `now`, 0 for none
+    pub fn set_trigger_rate(&mut self, rate: u32, now: u64) {
+        let half_period = (TICK_RATE as u64 * 1000 / 2).checked_div(rate as u64).filter(|half| *half > 0);
+        self.input = half_period.map(|half_period| Input {half_period, next: now + half_period, high: false});
+    }
+
+    /// ## handle every event up to `now`, in order
+    pub fn run_until(&mut self, now: u64) {
+        loop {
+            let chip = self.chip.borrow().next_event();
+            let timer = self.io.timer.as_ref().map(|timer| timer.next);
+            let input = self.input.as_ref().map(|input| input.next);
+            let next = [chip, timer, input, Some(self.systick)].into_iter().flatten().min();
+            let Some(tick) = next.filter(|tick| *tick <= now) else {break};
+            self.io.now = tick;
+            if self.chip.borrow_mut().advance(tick) {
+                // DRDY
+                self.server.on_drdy(&mut self.io);
+                let mag = self.sensor.read_mag();
+                self.server.store_sample(&mut self.sensor, &mut self.io, mag, tick as u32);
+            } else if timer == Some(tick) {
+                let timer = self.io.timer.as_mut().expect("timer event");
+                timer.next += timer.period;
+                self.server.on_timer(&mut self.sensor, &mut self.io, tick as u32);
+            } else if input == Some(tick) {
+                let input = self.input.as_mut().expect("input event");
+                input.next += input.half_period;
+                input.high = !input.high;
+                let high = input.high;
+                self.server.on_edge(&mut self.sensor, &mut self.io, high, tick as u32);
+            } else if self.systick == tick {
+                self.systick += ticks_us(self.uptime_step_ms as u64 * 1000);
+                self.server.on_systick(&mut self.sensor, &mut self.io);
+            }
+        }
+        self.chip.borrow_mut().advance(now);
+        self.io.now = now;
+    }
+
+    /// ## USB: take requests, answer them, push stream frames, flush
+    ///
+    /// port reads and writes must not block; `BrokenPipe` means the host
+    /// closed the port. With `stall` the host takes no data. Writes are
+    /// packets of at most 63 bytes, as the app's vendor class sends them
+    pub fn service(&mut self, port: &mut (impl Read + Write), stall: bool) -> io::Result<()> {
+        let mut buf = [0u8; PACKET_SIZE as usize];
+        let count = if self.tx.free() >= protocol::MAX_FRAME {
+            match port.read(&mut buf) {
+                Ok(count) => count,
+                Err(error) if would_block(&error) => 0,
+                Err(error) if error.kind() == io::ErrorKind::BrokenPipe => {
+                    self.disconnect();
+                    return Ok(());
+                },
+                Err(error) => return Err(error),
+            }
+        } else {0};
+        self.connected = true;
+        for byte in &buf[..count] {
+            if let Some(frame) = self.decoder.push(*byte) {
+                self.server.request(&mut self.sensor, &mut self.io, &mut self.stream, frame, &mut self.tx);
+            }
+        }
+        self.server.push_stream(&mut self.stream, &mut self.tx, self.tx_policy);
+        let mut failed = None;
+        let stalled = self.tx.flush(|data| {
+            if stall {
+                return Some(0);
+            }
+            let len = data.len().min(PACKET_SIZE as usize - 1);
+            match port.write(&data[..len]) {
+                Ok(len) => Some(len),
+                Err(error) if would_block(&error) => Some(0),
+                Err(error) => {
+                    failed = Some(error);
+                    None
+                },
+            }
+        });
+        if stalled {
+            self.server.count_stall();
+        }
+        match failed {
+            Some(error) if error.kind() == io::ErrorKind::BrokenPipe => {
+                self.disconnect();
+                Ok(())
+            },
+            Some(error) => Err(error),
+            None => Ok(()),
+        }
+    }
+
+    /// host gone: nobody reads queued replies or stream
+    fn disconnect(&mut self) {
+        if self.connected {
+            self.tx.clear();
+            self.decoder.reset();
+            self.stream = Stream::default();
+        }
+        self.connected = false;
+    }
+}
+
+/// ticks in `us`
+fn ticks_us(us: u64) -> u64 {
+    us * TICK_RATE as u64 / 1_000_000
+}
+
+fn would_block(error: &io::Error) -> bool {
+    matches!(error.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut | io::ErrorKind::Interrupted)
+}
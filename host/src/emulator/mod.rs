@@ -0,0 +1,171 @@
+//! emulated device for testing host tools without hardware
+//!
+//! a register model of the RM3100 (`sensor`) driven by the real driver and
+//! the app firmware's request handling, sampling, stream and TX queue
+//! (`rm3100::server`), on a 48MHz tick clock following real time. It talks the protocol over an
+//! in-memory `pipe` or a Linux pseudo terminal (`pty`), so `Device` and the
+//! command line tool run unchanged against it.
+//!
+//! besides the firmware's own behaviour it can generate trigger input edges
+//! at a set rate and stall the host link (the host takes no data), to bring
+//! out overflow, dropped stream frames and USB stall counting
+
+mod firmware;
+mod pipe;
+pub mod sensor;
+
+use std::io::{self, Read, Write};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use serialport::{SerialPort, TTYPort};
+
+use rm3100::server::{BUFFER_SIZE, LOW_POWER_BUFFER_SIZE};
+
+use firmware::Firmware;
+pub use pipe::{pair, DevicePort, HostPort};
+pub use rm3100::server::TxPolicy;
+
+/// DWT cycle counter rate of the discovery board
+pub const TICK_RATE: u32 = 48_000_000;
+/// time between two runs of the firmware
+const STEP: Duration = Duration::from_millis(1);
+
+/// ## setup of an emulated device
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Options {
+    /// the app's `low-power` build: x/y/z measured continuously from
+    /// power-up into a larger buffer, slower SysTick
+    pub low_power: bool,
+    /// field seen by the sensor(nT)
+    pub field_nt: [i32; 3],
+    /// peak noise(nT) added to each measurement
+    pub noise_nt: i32,
+    /// square wave on the trigger input(mHz), 0 for none
+    pub trigger_rate: u32,
+    pub tx_policy: TxPolicy,
+}
+
+impl Default for Options {
+    /// the app, earth-like field
+    fn default() -> Self {
+        Options {
+            low_power: false,
+            field_nt: [20_000, 0, -40_000],
+            noise_nt: 0,
+            trigger_rate: 0,
+            tx_policy: TxPolicy::Retain,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Control {
+    quit: AtomicBool,
+    stall: AtomicBool,
+    trigger_rate: AtomicU32,
+}
+
+/// ## a running emulator, stopped on drop
+pub struct Handle {
+    control: Arc<Control>,
+    thread: Option<JoinHandle<io::Result<()>>>,
+}
+
+impl Handle {
+    /// ## stall the link: the host takes no data until called with false
+    pub fn stall(&self, stall: bool) {
+        self.control.stall.store(stall, Ordering::Relaxed);
+    }
+
+    /// ## trigger input square wave(mHz) from now on, 0 to stop it
+    pub fn set_trigger_rate(&self, rate: u32) {
+        self.control.trigger_rate.store(rate, Ordering::Relaxed);
+    }
+
+    /// ## stop the emulator, return the error it stopped on
+    pub fn stop(mut self) -> io::Result<()> {
+        self.control.quit.store(true, Ordering::Relaxed);
+        self.join()
+    }
+
+    /// ## run until the link fails
+    pub fn wait(mut self) -> io::Result<()> {
+        self.join()
+    }
+
+    fn join(&mut self) -> io::Result<()> {
+        match self.thread.take().map(JoinHandle::join) {
+            Some(Ok(result)) => result,
+            Some(Err(_)) => Err(io::Error::other("emulator panicked")),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for Handle {
+    fn drop(&mut self) {
+        self.control.quit.store(true, Ordering::Relaxed);
+        let _ = self.join();
+    }
+}
+
+/// ## run an emulator on `port`
+///
+/// reads and writes of `port` must not block: `WouldBlock`/`TimedOut` when
+/// nothing can be moved, `BrokenPipe` while the host has the port closed
+pub fn spawn<P: Read + Write + Send + 'static>(mut port: P, options: Options) -> Handle {
+    let control = Arc::new(Control::default());
+    control.trigger_rate.store(options.trigger_rate, Ordering::Relaxed);
+    let thread = {
+        let control = control.clone();
+        thread::spawn(move || {
+            if options.low_power {
+                run(Firmware::<LOW_POWER_BUFFER_SIZE>::new(&options), &mut port, &control)
+            } else {
+                run(Firmware::<BUFFER_SIZE>::new(&options), &mut port, &control)
+            }
+        })
+    };
+    Handle {control, thread: Some(thread)}
+}
+
+/// step `firmware` along real time until told to quit
+fn run<const N: usize>(mut firmware: Firmware<N>, port: &mut (impl Read + Write), control: &Control) -> io::Result<()> {
+    let mut trigger_rate = control.trigger_rate.load(Ordering::Relaxed);
+    let start = Instant::now();
+    while !control.quit.load(Ordering::Relaxed) {
+        let now = (start.elapsed().as_nanos() * TICK_RATE as u128 / 1_000_000_000) as u64;
+        let rate = control.trigger_rate.load(Ordering::Relaxed);
+        if rate != trigger_rate {
+            firmware.set_trigger_rate(rate, now);
+            trigger_rate = rate;
+        }
+        firmware.run_until(now);
+        firmware.service(port, control.stall.load(Ordering::Relaxed))?;
+        thread::sleep(STEP);
+    }
+    Ok(())
+}
+
+/// ## emulator on an in-memory link, for `Device::new`
+pub fn pipe(options: Options) -> (HostPort, Handle) {
+    let (host, device) = pair();
+    (host, spawn(device, options))
+}
+
+/// ## emulator on a new pseudo terminal, return the path to open
+///
+/// the host side is a serial port at that path for `Device::open` and the
+/// command line tool; pseudo terminals have no DTR, the port counts as
+/// connected while it is open
+pub fn pty(options: Options) -> io::Result<(String, Handle)> {
+    let (mut master, slave) = TTYPort::pair()?;
+    let path = slave.name().ok_or_else(|| io::Error::other("pseudo terminal has no name"))?;
+    // an open slave would hide the host closing its port
+    drop(slave);
+    master.set_timeout(Duration::ZERO)?;
+    Ok((path, spawn(master, options)))
+}
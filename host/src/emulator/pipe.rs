@@ -0,0 +1,112 @@
+//! in-memory serial link between a host and the emulator
+
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+/// bytes the host side holds before the device sees it stall, like the
+/// buffers of the USB stack and the OS
+const HOST_BUFFER: usize = 4096;
+/// longest a host read blocks
+const POLL: Duration = Duration::from_millis(10);
+
+#[derive(Default)]
+struct Link {
+    to_device: VecDeque<u8>,
+    to_host: VecDeque<u8>,
+    host_open: bool,
+}
+
+#[derive(Default)]
+struct Shared {
+    link: Mutex<Link>,
+    /// bytes for the host arrived
+    arrived: Condvar,
+}
+
+/// ## host end, for `Device::new`
+///
+/// reads wait a short time and return `TimedOut` when nothing arrived,
+/// dropping it closes the port
+pub struct HostPort(Arc<Shared>);
+
+/// ## device end, never blocks
+///
+/// `WouldBlock` when empty or full, `BrokenPipe` while the host end is dropped
+pub struct DevicePort(Arc<Shared>);
+
+/// ## a connected pair of ends
+pub fn pair() -> (HostPort, DevicePort) {
+    let shared = Arc::new(Shared::default());
+    shared.link.lock().unwrap().host_open = true;
+    (HostPort(shared.clone()), DevicePort(shared))
+}
+
+impl Read for HostPort {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let link = self.0.link.lock().unwrap();
+        let (mut link, _) = self.0.arrived.wait_timeout_while(link, POLL, |link| link.to_host.is_empty()).unwrap();
+        if link.to_host.is_empty() {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        let count = buf.len().min(link.to_host.len());
+        for (byte, received) in buf.iter_mut().zip(link.to_host.drain(..count)) {
+            *byte = received;
+        }
+        Ok(count)
+    }
+}
+
+impl Write for HostPort {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.link.lock().unwrap().to_device.extend(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {Ok(())}
+}
+
+impl Drop for HostPort {
+    fn drop(&mut self) {
+        let mut link = self.0.link.lock().unwrap();
+        link.host_open = false;
+        link.to_device.clear();
+        link.to_host.clear();
+    }
+}
+
+impl Read for DevicePort {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut link = self.0.link.lock().unwrap();
+        if !link.host_open {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        if link.to_device.is_empty() {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        let count = buf.len().min(link.to_device.len());
+        for (byte, received) in buf.iter_mut().zip(link.to_device.drain(..count)) {
+            *byte = received;
+        }
+        Ok(count)
+    }
+}
+
+impl Write for DevicePort {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut link = self.0.link.lock().unwrap();
+        if !link.host_open {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        let count = buf.len().min(HOST_BUFFER - link.to_host.len());
+        if count == 0 {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        link.to_host.extend(&buf[..count]);
+        self.0.arrived.notify_all();
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {Ok(())}
+}
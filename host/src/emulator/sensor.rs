@@ -0,0 +1,370 @@
+//! register model of the RM3100 behind its SPI interface
+//!
+//! `Spi` and `ChipSelect` implement the embedded-hal traits of the driver, so
+//! the emulator runs the real `rm3100::RM3100` against it. Time is the
+//! emulator's tick count, set by `advance` before every access
+
+use std::cell::RefCell;
+use std::convert::Infallible;
+use std::rc::Rc;
+
+use embedded_hal::blocking::spi::{Transfer, Write};
+use embedded_hal::digital::v2::OutputPin;
+use rm3100::server::REVID;
+use rm3100::{Axes, CycleCount, UpdateRate};
+
+const POLL_REG: u8 = 0x00;
+const CMM_REG: u8 = 0x01;
+const CCX_REG: u8 = 0x04;
+const TMRC_REG: u8 = 0x0B;
+const MX_REG: u8 = 0x24;
+const MZ_END: u8 = 0x2D;
+const BIST_REG: u8 = 0x33;
+const STATUS_REG: u8 = 0x34;
+const HSHAKE_REG: u8 = 0x35;
+const REVID_REG: u8 = 0x36;
+const READ_FLAG: u8 = 0x80;
+const DRDY: u8 = 0x80;
+const START: u8 = 0x01;
+
+/// ## the sensor chip
+pub struct Chip {
+    registers: [u8; 0x40],
+    /// field seen by the sensor(nT)
+    field_nt: [i32; 3],
+    /// peak noise(nT) added to each measured axis
+    noise_nt: i32,
+    /// xorshift state of the noise
+    seed: u32,
+    tick_rate: u32,
+    now: u64,
+    selected: bool,
+    /// end tick and axes of a single measurement
+    single: Option<(u64, Axes)>,
+    /// end tick of the running continuous measurement
+    continuous: Option<u64>,
+}
+
+impl Chip {
+    /// ## chip after power-up, ticks at `tick_rate`
+    pub fn new(field_nt: [i32; 3], noise_nt: i32, tick_rate: u32) -> Self {
+        let mut registers = [0u8; 0x40];
+        for cc in registers[CCX_REG as usize..CCX_REG as usize + 6].chunks_exact_mut(2) {
+            cc.copy_from_slice(&200u16.to_be_bytes());
+        }
+        registers[TMRC_REG as usize] = UpdateRate::default() as u8;
+        registers[HSHAKE_REG as usize] = 0x1B;
+        registers[REVID_REG as usize] = REVID;
+        Chip {
+            registers, field_nt, noise_nt, seed: 0x1234_5678, tick_rate, now: 0,
+            selected: false, single: None, continuous: None,
+        }
+    }
+
+    pub fn set_field(&mut self, field_nt: [i32; 3]) {
+        self.field_nt = field_nt;
+    }
+
+    pub fn now(&self) -> u64 {self.now}
+
+    /// DRDY pin
+    pub fn drdy(&self) -> bool {
+        self.registers[STATUS_REG as usize] & DRDY != 0
+    }
+
+    /// tick a running measurement ends
+    pub fn next_event(&self) -> Option<u64> {
+        match (self.single, self.continuous) {
+            (Some((single, _)), Some(continuous)) => Some(single.min(continuous)),
+            (single, continuous) => single.map(|(end, _)| end).or(continuous),
+        }
+    }
+
+    /// ## run until `tick`, return true if DRDY rose
+    pub fn advance(&mut self, tick: u64) -> bool {
+        let before = self.drdy();
+        self.now = self.now.max(tick);
+        if let Some((end, axes)) = self.single {
+            if end <= self.now {
+                self.single = None;
+                self.complete(axes);
+            }
+        }
+        while let Some(end) = self.continuous.filter(|end| *end <= self.now) {
+            self.complete(self.continuous_axes());
+            self.continuous = Some(end + self.continuous_period());
+        }
+        !before && self.drdy()
+    }
+
+    fn cycle_count(&self) -> CycleCount {
+        let cc = |address: u8| {
+            let address = address as usize;
+            u16::from_be_bytes([self.registers[address], self.registers[address + 1]])
+        };
+        CycleCount {x: cc(CCX_REG), y: cc(CCX_REG + 2), z: cc(CCX_REG + 4)}
+    }
+
+    fn measure_ticks(&self, axes: Axes) -> u64 {
+        self.cycle_count().measure_time_us(axes) as u64 * self.tick_rate as u64 / 1_000_000
+    }
+
+    fn continuous_axes(&self) -> Axes {
+        Axes::from(self.registers[CMM_REG as usize] >> 4)
+    }
+
+    /// TMRC period, at least one measurement
+    fn continuous_period(&self) -> u64 {
+        let rate = UpdateRate::try_from(self.registers[TMRC_REG as usize]).unwrap_or_default();
+        let period = (self.tick_rate as f64 / f32::from(rate) as f64) as u64;
+        period.max(self.measure_ticks(self.continuous_axes())).max(1)
+    }
+
+    /// results of `axes` to MX..MZ, DRDY set
+    fn complete(&mut self, axes: Axes) {
+        let cc = self.cycle_count();
+        let measured = [(axes.x, cc.x), (axes.y, cc.y), (axes.z, cc.z)];
+        for (axis, (on, cc)) in measured.into_iter().enumerate() {
+            if !on {continue;}
+            let nt = self.field_nt[axis] + self.noise();
+            let counts = (nt as i64 * CycleCount::gain_x30(cc) / 30_000) as i32;
+            let address = (MX_REG as usize) + 3 * axis;
+            self.registers[address..address + 3].copy_from_slice(&counts.to_be_bytes()[1..]);
+        }
+        self.registers[STATUS_REG as usize] |= DRDY;
+    }
+
+    fn noise(&mut self) -> i32 {
+        if self.noise_nt == 0 {
+            return 0;
+        }
+        self.seed ^= self.seed << 13;
+        self.seed ^= self.seed >> 17;
+        self.seed ^= self.seed << 5;
+        (self.seed % (2 * self.noise_nt as u32 + 1)) as i32 - self.noise_nt
+    }
+
+    /// ## one SPI transaction: address byte, then data at increasing addresses
+    fn transaction(&mut self, bytes: &mut [u8]) {
+        let Some((&mut first, data)) = bytes.split_first_mut() else {return};
+        let address = first & !READ_FLAG;
+        if first & READ_FLAG != 0 {
+            for (offset, byte) in data.iter_mut().enumerate() {
+                *byte = self.registers[(address as usize + offset) % self.registers.len()];
+            }
+            // reading any measurement result clears DRDY
+            let end = address as usize + data.len();
+            if (address as usize) < MZ_END as usize && end > MX_REG as usize {
+                self.registers[STATUS_REG as usize] &= !DRDY;
+            }
+        } else {
+            for (offset, byte) in data.iter().enumerate() {
+                self.write(((address as usize + offset) % self.registers.len()) as u8, *byte);
+            }
+        }
+    }
+
+    fn write(&mut self, address: u8, value: u8) {
+        // read only: results, BIST result bits, status, handshake, REVID
+        if (MX_REG..MZ_END).contains(&address) || (STATUS_REG..=REVID_REG).contains(&address) {
+            return;
+        }
+        if address == BIST_REG {
+            self.registers[address as usize] = value & 0x8F;
+            return;
+        }
+        self.registers[address as usize] = value;
+        match address {
+            POLL_REG => {
+                let axes = Axes::from(value >> 4);
+                self.registers[STATUS_REG as usize] &= !DRDY;
+                if u8::from(axes) != 0 {
+                    self.single = Some((self.now + self.measure_ticks(axes), axes));
+                }
+            },
+            CMM_REG => {
+                self.registers[STATUS_REG as usize] &= !DRDY;
+                self.continuous = (value & START != 0 && u8::from(self.continuous_axes()) != 0)
+                    .then(|| self.now + self.continuous_period());
+            },
+            _ => {},
+        }
+    }
+}
+
+/// SPI bus to the chip, transfers while the chip is not selected see nothing
+pub struct Spi(pub Rc<RefCell<Chip>>);
+
+impl Transfer<u8> for Spi {
+    type Error = Infallible;
+
+    fn transfer<'w>(&mut self, words: &'w mut [u8]) -> Result<&'w [u8], Infallible> {
+        let mut chip = self.0.borrow_mut();
+        if chip.selected {
+            chip.transaction(words);
+        }
+        Ok(words)
+    }
+}
+
+impl Write<u8> for Spi {
+    type Error = Infallible;
+
+    fn write(&mut self, words: &[u8]) -> Result<(), Infallible> {
+        let mut chip = self.0.borrow_mut();
+        if chip.selected {
+            chip.transaction(&mut words.to_vec());
+        }
+        Ok(())
+    }
+}
+
+/// chip select, active low
+pub struct ChipSelect(pub Rc<RefCell<Chip>>);
+
+impl OutputPin for ChipSelect {
+    type Error = Infallible;
+
+    fn set_low(&mut self) -> Result<(), Infallible> {
+        self.0.borrow_mut().selected = true;
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Infallible> {
+        self.0.borrow_mut().selected = false;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rm3100::{Config, RM3100};
+
+    fn sensor(field_nt: [i32; 3]) -> (Rc<RefCell<Chip>>, RM3100<Spi, ChipSelect>) {
+        let chip = Rc::new(RefCell::new(Chip::new(field_nt, 0, 1_000_000)));
+        let sensor = RM3100::new(Spi(chip.clone()), ChipSelect(chip.clone()), Config::default());
+        (chip, sensor)
+    }
+
+    #[test]
+    fn driver_measures_the_field() {
+        let (chip, mut sensor) = sensor([10_000, -20_000, 0]);
+        assert!(sensor.check_connect(REVID));
+        // CMM resets to 0, DRDM alarm/full
+        assert!(!sensor.check_config());
+        sensor.reinit().set_cycle_count(100);
+        assert!(sensor.check_config());
+
+        sensor.start_single_measure(true, true, false);
+        let end = CycleCount {x: 100, y: 100, z: 100}.measure_time_us(Axes {x: true, y: true, z: false}) as u64;
+        assert_eq!(chip.borrow().next_event(), Some(end));
+        assert!(!chip.borrow_mut().advance(end - 1));
+        assert!(chip.borrow_mut().advance(end));
+        // 38.33 LSB/uT at cc 100, z not measured
+        assert_eq!(sensor.read_mag(), [383, -766, 0]);
+        assert!(!chip.borrow().drdy());
+    }
+
+    #[test]
+    fn continuous_measurement_at_tmrc() {
+        let (chip, mut sensor) = sensor([1_000, 0, 0]);
+        sensor.set_update_rate(UpdateRate::Hz75);
+        sensor.start_continuous_measure(true, false, false);
+        // 1/75s at 1MHz ticks
+        assert_eq!(chip.borrow().next_event(), Some(13_333));
+        assert!(chip.borrow_mut().advance(13_333));
+        assert_eq!(sensor.read_magx(), 75);
+        assert!(chip.borrow_mut().advance(26_666));
+        sensor.stop_continuous_measure();
+        assert_eq!(chip.borrow().next_event(), None);
+    }
+
+    #[test]
+    fn continuous_measurement_survives_config() {
+        let (chip, mut sensor) = sensor([1_000, 0, 0]);
+        sensor.reinit().set_update_rate(UpdateRate::Hz75);
+        sensor.start_continuous_measure(true, true, true);
+        assert!(sensor.is_continuous());
+        let drdy = |chip: &Rc<RefCell<Chip>>| {
+            let next = chip.borrow().next_event().expect("continuous measurement stopped");
+            chip.borrow_mut().advance(next)
+        };
+        assert!(drdy(&chip));
+        sensor.read_mag();
+        // host SetConfig
+        let config = Config { cc: CycleCount { x: 50, y: 50, z: 50 }, ..sensor.get_config() };
+        sensor.set_config(config);
+        assert!(sensor.check_config());
+        assert!(drdy(&chip));
+        assert_eq!(sensor.read_magx(), 20);
+        // health check recovery
+        sensor.reinit();
+        assert!(drdy(&chip));
+        sensor.stop_continuous_measure();
+        sensor.reinit();
+        assert_eq!(chip.borrow().next_event(), None);
+    }
+
+    /// bus on which every transaction takes 100us
+    struct Clocked(Spi);
+
+    impl Clocked {
+        fn tick(&self) {
+            let mut chip = self.0.0.borrow_mut();
+            let now = chip.now();
+            chip.advance(now + 100);
+        }
+    }
+
+    impl Transfer<u8> for Clocked {
+        type Error = Infallible;
+
+        fn transfer<'w>(&mut self, words: &'w mut [u8]) -> Result<&'w [u8], Infallible> {
+            self.tick();
+            self.0.transfer(words)
+        }
+    }
+
+    impl Write<u8> for Clocked {
+        type Error = Infallible;
+
+        fn write(&mut self, words: &[u8]) -> Result<(), Infallible> {
+            self.tick();
+            self.0.write(words)
+        }
+    }
+
+    #[test]
+    fn averaged_single_measurements() {
+        let chip = Rc::new(RefCell::new(Chip::new([10_000, -20_000, 0], 0, 1_000_000)));
+        let mut sensor = RM3100::new(Clocked(Spi(chip.clone())), ChipSelect(chip.clone()), Config::default());
+        sensor.reinit().set_cycle_count(100);
+        let start = chip.borrow().now();
+        let averaged = sensor.measure_averaged(Axes {x: true, y: true, z: false}, 4).unwrap();
+        assert_eq!(averaged.count, 4);
+        assert_eq!(averaged.mean, [383.0, -766.0, 0.0]);
+        assert_eq!(averaged.std_dev, [0.0; 3]);
+        assert_eq!((averaged.min, averaged.max), ([383, -766, 0], [383, -766, 0]));
+        // 4 measurements of 2.35ms, each transaction 100us
+        let elapsed = chip.borrow().now() - start;
+        assert!((9_400..=10_400).contains(&elapsed), "{}", elapsed);
+
+        // +-1000nT uniform noise: sd 577nT, 22 counts
+        chip.borrow_mut().noise_nt = 1_000;
+        let averaged = sensor.measure_averaged(Axes::XYZ, 400).unwrap();
+        for axis in 0..3 {
+            assert!(averaged.min[axis] as f32 <= averaged.mean[axis]);
+            assert!(averaged.mean[axis] <= averaged.max[axis] as f32);
+            assert!((18.0..26.0).contains(&averaged.std_dev[axis]), "{:?}", averaged);
+        }
+        assert!((averaged.mean[0] - 383.0).abs() < 5.0);
+        assert_eq!(sensor.measure_averaged(Axes::X, 0), None);
+        assert_eq!(sensor.measure_averaged(Axes::from(0), 10), None);
+        // CMM results would mix in
+        sensor.start_continuous_measure(true, true, true);
+        assert_eq!(sensor.measure_averaged(Axes::X, 10), None);
+        sensor.stop_continuous_measure();
+        assert!(sensor.measure_averaged(Axes::X, 10).is_some());
+    }
+}
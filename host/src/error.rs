@@ -0,0 +1,61 @@
+use std::{fmt, io};
+
+use rm3100::protocol::{FrameError, StatusCode};
+
+/// ## errors talking to the firmware
+#[derive(Debug)]
+pub enum Error {
+    /// port could not be opened or read/written
+    Io(io::Error),
+    /// no reply within the device timeout
+    Timeout,
+    /// corrupted frame(CRC, length) received
+    Frame(FrameError),
+    /// frame is not a valid response
+    Decode(StatusCode),
+    /// device rejected the request with this status
+    Device(StatusCode),
+    /// reply of an unexpected type for command
+    Unexpected { command: u8 },
+    /// stream frames lost between expected and received sequence
+    Gap { expected: u32, received: u32 },
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(error) => write!(f, "io: {}", error),
+            Error::Timeout => write!(f, "no reply from device"),
+            Error::Frame(error) => write!(f, "bad frame for command {:#04x}: {:?}", error.command, error.status),
+            Error::Decode(status) => write!(f, "malformed reply: {:?}", status),
+            Error::Device(status) => write!(f, "device error: {:?}", status),
+            Error::Unexpected { command } => write!(f, "unexpected reply to command {:#04x}", command),
+            Error::Gap { expected, received } => {
+                write!(f, "stream frames {} to {} lost", expected, received.wrapping_sub(1))
+            },
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Error::Io(error)
+    }
+}
+
+impl From<serialport::Error> for Error {
+    fn from(error: serialport::Error) -> Self {
+        Error::Io(error.into())
+    }
+}
@@ -0,0 +1,126 @@
+//! recordings as text
+//!
+//! both exporters give per sample: sequence, time since the first sample(s,
+//! from DRDY ticks), trigger and DRDY tick, x/y/z counts and x/y/z in nT by
+//! the recorded gain
+
+use std::io::{self, Write};
+
+use crate::json;
+use crate::recording::{Header, Record, Sink, Timeline};
+
+/// ## CSV, one header row then one row per sample
+pub struct Csv<W: Write> {
+    out: W,
+    header: Header,
+    timeline: Timeline,
+}
+
+impl<W: Write> Csv<W> {
+    pub fn new(mut out: W, header: &Header) -> io::Result<Self> {
+        writeln!(out, "sequence,time_s,trigger_tick,drdy_tick,x,y,z,x_nt,y_nt,z_nt")?;
+        Ok(Csv {out, header: *header, timeline: Timeline::new(header.tick_rate)})
+    }
+
+    pub fn into_inner(self) -> W {self.out}
+}
+
+impl<W: Write> Sink for Csv<W> {
+    fn write(&mut self, record: &Record) -> io::Result<()> {
+        let sample = &record.sample;
+        let [x, y, z] = sample.mag;
+        let [x_nt, y_nt, z_nt] = self.header.to_nanotesla(sample.mag);
+        writeln!(self.out, "{},{:.6},{},{},{},{},{},{:.1},{:.1},{:.1}",
+            record.sequence, self.timeline.seconds(sample.drdy_tick), sample.trigger_tick, sample.drdy_tick,
+            x, y, z, x_nt, y_nt, z_nt)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// ## JSON Lines, the header object then one object per sample
+pub struct JsonLines<W: Write> {
+    out: W,
+    header: Header,
+    timeline: Timeline,
+}
+
+impl<W: Write> JsonLines<W> {
+    pub fn new(mut out: W, header: &Header) -> io::Result<Self> {
+        writeln!(out, "{}", json::header(header))?;
+        Ok(JsonLines {out, header: *header, timeline: Timeline::new(header.tick_rate)})
+    }
+
+    pub fn into_inner(self) -> W {self.out}
+}
+
+impl<W: Write> Sink for JsonLines<W> {
+    fn write(&mut self, record: &Record) -> io::Result<()> {
+        let time_s = self.timeline.seconds(record.sample.drdy_tick);
+        writeln!(self.out, "{}", json::record(record, time_s, &self.header))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::recording::{Reader, Writer};
+    use rm3100::protocol::{DeviceInfo, Sample};
+    use rm3100::{Axes, Config};
+
+    fn recording() -> Vec<u8> {
+        let info = DeviceInfo {
+            protocol_version: 1,
+            firmware_version: [0, 1, 0],
+            tick_rate: 1000,
+            revid: 0x22,
+            buffer_size: 32,
+            uptime_ms: 0,
+            config: Config::default(),
+            axes: Axes::XYZ,
+        };
+        let header = Header { start_ms: 1_700_000_000_000, ..Header::new(info) };
+        let mut writer = Writer::new(Vec::new(), &header).unwrap();
+        for (sequence, drdy_tick) in [(0, u32::MAX - 249), (1, 250)] {
+            let sample = Sample { mag: [75, -150, 0], trigger_tick: drdy_tick.wrapping_sub(10), drdy_tick };
+            writer.write(&Record { sequence, sample }).unwrap();
+        }
+        writer.into_inner()
+    }
+
+    fn export<S: Sink>(sink: impl FnOnce(Vec<u8>, &Header) -> io::Result<S>, into_inner: fn(S) -> Vec<u8>) -> String {
+        let bytes = recording();
+        let reader = Reader::new(&bytes[..]).unwrap();
+        let mut sink = sink(Vec::new(), reader.header()).unwrap();
+        assert_eq!(reader.copy_to(&mut sink).unwrap(), 2);
+        String::from_utf8(into_inner(sink)).unwrap()
+    }
+
+    #[test]
+    fn csv_rows() {
+        let text = export(Csv::new, Csv::into_inner);
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines, [
+            "sequence,time_s,trigger_tick,drdy_tick,x,y,z,x_nt,y_nt,z_nt",
+            "0,0.000000,4294967036,4294967046,75,-150,0,1000.0,-2000.0,0.0",
+            "1,0.500000,240,250,75,-150,0,1000.0,-2000.0,0.0",
+        ]);
+    }
+
+    #[test]
+    fn json_lines() {
+        let text = export(JsonLines::new, JsonLines::into_inner);
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("{\"format_version\":1,\"info\":{\"protocol_version\":1,"));
+        assert!(lines[0].ends_with(",\"gain\":[75,75,75],\"tick_rate\":1000,\"start_ms\":1700000000000}"));
+        assert_eq!(lines[2], "{\"sequence\":1,\"time_s\":0.5,\"trigger_tick\":240,\"drdy_tick\":250,\
+            \"mag\":[75,-150,0],\"nt\":[1000,-2000,0]}");
+    }
+}
@@ -0,0 +1,100 @@
+//! JSON text of protocol types for scripts, one object per value
+
+use std::fmt::Write;
+
+use rm3100::filter::FilterConfig;
+use rm3100::protocol::{DeviceInfo, Sample};
+use rm3100::{Axes, Config, CycleCount, DRDM};
+
+use crate::recording::{Header, Record, FORMAT_VERSION};
+
+/// ## `value` as a JSON string
+pub fn string(value: &str) -> String {
+    let mut out = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c if (c as u32) < 0x20 => {let _ = write!(out, "\\u{:04x}", c as u32);},
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// letters of measured axes, e.g. "xz"
+pub fn axes(axes: Axes) -> String {
+    let letters = [('x', axes.x), ('y', axes.y), ('z', axes.z)];
+    string(&letters.iter().filter(|(_, on)| *on).map(|(letter, _)| letter).collect::<String>())
+}
+
+/// same names as the console
+pub fn drdm(drdm: DRDM) -> String {
+    let name = match drdm {
+        DRDM::AlarmFull => "alarmfull",
+        DRDM::Any => "any",
+        DRDM::Full => "full",
+        DRDM::Alarm => "alarm",
+    };
+    string(name)
+}
+
+/// {"cc":[x,y,z],"rate_hz":r,"drdm":name}
+pub fn config(config: &Config) -> String {
+    let CycleCount { x, y, z } = config.cc;
+    format!("{{\"cc\":[{},{},{}],\"rate_hz\":{},\"drdm\":{}}}",
+        x, y, z, f32::from(config.rate), drdm(config.drdm))
+}
+
+/// {"filter":kind, parameters}, same kind names as `filter set`
+pub fn filter(filter: &FilterConfig) -> String {
+    match *filter {
+        FilterConfig::None => "{\"filter\":\"none\"}".into(),
+        FilterConfig::MovingAverage { len } => format!("{{\"filter\":\"average\",\"len\":{}}}", len),
+        FilterConfig::Ema { alpha } => format!("{{\"filter\":\"ema\",\"alpha\":{}}}", alpha),
+        FilterConfig::Biquad(coefficients) => format!(
+            "{{\"filter\":\"biquad\",\"b\":[{},{},{}],\"a\":[{},{}]}}",
+            coefficients.b0, coefficients.b1, coefficients.b2, coefficients.a1, coefficients.a2,
+        ),
+        FilterConfig::Median { len } => format!("{{\"filter\":\"median\",\"len\":{}}}", len),
+        FilterConfig::Cic { order, decimation } => {
+            format!("{{\"filter\":\"cic\",\"order\":{},\"decimation\":{}}}", order, decimation)
+        },
+    }
+}
+
+pub fn info(info: &DeviceInfo) -> String {
+    let [major, minor, patch] = info.firmware_version;
+    format!(
+        "{{\"protocol_version\":{},\"firmware_version\":[{},{},{}],\"tick_rate\":{},\"revid\":{},\
+        \"buffer_size\":{},\"uptime_ms\":{},\"config\":{},\"axes\":{}}}",
+        info.protocol_version, major, minor, patch, info.tick_rate, info.revid,
+        info.buffer_size, info.uptime_ms, config(&info.config), axes(info.axes),
+    )
+}
+
+/// ticks, counts and nT (gain from cycle count `cc`)
+pub fn sample(sample: &Sample, cc: CycleCount) -> String {
+    let [x, y, z] = sample.mag;
+    let [nx, ny, nz] = cc.to_nanotesla(sample.mag);
+    format!("{{\"trigger_tick\":{},\"drdy_tick\":{},\"mag\":[{},{},{}],\"nt\":[{},{},{}]}}",
+        sample.trigger_tick, sample.drdy_tick, x, y, z, nx, ny, nz)
+}
+
+/// header of a recording
+pub fn header(header: &Header) -> String {
+    let [x, y, z] = header.gain;
+    format!("{{\"format_version\":{},\"info\":{},\"config\":{},\"gain\":[{},{},{}],\"tick_rate\":{},\"start_ms\":{}}}",
+        FORMAT_VERSION, info(&header.info), config(&header.config), x, y, z, header.tick_rate, header.start_ms)
+}
+
+/// recorded sample at `time_s`, nT by the recorded gain to 0.1
+pub fn record(record: &Record, time_s: f64, header: &Header) -> String {
+    let sample = &record.sample;
+    let [x, y, z] = sample.mag;
+    let [nx, ny, nz] = header.to_nanotesla(sample.mag).map(|nt| (nt * 10.0).round() / 10.0);
+    format!("{{\"sequence\":{},\"time_s\":{},\"trigger_tick\":{},\"drdy_tick\":{},\"mag\":[{},{},{}],\"nt\":[{},{},{}]}}",
+        record.sequence, time_s, sample.trigger_tick, sample.drdy_tick, x, y, z, nx, ny, nz)
+}
@@ -0,0 +1,20 @@
+//! host side of the rm3100 firmware
+//!
+//! `Device` talks the framed protocol of `rm3100::protocol` over the app's
+//! CDC serial port, so host tools get typed requests instead of bytes;
+//! `recording` stores streamed samples with their setup, `export` turns
+//! recordings into CSV or JSON Lines, `emulator` stands in for the device
+//! in tests
+
+pub mod device;
+pub mod emulator;
+pub mod error;
+pub mod export;
+pub mod json;
+pub mod recording;
+
+pub use device::{Device, Stream};
+pub use error::{Error, Result};
+pub use rm3100;
+pub use rm3100::protocol::{DeviceInfo, Sample};
+pub use rm3100::{Axes, Config, CycleCount, UpdateRate, DRDM};
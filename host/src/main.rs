@@ -0,0 +1,429 @@
+//! rm3100 command line tool
+//!
+//! every command prints JSON on stdout(one object per line), errors go to
+//! stderr with exit code 1, usage errors exit with 2
+
+use std::env;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::time::{Duration, Instant};
+
+use rm3100_host::rm3100::filter::{Coefficients, Ema, FilterConfig};
+use rm3100_host::rm3100::health::Fault;
+use rm3100_host::rm3100::protocol::{StatusCode, VERSION};
+use rm3100_host::rm3100::server;
+use rm3100_host::export::{Csv, JsonLines};
+use rm3100_host::recording::{Header, Reader, Record, Sink, Writer};
+use rm3100_host::{emulator, json, Axes, Config, CycleCount, Device, Error, Result, UpdateRate, DRDM};
+
+const USAGE: &str = "\
+usage: rm3100 [--port PATH] [--timeout MS] COMMAND
+
+commands:
+  info                  device info
+  config get            sensor config
+  config set [cc N | cc X Y Z] [rate HZ] [drdm alarmfull|any|full|alarm]
+                        change the sensor config, print the applied config
+  filter get            filter samples pass before the device buffer
+  filter set none | average N | ema SAMPLES | median N | cic ORDER DECIMATION
+             | lowpass HZ SAMPLE_HZ | highpass HZ SAMPLE_HZ
+                        replace the filter, print it; ema takes a time
+                        constant, lowpass/highpass design a Butterworth biquad
+                        for samples at SAMPLE_HZ
+  read [--count N]      pop N buffered samples(default 1), waiting for each
+  stream --duration S [--rate HZ] [--out FILE]
+                        stream samples for S seconds as JSON lines(export
+                        jsonl), --rate runs the timer trigger meanwhile,
+                        --out records them to FILE instead
+  export FILE [--format csv|jsonl]
+                        recording as CSV(default) or JSON lines, no device
+  selftest              check protocol, sensor, health, config and sampling,
+                        clears the sample buffer; exit code 1 if a check fails,
+                        checks the firmware does not support are skipped
+  dump-registers [--all]
+                        sensor registers 0x00..0x23, --all up to 0x36 with
+                        the measurement registers, clearing DRDY
+  emulate [--rate HZ]   run an emulated device on a pseudo terminal until
+                        killed, print its port; --rate drives its trigger
+                        input, no device needed
+
+the port is --port, else $RM3100_PORT, else /dev/ttyACM0";
+
+const DEFAULT_PORT: &str = "/dev/ttyACM0";
+/// sensor range(nT) per axis
+const FULL_SCALE_NT: i32 = 800_000;
+/// timer rate(mHz) of the selftest sample
+const SELFTEST_RATE: u32 = 10_000;
+/// registers up to the REVID register(0x36)
+const REGISTER_COUNT: u8 = 0x37;
+/// MX, the first measurement register, reading from it on clears DRDY
+const MX: u8 = 0x24;
+/// longest a stream waits past its duration when no samples arrive
+const STREAM_POLL: Duration = Duration::from_millis(100);
+
+struct Options {
+    port: String,
+    timeout: Duration,
+}
+
+enum Action {
+    Help,
+    Info,
+    ConfigGet,
+    ConfigSet(Changes),
+    FilterGet,
+    FilterSet(FilterConfig),
+    Read { count: usize },
+    /// rate in mHz
+    Stream { duration: Duration, rate: Option<u32>, out: Option<PathBuf> },
+    Selftest,
+    /// include the measurement registers
+    DumpRegisters { all: bool },
+    Export { path: PathBuf, format: Format },
+    /// trigger input rate in mHz
+    Emulate { rate: Option<u32> },
+}
+
+enum Format {
+    Csv,
+    JsonLines,
+}
+
+/// settings of `config set`, the rest is kept
+#[derive(Default)]
+struct Changes {
+    cc: Option<CycleCount>,
+    rate: Option<UpdateRate>,
+    drdm: Option<DRDM>,
+}
+
+impl Changes {
+    fn apply(&self, config: Config) -> Config {
+        Config {
+            cc: self.cc.unwrap_or(config.cc),
+            rate: self.rate.unwrap_or(config.rate),
+            drdm: self.drdm.unwrap_or(config.drdm),
+        }
+    }
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let (options, action) = match parse(&args) {
+        Ok(parsed) => parsed,
+        Err(message) => {
+            eprintln!("{}\n\n{}", message, USAGE);
+            return ExitCode::from(2);
+        },
+    };
+    match run(&options, action) {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::FAILURE,
+        Err(error) => {
+            eprintln!("error: {}", error);
+            ExitCode::FAILURE
+        },
+    }
+}
+
+// # arguments
+
+fn parse(args: &[String]) -> std::result::Result<(Options, Action), String> {
+    let mut options = Options {
+        port: env::var("RM3100_PORT").unwrap_or_else(|_| DEFAULT_PORT.into()),
+        timeout: rm3100_host::device::DEFAULT_TIMEOUT,
+    };
+    let (mut count, mut duration, mut rate, mut out, mut format) = (None, None, None, None, None);
+    let mut all = false;
+    let mut words = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().map(String::as_str).ok_or(format!("{} needs a value", arg));
+        match arg.as_str() {
+            "-h" | "--help" => return Ok((options, Action::Help)),
+            "--port" => options.port = value()?.into(),
+            "--timeout" => options.timeout = Duration::from_millis(number(value()?)?),
+            "--count" => count = Some(number(value()?)?),
+            "--duration" => duration = Some(Duration::from_secs_f64(positive(value()?)?)),
+            "--rate" => rate = Some((positive(value()?)? * 1000.0).round() as u32),
+            "--out" => out = Some(PathBuf::from(value()?)),
+            "--all" => all = true,
+            "--format" => format = Some(match value()? {
+                "csv" => Format::Csv,
+                "jsonl" => Format::JsonLines,
+                other => return Err(format!("unknown format {}", other)),
+            }),
+            option if option.starts_with("--") => return Err(format!("unknown option {}", option)),
+            word => words.push(word),
+        }
+    }
+    let action = match words.as_slice() {
+        ["help"] => Action::Help,
+        ["info"] => Action::Info,
+        ["config", "get"] => Action::ConfigGet,
+        ["config", "set", settings @ ..] => Action::ConfigSet(parse_changes(settings)?),
+        ["filter", "get"] => Action::FilterGet,
+        ["filter", "set", words @ ..] => Action::FilterSet(parse_filter(words)?),
+        ["read"] => Action::Read { count: count.take().unwrap_or(1) },
+        ["stream"] => Action::Stream {
+            duration: duration.take().ok_or("stream needs --duration")?,
+            rate: rate.take(),
+            out: out.take(),
+        },
+        ["selftest"] => Action::Selftest,
+        ["dump-registers"] => Action::DumpRegisters { all: std::mem::take(&mut all) },
+        ["export", path] => Action::Export { path: path.into(), format: format.take().unwrap_or(Format::Csv) },
+        ["emulate"] => Action::Emulate { rate: rate.take() },
+        [] => return Err("no command".into()),
+        _ => return Err(format!("unknown command {}", words.join(" "))),
+    };
+    if count.is_some() || duration.is_some() || rate.is_some() || out.is_some() || format.is_some() || all {
+        return Err("option not taken by this command".into());
+    }
+    Ok((options, action))
+}
+
+/// same words as the console `set` commands
+fn parse_changes(settings: &[&str]) -> std::result::Result<Changes, String> {
+    if settings.is_empty() {
+        return Err("nothing to set".into());
+    }
+    let mut changes = Changes::default();
+    let mut words = settings.iter().peekable();
+    while let Some(word) = words.next() {
+        match *word {
+            "cc" => {
+                let mut values = Vec::new();
+                while let Some(cc) = words.peek().and_then(|word| word.parse::<u16>().ok()) {
+                    values.push(cc);
+                    words.next();
+                }
+                changes.cc = Some(match values[..] {
+                    [cc] if cc > 0 => CycleCount { x: cc, y: cc, z: cc },
+                    [x, y, z] if x > 0 && y > 0 && z > 0 => CycleCount { x, y, z },
+                    _ => return Err("cc takes 1 or 3 cycle counts above 0".into()),
+                });
+            },
+            "rate" => {
+                let rate = words.next().ok_or("rate needs a value")?;
+                changes.rate = Some(UpdateRate::from(positive(rate)? as f32));
+            },
+            "drdm" => changes.drdm = Some(match words.next().copied() {
+                Some("alarmfull") => DRDM::AlarmFull,
+                Some("any") => DRDM::Any,
+                Some("full") => DRDM::Full,
+                Some("alarm") => DRDM::Alarm,
+                _ => return Err("drdm takes alarmfull, any, full or alarm".into()),
+            }),
+            setting => return Err(format!("unknown setting {}", setting)),
+        }
+    }
+    Ok(changes)
+}
+
+/// filter and its parameters, ranges are checked by the device
+fn parse_filter(words: &[&str]) -> std::result::Result<FilterConfig, String> {
+    let q = core::f32::consts::FRAC_1_SQRT_2;
+    Ok(match words {
+        ["none"] => FilterConfig::None,
+        ["average", len] => FilterConfig::MovingAverage { len: number(len)? },
+        ["ema", samples] => FilterConfig::Ema { alpha: Ema::alpha(positive(samples)? as f32) },
+        ["median", len] => FilterConfig::Median { len: number(len)? },
+        ["cic", order, decimation] => FilterConfig::Cic { order: number(order)?, decimation: number(decimation)? },
+        ["lowpass" | "highpass", cutoff, sample_rate] => {
+            let (cutoff, sample_rate) = (positive(cutoff)? as f32, positive(sample_rate)? as f32);
+            if cutoff >= sample_rate / 2.0 {
+                return Err("cutoff must be below half the sample rate".into());
+            }
+            FilterConfig::Biquad(match words[0] {
+                "lowpass" => Coefficients::lowpass(cutoff, sample_rate, q),
+                _ => Coefficients::highpass(cutoff, sample_rate, q),
+            })
+        },
+        _ => return Err("filter set takes none, average N, ema SAMPLES, median N, cic ORDER DECIMATION, \
+            lowpass HZ SAMPLE_HZ or highpass HZ SAMPLE_HZ".into()),
+    })
+}
+
+fn number<T: std::str::FromStr>(word: &str) -> std::result::Result<T, String> {
+    word.parse().map_err(|_| format!("{} is not a number", word))
+}
+
+fn positive(word: &str) -> std::result::Result<f64, String> {
+    match number::<f64>(word)? {
+        value if value > 0.0 && value.is_finite() => Ok(value),
+        _ => Err(format!("{} is not above 0", word)),
+    }
+}
+
+// # commands
+
+/// return false if a selftest check failed
+fn run(options: &Options, action: Action) -> Result<bool> {
+    match action {
+        Action::Help => {
+            println!("{}", USAGE);
+            return Ok(true);
+        },
+        Action::Export { path, format } => {
+            let reader = Reader::new(BufReader::new(File::open(path)?))?;
+            let header = *reader.header();
+            let out = io::stdout().lock();
+            match format {
+                Format::Csv => reader.copy_to(&mut Csv::new(out, &header)?)?,
+                Format::JsonLines => reader.copy_to(&mut JsonLines::new(out, &header)?)?,
+            };
+            return Ok(true);
+        },
+        Action::Emulate { rate } => {
+            let options = emulator::Options { trigger_rate: rate.unwrap_or(0), ..emulator::Options::default() };
+            let (path, handle) = emulator::pty(options)?;
+            println!("{{\"port\":{}}}", json::string(&path));
+            // stdout may be a pipe to a script waiting for the port
+            io::stdout().flush()?;
+            handle.wait()?;
+            return Ok(true);
+        },
+        _ => {},
+    }
+    let mut device = Device::open(&options.port)?;
+    device.set_timeout(options.timeout);
+    match action {
+        Action::Help | Action::Export { .. } | Action::Emulate { .. } => {},
+        Action::Info => println!("{}", json::info(&device.info()?)),
+        Action::ConfigGet => println!("{}", json::config(&device.config()?)),
+        Action::ConfigSet(changes) => {
+            let config = changes.apply(device.config()?);
+            println!("{}", json::config(&device.configure(config)?));
+        },
+        Action::FilterGet => println!("{}", json::filter(&device.filter()?)),
+        Action::FilterSet(filter) => println!("{}", json::filter(&device.set_filter(filter)?)),
+        Action::Read { count } => {
+            let cc = device.config()?.cc;
+            for _ in 0..count {
+                println!("{}", json::sample(&device.read_sample()?, cc));
+            }
+        },
+        Action::Stream { duration, rate, out } => {
+            let header = Header::new(device.info()?);
+            device.set_timeout(options.timeout.min(STREAM_POLL));
+            let samples = match &out {
+                Some(path) => {
+                    let mut writer = Writer::new(BufWriter::new(File::create(path)?), &header)?;
+                    stream(&mut device, duration, rate, &mut writer)?
+                },
+                None => stream(&mut device, duration, rate, &mut JsonLines::new(io::stdout().lock(), &header)?)?,
+            };
+            if let Some(path) = out {
+                let path = json::string(&path.to_string_lossy());
+                println!("{{\"samples\":{},\"out\":{}}}", samples, path);
+            }
+        },
+        Action::Selftest => return selftest(&mut device),
+        Action::DumpRegisters { all } => {
+            let count = if all {
+                eprintln!("warning: reading the measurement registers clears DRDY, a pending sample may be lost");
+                REGISTER_COUNT
+            } else {
+                MX
+            };
+            let registers = device.read_registers(0, count)?;
+            let fields: Vec<String> = registers.iter().enumerate()
+                .map(|(address, value)| format!("\"{:#04x}\":{}", address, value))
+                .collect();
+            println!("{{{}}}", fields.join(","));
+        },
+    }
+    Ok(true)
+}
+
+/// ## write samples of `duration` to sink
+///
+/// return samples written, lost stream frames are reported on stderr.
+/// The timer is stopped on every exit, the first error is returned
+fn stream(device: &mut Device, duration: Duration, rate: Option<u32>, sink: &mut impl Sink) -> Result<u64> {
+    if let Some(rate) = rate {
+        device.start_timer(rate)?;
+    }
+    let written = write_stream(device, duration, sink);
+    // the device would keep sampling into its buffer and overflow
+    let stopped = if rate.is_some() {device.stop_timer()} else {Ok(())};
+    let written = written?;
+    stopped?;
+    sink.flush()?;
+    Ok(written)
+}
+
+/// stream until `duration` is over, streaming stops when done
+fn write_stream(device: &mut Device, duration: Duration, sink: &mut impl Sink) -> Result<u64> {
+    let mut written = 0;
+    let end = Instant::now() + duration;
+    let mut stream = device.stream()?;
+    while Instant::now() < end {
+        match stream.next() {
+            Some(Ok(sample)) => {
+                sink.write(&Record { sequence: stream.frame(), sample })?;
+                written += 1;
+            },
+            Some(Err(Error::Timeout)) | None => {},
+            Some(Err(error @ Error::Gap { .. })) => eprintln!("warning: {}", error),
+            Some(Err(error)) => return Err(error),
+        }
+    }
+    Ok(written)
+}
+
+/// passed(None if skipped), detail
+type Outcome = (Option<bool>, String);
+
+fn failed(error: Error) -> Outcome {
+    match error {
+        // e.g. the gradiometer firmware, which answers a subset of the commands
+        Error::Device(StatusCode::UnknownCommand) => (None, "not supported by the firmware".into()),
+        error => (Some(false), error.to_string()),
+    }
+}
+
+fn selftest(device: &mut Device) -> Result<bool> {
+    let info = device.info()?;
+    let checks = [
+        ("protocol", (Some(info.protocol_version == VERSION), format!("version {}", info.protocol_version))),
+        ("sensor", (Some(info.revid == server::REVID), format!("revid {:#04x}", info.revid))),
+        ("health", device.health().map_or_else(failed, |health| {
+            (Some(health.fault == Fault::None), format!("fault {:?}, faults {}", health.fault, health.faults))
+        })),
+        ("config", device.configure(info.config).map_or_else(failed, |applied| {
+            (Some(applied == info.config), format!("applied {:?}", applied))
+        })),
+        ("sample", sample_check(device, info.config.cc, info.axes).unwrap_or_else(failed)),
+    ];
+    // skipped checks do not fail the selftest
+    let ok = checks.iter().all(|(_, (passed, _))| *passed != Some(false));
+    let checks: Vec<String> = checks.iter()
+        .map(|(name, (passed, detail))| format!(
+            "{{\"name\":\"{}\",\"ok\":{},\"skipped\":{},\"detail\":{}}}",
+            name, passed.unwrap_or(true), passed.is_none(), json::string(detail),
+        ))
+        .collect();
+    println!("{{\"ok\":{},\"checks\":[{}]}}", ok, checks.join(","));
+    Ok(ok)
+}
+
+/// ## one sample by the timer, measured axes in range and not all 0
+///
+/// the timer is restored afterwards
+fn sample_check(device: &mut Device, cc: CycleCount, axes: Axes) -> Result<Outcome> {
+    let timer = device.timer()?;
+    device.clear_buffer()?;
+    device.start_timer(SELFTEST_RATE)?;
+    let sample = device.read_sample();
+    if timer == 0 {device.stop_timer()?;} else {device.start_timer(timer)?;}
+    let nt = cc.to_nanotesla(sample?.mag);
+    let measured: Vec<i32> = nt.iter().zip([axes.x, axes.y, axes.z])
+        .filter_map(|(nt, on)| on.then_some(*nt))
+        .collect();
+    let passed = measured.iter().all(|nt| nt.abs() <= FULL_SCALE_NT) && measured.iter().any(|nt| *nt != 0);
+    Ok((Some(passed), format!("nT {:?}", nt)))
+}
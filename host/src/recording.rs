@@ -0,0 +1,303 @@
+//! binary recording of streamed samples
+//!
+//! a header describing device and sensor setup, then one record per sample,
+//! multi-byte values big endian like the protocol:
+//!
+//! | magic | format version | device info | config | gain x/y/z | tick rate | start time |
+//! | - | - | - | - | - | - | - |
+//! | "RM3100RC" | 1 byte | 28 bytes, GET_INFO payload | 8 bytes, as in SET_CONFIG | 3 * f32, LSB/uT | u32, Hz | u64, ms since the unix epoch |
+//!
+//! record: sequence(u32) + sample(20, as in READ_SAMPLES). Samples of one
+//! stream frame share its sequence, a jump by more than 1 means frames were lost
+
+use std::io::{self, Read, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rm3100::protocol::{self, DeviceInfo, Sample, CONFIG_LEN, INFO_LEN, SAMPLE_LEN};
+use rm3100::{Config, CycleCount};
+
+pub const MAGIC: [u8; 8] = *b"RM3100RC";
+pub const FORMAT_VERSION: u8 = 1;
+/// magic(8) + version(1) + info(28) + config(8) + gain(12) + tick rate(4) + start time(8)
+pub const HEADER_LEN: usize = MAGIC.len() + 1 + INFO_LEN + CONFIG_LEN + 12 + 4 + 8;
+/// sequence(4) + sample(20)
+pub const RECORD_LEN: usize = 4 + SAMPLE_LEN;
+
+/// ## what was recorded, and how
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Header {
+    /// device info when the recording started
+    pub info: DeviceInfo,
+    /// sensor config of all samples
+    pub config: Config,
+    /// x/y/z gain(LSB/uT) of the config
+    pub gain: [f32; 3],
+    /// sample ticks per second
+    pub tick_rate: u32,
+    /// wall clock at the start(ms since the unix epoch)
+    pub start_ms: u64,
+}
+
+impl Header {
+    /// ## header of a recording starting now with the config in `info`
+    pub fn new(info: DeviceInfo) -> Self {
+        let CycleCount { x, y, z } = info.config.cc;
+        let gain = |cc| CycleCount::gain_x30(cc) as f32 / 30.0;
+        let start_ms = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_millis() as u64);
+        Header {info, config: info.config, gain: [gain(x), gain(y), gain(z)], tick_rate: info.tick_rate, start_ms}
+    }
+
+    pub fn to_bytes(&self) -> [u8; HEADER_LEN] {
+        let mut bytes = [0u8; HEADER_LEN];
+        let (magic, rest) = bytes.split_at_mut(MAGIC.len());
+        magic.copy_from_slice(&MAGIC);
+        rest[0] = FORMAT_VERSION;
+        let (info, rest) = rest[1..].split_at_mut(INFO_LEN);
+        info.copy_from_slice(&self.info.to_bytes());
+        let (config, rest) = rest.split_at_mut(CONFIG_LEN);
+        config.copy_from_slice(&protocol::config_to_bytes(&self.config));
+        for (chunk, gain) in rest[..12].chunks_exact_mut(4).zip(self.gain) {
+            chunk.copy_from_slice(&gain.to_be_bytes());
+        }
+        rest[12..16].copy_from_slice(&self.tick_rate.to_be_bytes());
+        rest[16..24].copy_from_slice(&self.start_ms.to_be_bytes());
+        bytes
+    }
+
+    /// ## parse a header, `InvalidData` if it is none or of another format version
+    pub fn from_bytes(bytes: &[u8; HEADER_LEN]) -> io::Result<Self> {
+        let (magic, rest) = bytes.split_at(MAGIC.len());
+        if magic != MAGIC {
+            return Err(invalid("not an rm3100 recording"));
+        }
+        if rest[0] != FORMAT_VERSION {
+            return Err(invalid("unsupported recording format version"));
+        }
+        let (info, rest) = rest[1..].split_at(INFO_LEN);
+        let (config, rest) = rest.split_at(CONFIG_LEN);
+        let mut gain = [0f32; 3];
+        for (gain, chunk) in gain.iter_mut().zip(rest[..12].chunks_exact(4)) {
+            *gain = f32::from_be_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        }
+        let mut start_ms = [0u8; 8];
+        start_ms.copy_from_slice(&rest[16..24]);
+        Ok(Header {
+            info: DeviceInfo::from_bytes(info).map_err(|_| invalid("bad device info"))?,
+            config: protocol::config_from_bytes(config).map_err(|_| invalid("bad config"))?,
+            gain,
+            tick_rate: u32::from_be_bytes([rest[12], rest[13], rest[14], rest[15]]),
+            start_ms: u64::from_be_bytes(start_ms),
+        })
+    }
+
+    /// ## x/y/z counts to nT by the recorded gain
+    pub fn to_nanotesla(&self, mag: [i32; 3]) -> [f64; 3] {
+        let mut nt = [0f64; 3];
+        for ((nt, counts), gain) in nt.iter_mut().zip(mag).zip(self.gain) {
+            *nt = counts as f64 * 1000.0 / gain as f64;
+        }
+        nt
+    }
+}
+
+/// one recorded sample
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct Record {
+    /// sequence of the stream frame the sample came in
+    pub sequence: u32,
+    pub sample: Sample,
+}
+
+impl Record {
+    pub fn to_bytes(&self) -> [u8; RECORD_LEN] {
+        let mut bytes = [0u8; RECORD_LEN];
+        bytes[0..4].copy_from_slice(&self.sequence.to_be_bytes());
+        bytes[4..].copy_from_slice(&self.sample.to_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8; RECORD_LEN]) -> Self {
+        Record {
+            sequence: u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+            sample: Sample::from_bytes(&bytes[4..]).expect("record holds one sample"),
+        }
+    }
+}
+
+/// ## destination of records: a recording or an exporter
+pub trait Sink {
+    fn write(&mut self, record: &Record) -> io::Result<()>;
+
+    fn flush(&mut self) -> io::Result<()>;
+}
+
+/// ## writes a recording
+pub struct Writer<W: Write> {
+    out: W,
+}
+
+impl<W: Write> Writer<W> {
+    /// ## start a recording, writes the header
+    pub fn new(mut out: W, header: &Header) -> io::Result<Self> {
+        out.write_all(&header.to_bytes())?;
+        Ok(Writer {out})
+    }
+
+    pub fn into_inner(self) -> W {self.out}
+}
+
+impl<W: Write> Sink for Writer<W> {
+    fn write(&mut self, record: &Record) -> io::Result<()> {
+        self.out.write_all(&record.to_bytes())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// ## reads a recording, records by iterating
+///
+/// a record cut short at the end (recording interrupted) is reported as
+/// `UnexpectedEof`
+pub struct Reader<R: Read> {
+    input: R,
+    header: Header,
+}
+
+impl<R: Read> Reader<R> {
+    /// ## open a recording, reads the header
+    pub fn new(mut input: R) -> io::Result<Self> {
+        let mut bytes = [0u8; HEADER_LEN];
+        input.read_exact(&mut bytes).map_err(|error| match error.kind() {
+            io::ErrorKind::UnexpectedEof => invalid("not an rm3100 recording"),
+            _ => error,
+        })?;
+        Ok(Reader {header: Header::from_bytes(&bytes)?, input})
+    }
+
+    pub fn header(&self) -> &Header {&self.header}
+
+    /// ## copy every record to sink, return records copied
+    pub fn copy_to(self, sink: &mut impl Sink) -> io::Result<u64> {
+        let mut records = 0;
+        for record in self {
+            sink.write(&record?)?;
+            records += 1;
+        }
+        sink.flush()?;
+        Ok(records)
+    }
+}
+
+impl<R: Read> Iterator for Reader<R> {
+    type Item = io::Result<Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut bytes = [0u8; RECORD_LEN];
+        let mut len = 0;
+        while len < RECORD_LEN {
+            match self.input.read(&mut bytes[len..]) {
+                Ok(0) if len == 0 => return None,
+                Ok(0) => return Some(Err(io::ErrorKind::UnexpectedEof.into())),
+                Ok(read) => len += read,
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => {},
+                Err(error) => return Some(Err(error)),
+            }
+        }
+        Some(Ok(Record::from_bytes(&bytes)))
+    }
+}
+
+/// ## seconds since the first sample, from wrapping ticks
+///
+/// samples must be less than one wrap apart (89s at 48MHz)
+pub struct Timeline {
+    tick_rate: u32,
+    last: Option<u32>,
+    ticks: u64,
+}
+
+impl Timeline {
+    pub fn new(tick_rate: u32) -> Self {
+        Timeline {tick_rate, last: None, ticks: 0}
+    }
+
+    pub fn seconds(&mut self, tick: u32) -> f64 {
+        if let Some(last) = self.last {
+            self.ticks += tick.wrapping_sub(last) as u64;
+        }
+        self.last = Some(tick);
+        self.ticks as f64 / self.tick_rate as f64
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rm3100::{Axes, UpdateRate, DRDM};
+
+    fn header() -> Header {
+        let config = Config { cc: CycleCount { x: 100, y: 200, z: 200 }, rate: UpdateRate::Hz37, drdm: DRDM::Any };
+        let info = DeviceInfo {
+            protocol_version: protocol::VERSION,
+            firmware_version: [0, 1, 0],
+            tick_rate: 48_000_000,
+            revid: 0x22,
+            buffer_size: 32,
+            uptime_ms: 5_000,
+            config,
+            axes: Axes::XYZ,
+        };
+        Header::new(info)
+    }
+
+    fn record(sequence: u32, drdy_tick: u32) -> Record {
+        Record { sequence, sample: Sample { mag: [750, -1500, 0], trigger_tick: drdy_tick - 100, drdy_tick } }
+    }
+
+    #[test]
+    fn recording_round_trip() {
+        let header = header();
+        assert_eq!(header.gain, [1150.0 / 30.0, 75.0, 75.0]);
+        let mut writer = Writer::new(Vec::new(), &header).unwrap();
+        let records = [record(0, 1000), record(0, 2000), record(2, 3000)];
+        for record in &records {
+            writer.write(record).unwrap();
+        }
+        let bytes = writer.into_inner();
+        assert_eq!(bytes.len(), HEADER_LEN + 3 * RECORD_LEN);
+
+        let reader = Reader::new(&bytes[..]).unwrap();
+        assert_eq!(*reader.header(), header);
+        assert_eq!(reader.map(Result::unwrap).collect::<Vec<_>>(), records);
+
+        // interrupted recording
+        let mut reader = Reader::new(&bytes[..bytes.len() - 1]).unwrap();
+        assert!(reader.nth(1).unwrap().is_ok());
+        assert_eq!(reader.next().unwrap().unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+        assert!(reader.next().is_none());
+    }
+
+    #[test]
+    fn reader_rejects_other_files() {
+        let mut bytes = header().to_bytes();
+        bytes[MAGIC.len()] = FORMAT_VERSION + 1;
+        assert_eq!(Reader::new(&bytes[..]).err().unwrap().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(Reader::new(&b"time,x,y,z\n"[..]).err().unwrap().kind(), io::ErrorKind::InvalidData);
+        let text = [b'#'; HEADER_LEN];
+        assert_eq!(Reader::new(&text[..]).err().unwrap().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn timeline_unwraps_ticks() {
+        let mut timeline = Timeline::new(1000);
+        assert_eq!(timeline.seconds(u32::MAX - 499), 0.0);
+        assert_eq!(timeline.seconds(500), 1.0);
+        assert_eq!(timeline.seconds(2500), 3.0);
+    }
+}
@@ -0,0 +1,179 @@
+//! client library and command line tool against the emulated device
+
+use std::fs;
+use std::process::Command;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rm3100_host::emulator::{self, Options, TxPolicy};
+use rm3100_host::rm3100::filter::FilterConfig;
+use rm3100_host::rm3100::health::Fault;
+use rm3100_host::{Axes, Config, CycleCount, Device, Error, UpdateRate};
+
+fn device(options: Options) -> (Device<emulator::HostPort>, emulator::Handle) {
+    let (port, handle) = emulator::pipe(options);
+    (Device::new(port), handle)
+}
+
+#[test]
+fn requests_and_samples() {
+    let (mut device, _emulator) = device(Options::default());
+    let info = device.info().unwrap();
+    // one of the 32 buffer slots is kept free
+    assert_eq!((info.revid, info.buffer_size, info.tick_rate), (0x22, 31, emulator::TICK_RATE));
+    let config = Config { cc: CycleCount { x: 100, y: 100, z: 100 }, ..info.config };
+    assert_eq!(device.configure(config).unwrap(), config);
+    assert_eq!(device.read_registers(0x04, 2).unwrap(), [0, 100]);
+    assert_eq!(device.read_registers(0x36, 1).unwrap(), [0x22]);
+
+    assert_eq!(device.start_timer(100_000).unwrap(), 100_000);
+    let sample = device.read_sample().unwrap();
+    // 20000/0/-40000nT at 38.3 LSB/uT
+    assert_eq!(sample.mag, [766, 0, -1533]);
+    let measure_ticks = config.cc.measure_time_us(info.axes) * (emulator::TICK_RATE / 1_000_000);
+    assert_eq!(sample.drdy_tick.wrapping_sub(sample.trigger_tick), measure_ticks);
+    device.stop_timer().unwrap();
+
+    assert!(matches!(device.start_timer(1_000_000), Err(Error::Device(_))));
+    let stats = device.stats().unwrap();
+    assert!(stats.samples >= 1);
+    assert_eq!(stats.latency_max, measure_ticks);
+    assert_eq!(device.health().unwrap().fault, Fault::None);
+}
+
+#[test]
+fn trigger_input_edges() {
+    let (mut device, emulator) = device(Options { trigger_rate: 20_000, ..Options::default() });
+    let sample = device.read_sample().unwrap();
+    assert_ne!(sample.mag, [0; 3]);
+    emulator.set_trigger_rate(0);
+    thread::sleep(Duration::from_millis(100));
+    device.clear_buffer().unwrap();
+    thread::sleep(Duration::from_millis(200));
+    assert!(device.read_samples(32).unwrap().is_empty());
+    // both edges count, rising ones measure
+    let stats = device.stats().unwrap();
+    assert!(stats.triggers >= 2 * stats.samples - 1);
+}
+
+#[test]
+fn low_power_samples_across_set_config() {
+    let (mut device, _emulator) = device(Options { low_power: true, ..Options::default() });
+    let info = device.info().unwrap();
+    assert_eq!((info.buffer_size, info.axes, info.config.rate), (255, Axes::XYZ, UpdateRate::Hz1_2));
+    // sampling from power-up at 1.2Hz
+    device.set_timeout(Duration::from_secs(2));
+    assert_ne!(device.read_sample().unwrap().mag, [0; 3]);
+    // the continuous measurement runs on at the new rate
+    device.configure(Config { rate: UpdateRate::Hz75, ..info.config }).unwrap();
+    device.clear_buffer().unwrap();
+    thread::sleep(Duration::from_millis(200));
+    assert!(device.read_samples(32).unwrap().len() >= 5);
+}
+
+#[test]
+fn filter_decimates_samples() {
+    let (mut device, _emulator) = device(Options::default());
+    device.configure(Config { cc: CycleCount { x: 100, y: 100, z: 100 }, ..Config::default() }).unwrap();
+    let cic = FilterConfig::Cic { order: 1, decimation: 10 };
+    assert_eq!(device.set_filter(cic).unwrap(), cic);
+    assert_eq!(device.filter().unwrap(), cic);
+    device.start_timer(100_000).unwrap();
+    // the mean of 10 samples of a steady field
+    assert_eq!(device.read_sample().unwrap().mag, [766, 0, -1533]);
+    thread::sleep(Duration::from_millis(200));
+    device.stop_timer().unwrap();
+    thread::sleep(Duration::from_millis(20));
+    let buffered = 1 + device.read_samples(32).unwrap().len() as u32;
+    // every measurement is counted, one in 10 is buffered
+    assert_eq!(buffered, device.stats().unwrap().samples / 10);
+    assert!(buffered >= 2);
+}
+
+/// stream a while, stall the link for `stall`, then collect until nothing arrives
+fn stalled_stream(options: Options, stall: Duration) -> (Device<emulator::HostPort>, emulator::Handle, usize, bool) {
+    let (mut device, emulator) = device(options);
+    // x/y/z in 1.9ms
+    device.configure(Config { cc: CycleCount { x: 50, y: 50, z: 50 }, ..Config::default() }).unwrap();
+    device.start_timer(200_000).unwrap();
+    device.set_timeout(Duration::from_millis(200));
+    let (mut samples, mut gap) = (0, false);
+    {
+        let mut stream = device.stream().unwrap();
+        stream.next().unwrap().unwrap();
+        emulator.stall(true);
+        thread::sleep(stall);
+        emulator.stall(false);
+        let end = Instant::now() + Duration::from_secs(2);
+        while Instant::now() < end {
+            match stream.next().unwrap() {
+                Ok(_) => samples += 1,
+                Err(Error::Gap { .. }) => gap = true,
+                Err(Error::Timeout) => break,
+                Err(error) => panic!("{}", error),
+            }
+            if samples > 100 {break;}
+        }
+    }
+    device.stop_timer().unwrap();
+    (device, emulator, samples, gap)
+}
+
+#[test]
+fn stall_overflows_the_buffer() {
+    let (mut device, _emulator, samples, gap) = stalled_stream(Options::default(), Duration::from_millis(600));
+    // buffered samples arrive late, nothing lost in transit
+    assert!(samples >= 32);
+    assert!(!gap);
+    assert!(device.overflow().unwrap());
+    let stats = device.stats().unwrap();
+    assert!(stats.dropped > 0);
+    assert!(stats.usb_stalls >= 1);
+}
+
+#[test]
+fn stall_drops_frames_with_discard() {
+    let options = Options { tx_policy: TxPolicy::Discard, ..Options::default() };
+    let (mut device, _emulator, _, gap) = stalled_stream(options, Duration::from_millis(600));
+    assert!(gap);
+    assert!(!device.overflow().unwrap());
+    assert!(device.stats().unwrap().dropped > 0);
+}
+
+fn cli(port: &str, args: &[&str]) -> (bool, String) {
+    let output = Command::new(env!("CARGO_BIN_EXE_rm3100")).arg("--port").arg(port).args(args).output().unwrap();
+    (output.status.success(), String::from_utf8(output.stdout).unwrap())
+}
+
+#[test]
+fn command_line_tool_on_a_pty() {
+    let (port, _emulator) = emulator::pty(Options::default()).unwrap();
+    let (ok, info) = cli(&port, &["info"]);
+    assert!(ok);
+    assert!(info.contains("\"revid\":34,\"buffer_size\":31"));
+    let (ok, config) = cli(&port, &["config", "set", "cc", "50"]);
+    assert!(ok);
+    assert!(config.starts_with("{\"cc\":[50,50,50],"));
+    let (ok, filter) = cli(&port, &["filter", "set", "average", "4"]);
+    assert!(ok);
+    assert_eq!(filter.trim(), "{\"filter\":\"average\",\"len\":4}");
+    assert!(!cli(&port, &["filter", "set", "median", "33"]).0);
+    let (ok, filter) = cli(&port, &["filter", "get"]);
+    assert!(ok);
+    assert_eq!(filter.trim(), "{\"filter\":\"average\",\"len\":4}");
+    assert!(cli(&port, &["filter", "set", "none"]).0);
+    let (ok, selftest) = cli(&port, &["selftest"]);
+    assert!(ok, "{}", selftest);
+
+    let path = std::env::temp_dir().join(format!("rm3100-emulator-{}.rec", std::process::id()));
+    let path = path.to_str().unwrap();
+    let (ok, stream) = cli(&port, &["stream", "--duration", "0.5", "--rate", "100", "--out", path]);
+    assert!(ok);
+    assert!(stream.starts_with("{\"samples\":"));
+    let (ok, csv) = cli(&port, &["export", path]);
+    fs::remove_file(path).unwrap();
+    assert!(ok);
+    let rows: Vec<&str> = csv.lines().collect();
+    assert!(rows.len() > 10);
+    assert!(rows[1].ends_with(",400,0,-800,20000.0,0.0,-40000.0"), "{}", rows[1]);
+}
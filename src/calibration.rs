@@ -0,0 +1,348 @@
+//! hard-iron and soft-iron calibration
+//!
+//! hardware independent: collect samples while the sensor is turned through
+//! as many orientations as possible, fit an ellipsoid to them, correct
+//! later samples with the fitted offset and 3x3 matrix. Corrected samples
+//! lie on a sphere whose radius is the mean radius of the fitted ellipsoid,
+//! so they stay in counts
+
+/// ## correction of one sensor
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Calibration {
+    /// hard-iron offset(counts)
+    pub offset: [f32; 3],
+    /// soft-iron matrix, applied after removing the offset
+    pub matrix: [[f32; 3]; 3],
+}
+
+impl Default for Calibration {
+    /// no correction
+    fn default() -> Self {
+        Calibration {offset: [0.0; 3], matrix: [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]]}
+    }
+}
+
+impl Calibration {
+    /// ## corrected x/y/z(counts) of a raw sample
+    pub fn apply(&self, mag: [i32; 3]) -> [f32; 3] {
+        let centered = [0, 1, 2].map(|axis| mag[axis] as f32 - self.offset[axis]);
+        self.matrix.map(|row| row[0] * centered[0] + row[1] * centered[1] + row[2] * centered[2])
+    }
+}
+
+/// ## how well the samples fit the ellipsoid
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Quality {
+    pub samples: usize,
+    /// mean radius of corrected samples(counts), the field strength
+    pub radius: f32,
+    /// rms deviation of corrected radii from `radius`, relative
+    pub residual: f32,
+    /// largest deviation, relative
+    pub max_residual: f32,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum FitError {
+    /// fewer than `MIN_SAMPLES`
+    TooFewSamples,
+    /// orientations do not span 3 dimensions (e.g. turned about one axis only)
+    Degenerate,
+    /// the fitted quadric is no ellipsoid
+    NotEllipsoid,
+}
+
+/// parameters of the ellipsoid
+pub const MIN_SAMPLES: usize = 9;
+
+/// ## samples of a calibration rotation
+///
+/// holds up to N samples, a sample closer than `min_distance`(counts) to the
+/// last kept one is skipped so that lingering in one orientation does not
+/// outweigh the others
+pub struct Collector<const N: usize> {
+    samples: [[i32; 3]; N],
+    len: usize,
+    min_distance: i32,
+}
+
+impl<const N: usize> Collector<N> {
+    pub fn new(min_distance: i32) -> Self {
+        Collector {samples: [[0; 3]; N], len: 0, min_distance}
+    }
+
+    /// ## keep sample, false if skipped or full
+    pub fn push(&mut self, mag: [i32; 3]) -> bool {
+        if self.is_full() {
+            return false;
+        }
+        if let Some(last) = self.samples[..self.len].last() {
+            let squared: i64 = (0..3).map(|axis| (mag[axis] as i64 - last[axis] as i64).pow(2)).sum();
+            if squared < (self.min_distance as i64).pow(2) {
+                return false;
+            }
+        }
+        self.samples[self.len] = mag;
+        self.len += 1;
+        true
+    }
+
+    pub fn samples(&self) -> &[[i32; 3]] {&self.samples[..self.len]}
+
+    pub fn len(&self) -> usize {self.len}
+
+    pub fn is_empty(&self) -> bool {self.len == 0}
+
+    pub fn is_full(&self) -> bool {self.len == N}
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    pub fn fit(&self) -> Result<(Calibration, Quality), FitError> {
+        fit(self.samples())
+    }
+}
+
+/// ## least squares ellipsoid through samples
+///
+/// fits a x² + b y² + c z² + 2f yz + 2g xz + 2h xy + 2p x + 2q y + 2r z = 1
+/// to samples centered and scaled around their mean, the correction matrix is
+/// the symmetric square root of the ellipsoid's shape matrix
+pub fn fit(samples: &[[i32; 3]]) -> Result<(Calibration, Quality), FitError> {
+    if samples.len() < MIN_SAMPLES {
+        return Err(FitError::TooFewSamples);
+    }
+    let count = samples.len() as f64;
+    let mut mean = [0f64; 3];
+    for sample in samples {
+        for axis in 0..3 {
+            mean[axis] += sample[axis] as f64 / count;
+        }
+    }
+    let mut scale = 0f64;
+    for sample in samples {
+        for axis in 0..3 {
+            scale = scale.max(libm::fabs(sample[axis] as f64 - mean[axis]));
+        }
+    }
+    if scale == 0.0 {
+        return Err(FitError::Degenerate);
+    }
+    let point = |sample: &[i32; 3]| [0, 1, 2].map(|axis| (sample[axis] as f64 - mean[axis]) / scale);
+
+    // normal equations
+    let mut normal = [[0f64; 10]; 9];
+    for sample in samples {
+        let [x, y, z] = point(sample);
+        let row = [x * x, y * y, z * z, 2.0 * y * z, 2.0 * x * z, 2.0 * x * y, 2.0 * x, 2.0 * y, 2.0 * z];
+        for i in 0..9 {
+            for j in 0..9 {
+                normal[i][j] += row[i] * row[j];
+            }
+            normal[i][9] += row[i];
+        }
+    }
+    let [a, b, c, f, g, h, p, q, r] = solve(normal).ok_or(FitError::Degenerate)?;
+    let shape = [[a, h, g], [h, b, f], [g, f, c]];
+    // center = -shape⁻¹ (p, q, r), then (u - center)ᵀ shape (u - center) = 1 + centerᵀ shape center
+    let inverse = invert(shape).ok_or(FitError::NotEllipsoid)?;
+    let center = inverse.map(|row| -(row[0] * p + row[1] * q + row[2] * r));
+    let level = 1.0 - (center[0] * p + center[1] * q + center[2] * r);
+    if level <= 0.0 {
+        return Err(FitError::NotEllipsoid);
+    }
+    let (values, vectors) = eigen(shape.map(|row| row.map(|value| value / level)));
+    if values.iter().any(|value| *value <= 0.0) {
+        return Err(FitError::NotEllipsoid);
+    }
+    // radius of a sphere of the same volume, in scaled units
+    let radius = libm::pow(values[0] * values[1] * values[2], -1.0 / 6.0);
+    let roots = values.map(|value| libm::sqrt(value) * radius);
+    let mut matrix = [[0f32; 3]; 3];
+    for (i, row) in matrix.iter_mut().enumerate() {
+        for (j, value) in row.iter_mut().enumerate() {
+            *value = (0..3).map(|k| vectors[i][k] * roots[k] * vectors[j][k]).sum::<f64>() as f32;
+        }
+    }
+    let calibration = Calibration {offset: [0, 1, 2].map(|axis| (mean[axis] + center[axis] * scale) as f32), matrix};
+
+    let radius = (radius * scale) as f32;
+    let (mut squares, mut max_residual) = (0f32, 0f32);
+    for sample in samples {
+        let [x, y, z] = calibration.apply(*sample);
+        let deviation = libm::fabsf(libm::sqrtf(x * x + y * y + z * z) / radius - 1.0);
+        squares += deviation * deviation;
+        max_residual = max_residual.max(deviation);
+    }
+    let residual = libm::sqrtf(squares / samples.len() as f32);
+    Ok((calibration, Quality {samples: samples.len(), radius, residual, max_residual}))
+}
+
+/// ## solve 9 linear equations, augmented matrix, None if singular
+fn solve(mut m: [[f64; 10]; 9]) -> Option<[f64; 9]> {
+    let largest = (0..9).map(|i| libm::fabs(m[i][i])).fold(0.0, f64::max);
+    for column in 0..9 {
+        let pivot = (column..9).max_by(|i, j| libm::fabs(m[*i][column]).total_cmp(&libm::fabs(m[*j][column])))?;
+        if libm::fabs(m[pivot][column]) <= largest * 1e-12 {
+            return None;
+        }
+        m.swap(column, pivot);
+        for row in 0..9 {
+            if row == column {continue;}
+            let factor = m[row][column] / m[column][column];
+            let pivot_row = m[column];
+            for (value, pivot) in m[row].iter_mut().zip(pivot_row).skip(column) {
+                *value -= factor * pivot;
+            }
+        }
+    }
+    Some([0, 1, 2, 3, 4, 5, 6, 7, 8].map(|i| m[i][9] / m[i][i]))
+}
+
+fn invert(m: [[f64; 3]; 3]) -> Option<[[f64; 3]; 3]> {
+    let cofactor = |i: usize, j: usize| {
+        let (r0, r1) = ((i + 1) % 3, (i + 2) % 3);
+        let (c0, c1) = ((j + 1) % 3, (j + 2) % 3);
+        m[r0][c0] * m[r1][c1] - m[r0][c1] * m[r1][c0]
+    };
+    let determinant = (0..3).map(|j| m[0][j] * cofactor(0, j)).sum::<f64>();
+    if determinant == 0.0 {
+        return None;
+    }
+    Some([0, 1, 2].map(|i| [0, 1, 2].map(|j| cofactor(j, i) / determinant)))
+}
+
+/// ## eigenvalues and eigenvectors(columns) of a symmetric matrix, Jacobi rotations
+fn eigen(mut a: [[f64; 3]; 3]) -> ([f64; 3], [[f64; 3]; 3]) {
+    let mut v = [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]];
+    for _ in 0..50 {
+        let off = a[0][1] * a[0][1] + a[0][2] * a[0][2] + a[1][2] * a[1][2];
+        if off < 1e-30 {
+            break;
+        }
+        for (p, q) in [(0, 1), (0, 2), (1, 2)] {
+            if a[p][q] == 0.0 {continue;}
+            let theta = (a[q][q] - a[p][p]) / (2.0 * a[p][q]);
+            let t = libm::copysign(1.0, theta) / (libm::fabs(theta) + libm::sqrt(theta * theta + 1.0));
+            let c = 1.0 / libm::sqrt(t * t + 1.0);
+            let s = t * c;
+            let mut rotation = [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]];
+            rotation[p][p] = c;
+            rotation[q][q] = c;
+            rotation[p][q] = s;
+            rotation[q][p] = -s;
+            a = multiply(transpose(rotation), multiply(a, rotation));
+            v = multiply(v, rotation);
+        }
+    }
+    ([a[0][0], a[1][1], a[2][2]], v)
+}
+
+fn multiply(a: [[f64; 3]; 3], b: [[f64; 3]; 3]) -> [[f64; 3]; 3] {
+    [0, 1, 2].map(|i| [0, 1, 2].map(|j| (0..3).map(|k| a[i][k] * b[k][j]).sum()))
+}
+
+fn transpose(m: [[f64; 3]; 3]) -> [[f64; 3]; 3] {
+    [0, 1, 2].map(|i| [0, 1, 2].map(|j| m[j][i]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RADIUS: f64 = 3750.0;
+    const OFFSET: [f64; 3] = [420.0, -180.0, 95.0];
+    /// symmetric soft-iron distortion
+    const DISTORTION: [[f64; 3]; 3] = [[1.12, 0.06, -0.03], [0.06, 0.91, 0.04], [-0.03, 0.04, 1.02]];
+
+    /// evenly spread directions (fibonacci sphere), distorted and offset
+    fn rotation(count: usize, noise: i32) -> impl Iterator<Item = [i32; 3]> {
+        let mut seed = 0x2545_f491u32;
+        (0..count).map(move |i| {
+            let z = 1.0 - 2.0 * (i as f64 + 0.5) / count as f64;
+            let ring = libm::sqrt(1.0 - z * z);
+            let angle = i as f64 * 2.399_963_229_728_653;
+            let field = [ring * libm::cos(angle), ring * libm::sin(angle), z].map(|value| value * RADIUS);
+            [0, 1, 2].map(|axis| {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                let noise = if noise == 0 {0} else {(seed >> 16) as i32 % (2 * noise + 1) - noise};
+                let distorted: f64 = (0..3).map(|k| DISTORTION[axis][k] * field[k]).sum();
+                libm::round(distorted + OFFSET[axis]) as i32 + noise
+            })
+        })
+    }
+
+    #[test]
+    fn fit_recovers_offset_and_matrix() {
+        let mut collector = Collector::<200>::new(0);
+        for sample in rotation(200, 0) {
+            assert!(collector.push(sample));
+        }
+        assert!(!collector.push([0; 3]));
+        let (calibration, quality) = collector.fit().unwrap();
+        for (offset, expected) in calibration.offset.iter().zip(OFFSET) {
+            assert!(libm::fabs(*offset as f64 - expected) < 1.0, "{:?}", calibration.offset);
+        }
+        assert_eq!(quality.samples, 200);
+        assert!(quality.residual < 2e-4 && quality.max_residual < 1e-3, "{:?}", quality);
+        // matrix undoes the distortion up to the scale
+        let scale = RADIUS as f32 / quality.radius;
+        for (i, row) in calibration.matrix.iter().enumerate() {
+            for j in 0..3 {
+                let product: f64 = row.iter().zip(&DISTORTION).map(|(value, distortion)| *value as f64 * distortion[j]).sum();
+                let expected = if i == j {1.0 / scale as f64} else {0.0};
+                assert!(libm::fabs(product - expected) < 1e-3, "{:?}", calibration.matrix);
+            }
+        }
+        // corrected samples lie on the sphere
+        for sample in rotation(50, 0) {
+            let [x, y, z] = calibration.apply(sample);
+            let radius = libm::sqrtf(x * x + y * y + z * z);
+            assert!(libm::fabsf(radius / quality.radius - 1.0) < 1e-3);
+        }
+    }
+
+    #[test]
+    fn noise_shows_in_quality() {
+        let samples: [[i32; 3]; 300] = {
+            let mut samples = [[0; 3]; 300];
+            for (slot, sample) in samples.iter_mut().zip(rotation(300, 20)) {
+                *slot = sample;
+            }
+            samples
+        };
+        let (calibration, quality) = fit(&samples).unwrap();
+        assert!(quality.residual > 1e-3 && quality.residual < 1e-2, "{:?}", quality);
+        assert!(libm::fabsf(quality.radius / 3750.0 - 1.0) < 0.05);
+        for (offset, expected) in calibration.offset.iter().zip(OFFSET) {
+            assert!(libm::fabs(*offset as f64 - expected) < 5.0);
+        }
+    }
+
+    #[test]
+    fn collector_skips_close_samples() {
+        let mut collector = Collector::<4>::new(10);
+        assert!(collector.push([0, 0, 0]));
+        assert!(!collector.push([5, 5, 5]));
+        assert!(collector.push([6, 6, 6]));
+        assert_eq!(collector.len(), 2);
+        collector.clear();
+        assert!(collector.is_empty());
+    }
+
+    #[test]
+    fn fit_rejects_poor_rotations() {
+        assert_eq!(fit(&[[1, 2, 3]; 8]), Err(FitError::TooFewSamples));
+        assert_eq!(fit(&[[100, -50, 7]; 20]), Err(FitError::Degenerate));
+        // turned about z only: a circle, no ellipsoid
+        let mut circle = [[0; 3]; 36];
+        for (i, sample) in circle.iter_mut().enumerate() {
+            let angle = i as f64 * 10f64.to_radians();
+            *sample = [libm::round(1000.0 * libm::cos(angle)) as i32, libm::round(1000.0 * libm::sin(angle)) as i32, 250];
+        }
+        assert!(fit(&circle).is_err());
+        // identity leaves samples as they are
+        assert_eq!(Calibration::default().apply([3, -4, 5]), [3.0, -4.0, 5.0]);
+    }
+}
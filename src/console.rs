@@ -0,0 +1,399 @@
+//! line-oriented text console for terminals
+//!
+//! one command per line (CR or LF), words separated by spaces:
+//!
+//! | command | action |
+//! | - | - |
+//! | `help` | list commands |
+//! | `binary` | leave the console, back to the framed protocol |
+//! | `info` | device info |
+//! | `stats` | statistics |
+//! | `power` | average current estimate |
+//! | `get cc` / `set cc <n>` / `set cc <x> <y> <z>` | cycle counts |
+//! | `get rate` / `set rate <hz>` | update rate, rounded like `UpdateRate::from` |
+//! | `get drdm` / `set drdm alarmfull\|any\|full\|alarm` | DRDY mode |
+//! | `get axes` / `set axes <x\|y\|z...>` | measured axes, e.g. `xz` |
+//! | `measure [axes]` | one single measurement, prints the sample |
+//! | `continuous on\|off` | continuous measurement |
+//! | `stream on\|off` | print every sample |
+//! | `read` | print buffered samples |
+//!
+//! samples are printed as drdy tick, x/y/z counts and x/y/z in uT
+
+use core::fmt::{self, Write};
+use crate::{Axes, Config, CycleCount, UpdateRate, DRDM};
+use crate::protocol::{DeviceInfo, Sample, Stats};
+use crate::power::Estimate;
+
+/// longest accepted line
+pub const LINE_LEN: usize = 64;
+/// line that switches from the framed protocol to the console
+pub const ENTER: &str = "console";
+
+pub const HELP: &str = "\
+help | binary | info | stats | power\r\n\
+get/set cc <n> | <x> <y> <z>\r\n\
+get/set rate <hz>\r\n\
+get/set drdm alarmfull|any|full|alarm\r\n\
+get/set axes <xyz>\r\n\
+measure [xyz] | continuous on|off | stream on|off | read\r\n";
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ConsoleCommand {
+    Help,
+    Binary,
+    Info,
+    Stats,
+    Power,
+    GetCycleCount,
+    SetCycleCount(CycleCount),
+    GetRate,
+    SetRate(UpdateRate),
+    GetDrdm,
+    SetDrdm(DRDM),
+    GetAxes,
+    SetAxes(Axes),
+    /// measure given axes, or the active ones
+    Measure(Option<Axes>),
+    Continuous(bool),
+    Stream(bool),
+    Read,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ParseError {
+    TooLong,
+    NotAscii,
+    UnknownCommand,
+    InvalidArgument,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            ParseError::TooLong => "line too long",
+            ParseError::NotAscii => "not ascii",
+            ParseError::UnknownCommand => "unknown command, try help",
+            ParseError::InvalidArgument => "invalid argument",
+        })
+    }
+}
+
+/// collect bytes into lines
+pub struct LineBuffer {
+    buf: [u8; LINE_LEN],
+    len: usize,
+    /// current line exceeded LINE_LEN, dropped at its end
+    overflow: bool,
+}
+
+impl Default for LineBuffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LineBuffer {
+    pub fn new() -> Self {
+        LineBuffer { buf: [0; LINE_LEN], len: 0, overflow: false }
+    }
+
+    pub fn reset(&mut self) {
+        self.len = 0;
+        self.overflow = false;
+    }
+
+    /// ## append one byte
+    ///
+    /// return trimmed line at CR/LF, empty lines are skipped,
+    /// backspace/DEL removes the last byte
+    pub fn push(&mut self, byte: u8) -> Option<Result<&str, ParseError>> {
+        match byte {
+            b'\r' | b'\n' => {
+                let len = self.len;
+                let overflow = self.overflow;
+                self.reset();
+                if overflow {
+                    return Some(Err(ParseError::TooLong));
+                }
+                let line = core::str::from_utf8(&self.buf[..len]).map(str::trim);
+                match line {
+                    Ok("") => None,
+                    Ok(line) if line.is_ascii() => Some(Ok(line)),
+                    _ => Some(Err(ParseError::NotAscii)),
+                }
+            },
+            0x08 | 0x7F => {
+                self.len = self.len.saturating_sub(1);
+                None
+            },
+            _ => {
+                if self.len == LINE_LEN {
+                    self.overflow = true;
+                } else {
+                    self.buf[self.len] = byte;
+                    self.len += 1;
+                }
+                None
+            },
+        }
+    }
+}
+
+/// terminal echo of one typed byte
+pub fn echo(out: &mut impl Write, byte: u8) -> fmt::Result {
+    match byte {
+        b'\r' => out.write_str("\r\n"),
+        // CR LF terminals already got the new line
+        b'\n' => Ok(()),
+        0x08 | 0x7F => out.write_str("\x08 \x08"),
+        0x20..=0x7E => out.write_char(byte as char),
+        _ => Ok(()),
+    }
+}
+
+/// parse one line
+pub fn parse(line: &str) -> Result<ConsoleCommand, ParseError> {
+    let mut words = line.split_ascii_whitespace();
+    let command = words.next().ok_or(ParseError::UnknownCommand)?;
+    let mut args = [""; 4];
+    let mut count = 0;
+    for word in words {
+        *args.get_mut(count).ok_or(ParseError::InvalidArgument)? = word;
+        count += 1;
+    }
+    let args = &args[..count];
+    let no_args = |command| if args.is_empty() {Ok(command)} else {Err(ParseError::InvalidArgument)};
+    match (command, args) {
+        ("help", _) => no_args(ConsoleCommand::Help),
+        ("binary", _) => no_args(ConsoleCommand::Binary),
+        ("info", _) => no_args(ConsoleCommand::Info),
+        ("stats", _) => no_args(ConsoleCommand::Stats),
+        ("power", _) => no_args(ConsoleCommand::Power),
+        ("get", ["cc"]) => Ok(ConsoleCommand::GetCycleCount),
+        ("get", ["rate"]) => Ok(ConsoleCommand::GetRate),
+        ("get", ["drdm"]) => Ok(ConsoleCommand::GetDrdm),
+        ("get", ["axes"]) => Ok(ConsoleCommand::GetAxes),
+        ("set", ["cc", cc]) => {
+            let cc = parse_cc(cc)?;
+            Ok(ConsoleCommand::SetCycleCount(CycleCount { x: cc, y: cc, z: cc }))
+        },
+        ("set", ["cc", x, y, z]) => Ok(ConsoleCommand::SetCycleCount(CycleCount {
+            x: parse_cc(x)?,
+            y: parse_cc(y)?,
+            z: parse_cc(z)?,
+        })),
+        ("set", ["rate", rate]) => match rate.parse::<f32>() {
+            Ok(rate) if rate > 0.0 => Ok(ConsoleCommand::SetRate(rate.into())),
+            _ => Err(ParseError::InvalidArgument),
+        },
+        ("set", ["drdm", drdm]) => Ok(ConsoleCommand::SetDrdm(match *drdm {
+            "alarmfull" => DRDM::AlarmFull,
+            "any" => DRDM::Any,
+            "full" => DRDM::Full,
+            "alarm" => DRDM::Alarm,
+            _ => return Err(ParseError::InvalidArgument),
+        })),
+        ("set", ["axes", axes]) => Ok(ConsoleCommand::SetAxes(parse_axes(axes)?)),
+        ("get", _) | ("set", _) => Err(ParseError::InvalidArgument),
+        ("measure", []) => Ok(ConsoleCommand::Measure(None)),
+        ("measure", [axes]) => Ok(ConsoleCommand::Measure(Some(parse_axes(axes)?))),
+        ("continuous", [on]) => Ok(ConsoleCommand::Continuous(parse_on(on)?)),
+        ("stream", [on]) => Ok(ConsoleCommand::Stream(parse_on(on)?)),
+        ("read", _) => no_args(ConsoleCommand::Read),
+        ("measure", _) | ("continuous", _) | ("stream", _) => Err(ParseError::InvalidArgument),
+        _ => Err(ParseError::UnknownCommand),
+    }
+}
+
+fn parse_cc(word: &str) -> Result<u16, ParseError> {
+    match word.parse::<u16>() {
+        Ok(cc) if cc > 0 => Ok(cc),
+        _ => Err(ParseError::InvalidArgument),
+    }
+}
+
+/// letters x, y, z in any order, at least one
+fn parse_axes(word: &str) -> Result<Axes, ParseError> {
+    let mut axes = Axes { x: false, y: false, z: false };
+    for letter in word.bytes() {
+        match letter {
+            b'x' => axes.x = true,
+            b'y' => axes.y = true,
+            b'z' => axes.z = true,
+            _ => return Err(ParseError::InvalidArgument),
+        }
+    }
+    if word.is_empty() {
+        return Err(ParseError::InvalidArgument);
+    }
+    Ok(axes)
+}
+
+fn parse_on(word: &str) -> Result<bool, ParseError> {
+    match word {
+        "on" => Ok(true),
+        "off" => Ok(false),
+        _ => Err(ParseError::InvalidArgument),
+    }
+}
+
+// # output
+
+pub fn write_cc(out: &mut impl Write, cc: CycleCount) -> fmt::Result {
+    write!(out, "cc {} {} {}\r\n", cc.x, cc.y, cc.z)
+}
+
+pub fn write_rate(out: &mut impl Write, rate: UpdateRate) -> fmt::Result {
+    write!(out, "rate {} Hz\r\n", f32::from(rate))
+}
+
+pub fn write_drdm(out: &mut impl Write, drdm: DRDM) -> fmt::Result {
+    let name = match drdm {
+        DRDM::AlarmFull => "alarmfull",
+        DRDM::Any => "any",
+        DRDM::Full => "full",
+        DRDM::Alarm => "alarm",
+    };
+    write!(out, "drdm {}\r\n", name)
+}
+
+pub fn write_axes(out: &mut impl Write, axes: Axes) -> fmt::Result {
+    out.write_str("axes ")?;
+    for (letter, measured) in [('x', axes.x), ('y', axes.y), ('z', axes.z)] {
+        if measured {out.write_char(letter)?;}
+    }
+    out.write_str("\r\n")
+}
+
+pub fn write_config(out: &mut impl Write, config: &Config) -> fmt::Result {
+    write_cc(out, config.cc)?;
+    write_rate(out, config.rate)?;
+    write_drdm(out, config.drdm)
+}
+
+pub fn write_info(out: &mut impl Write, info: &DeviceInfo) -> fmt::Result {
+    let [major, minor, patch] = info.firmware_version;
+    write!(out, "protocol {}, firmware {}.{}.{}\r\n", info.protocol_version, major, minor, patch)?;
+    write!(out, "revid 0x{:02X}, tick rate {} Hz, buffer {}, uptime {} ms\r\n",
+        info.revid, info.tick_rate, info.buffer_size, info.uptime_ms)?;
+    write_config(out, &info.config)?;
+    write_axes(out, info.axes)
+}
+
+pub fn write_stats(out: &mut impl Write, stats: &Stats) -> fmt::Result {
+    write!(out, "samples {}, dropped {}, triggers {}, ignored {}, usb stalls {}\r\n",
+        stats.samples, stats.dropped, stats.triggers, stats.ignored, stats.usb_stalls)?;
+    if stats.latency_max == 0 {
+        out.write_str("latency -\r\n")
+    } else {
+        write!(out, "latency {}..{} ticks\r\n", stats.latency_min, stats.latency_max)
+    }
+}
+
+pub fn write_power(out: &mut impl Write, power: &Estimate) -> fmt::Result {
+    write!(out, "sensor {} uA, mcu {} uA, total {} uA\r\n",
+        power.sensor_ua, power.mcu_ua, power.total_ua())
+}
+
+/// drdy tick, x/y/z counts, x/y/z in uT (gain from cycle count)
+pub fn write_sample(out: &mut impl Write, sample: &Sample, cc: CycleCount) -> fmt::Result {
+    let [x, y, z] = sample.mag;
+    write!(out, "{} {} {} {}", sample.drdy_tick, x, y, z)?;
+    for nt in cc.to_nanotesla(sample.mag) {
+        // fixed point, 3 decimals
+        let sign = if nt < 0 {"-"} else {""};
+        let nt = nt.unsigned_abs();
+        write!(out, " {}{}.{:03}", sign, nt / 1000, nt % 1000)?;
+    }
+    out.write_str(" uT\r\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// fixed size text sink
+    struct Text {
+        buf: [u8; 256],
+        len: usize,
+    }
+
+    impl Text {
+        fn new() -> Self {
+            Text { buf: [0; 256], len: 0 }
+        }
+
+        fn as_str(&self) -> &str {
+            core::str::from_utf8(&self.buf[..self.len]).unwrap()
+        }
+    }
+
+    impl Write for Text {
+        fn write_str(&mut self, s: &str) -> fmt::Result {
+            let end = self.len + s.len();
+            self.buf.get_mut(self.len..end).ok_or(fmt::Error)?.copy_from_slice(s.as_bytes());
+            self.len = end;
+            Ok(())
+        }
+    }
+
+    fn lines(input: &[u8]) -> ([Option<Result<ConsoleCommand, ParseError>>; 4], usize) {
+        let mut buffer = LineBuffer::new();
+        let mut out = [None; 4];
+        let mut count = 0;
+        for byte in input {
+            if let Some(line) = buffer.push(*byte) {
+                out[count] = Some(line.and_then(parse));
+                count += 1;
+            }
+        }
+        (out, count)
+    }
+
+    #[test]
+    fn parse_commands() {
+        assert_eq!(parse("get cc"), Ok(ConsoleCommand::GetCycleCount));
+        assert_eq!(parse("set  cc 150"), Ok(ConsoleCommand::SetCycleCount(CycleCount { x: 150, y: 150, z: 150 })));
+        assert_eq!(parse("set cc 1 2 3"), Ok(ConsoleCommand::SetCycleCount(CycleCount { x: 1, y: 2, z: 3 })));
+        assert_eq!(parse("set rate 150"), Ok(ConsoleCommand::SetRate(UpdateRate::Hz150)));
+        assert_eq!(parse("set drdm any"), Ok(ConsoleCommand::SetDrdm(DRDM::Any)));
+        assert_eq!(parse("set axes zx"), Ok(ConsoleCommand::SetAxes(Axes { x: true, y: false, z: true })));
+        assert_eq!(parse("measure xyz"), Ok(ConsoleCommand::Measure(Some(Axes::XYZ))));
+        assert_eq!(parse("measure"), Ok(ConsoleCommand::Measure(None)));
+        assert_eq!(parse("stream on"), Ok(ConsoleCommand::Stream(true)));
+        assert_eq!(parse("continuous off"), Ok(ConsoleCommand::Continuous(false)));
+        assert_eq!(parse("power"), Ok(ConsoleCommand::Power));
+
+        assert_eq!(parse("jump"), Err(ParseError::UnknownCommand));
+        assert_eq!(parse("set cc 0"), Err(ParseError::InvalidArgument));
+        assert_eq!(parse("set cc 1 2"), Err(ParseError::InvalidArgument));
+        assert_eq!(parse("set rate -1"), Err(ParseError::InvalidArgument));
+        assert_eq!(parse("measure xw"), Err(ParseError::InvalidArgument));
+        assert_eq!(parse("help me"), Err(ParseError::InvalidArgument));
+        assert_eq!(parse("set axes x y z w"), Err(ParseError::InvalidArgument));
+    }
+
+    #[test]
+    fn line_buffer_splits_and_edits() {
+        let (out, count) = lines(b"get cx\x08c\r\n\r\nstream on\n");
+        assert_eq!(count, 2);
+        assert_eq!(out[0], Some(Ok(ConsoleCommand::GetCycleCount)));
+        assert_eq!(out[1], Some(Ok(ConsoleCommand::Stream(true))));
+
+        let mut long = [b'a'; LINE_LEN + 2];
+        long[LINE_LEN + 1] = b'\r';
+        let (out, count) = lines(&long);
+        assert_eq!((out[0], count), (Some(Err(ParseError::TooLong)), 1));
+        let (out, _) = lines(b"\xA5\x5A\r");
+        assert_eq!(out[0], Some(Err(ParseError::NotAscii)));
+    }
+
+    #[test]
+    fn sample_in_microtesla() {
+        let mut text = Text::new();
+        let sample = Sample { mag: [75, -7500, 0], trigger_tick: 0, drdy_tick: 42 };
+        write_sample(&mut text, &sample, CycleCount::default()).unwrap();
+        assert_eq!(text.as_str(), "42 75 -7500 0 1.000 -100.000 0.000 uT\r\n");
+    }
+}
@@ -0,0 +1,515 @@
+//! streaming filters and decimators for x/y/z samples
+//!
+//! hardware independent, integer arithmetic only in `push` (one call per
+//! sample, fit for the DRDY interrupt of a Cortex-M4); floating point is only
+//! used to design biquad coefficients. Each filter keeps its own state per
+//! axis. `Filter` picks one at runtime from a `FilterConfig`
+
+/// longest moving average and median window
+pub const MAX_WINDOW: usize = 32;
+/// most CIC integrator/comb stages
+pub const MAX_CIC_ORDER: usize = 4;
+/// fraction bits of biquad coefficients, Q3.28
+pub const BIQUAD_FRAC_BITS: u32 = 28;
+
+/// ## a filter of x/y/z samples
+pub trait Filter3 {
+    /// ## feed one sample, return a filtered one when due
+    ///
+    /// filters without decimation return one for every sample
+    fn push(&mut self, sample: [i32; 3]) -> Option<[i32; 3]>;
+
+    /// forget all past samples
+    fn reset(&mut self);
+}
+
+/// ## mean of the last `len` samples
+pub struct MovingAverage<const N: usize> {
+    window: [[i32; 3]; N],
+    len: usize,
+    /// next slot to overwrite
+    next: usize,
+    filled: usize,
+    sum: [i64; 3],
+}
+
+impl<const N: usize> MovingAverage<N> {
+    /// None unless 1 <= len <= N
+    pub fn new(len: usize) -> Option<Self> {
+        (1..=N).contains(&len).then_some(MovingAverage {window: [[0; 3]; N], len, next: 0, filled: 0, sum: [0; 3]})
+    }
+}
+
+impl<const N: usize> Filter3 for MovingAverage<N> {
+    /// mean of the samples so far until the window is filled
+    fn push(&mut self, sample: [i32; 3]) -> Option<[i32; 3]> {
+        if self.filled == self.len {
+            for (sum, old) in self.sum.iter_mut().zip(self.window[self.next]) {
+                *sum -= old as i64;
+            }
+        } else {
+            self.filled += 1;
+        }
+        for (sum, value) in self.sum.iter_mut().zip(sample) {
+            *sum += value as i64;
+        }
+        self.window[self.next] = sample;
+        self.next = (self.next + 1) % self.len;
+        Some(self.sum.map(|sum| div_round(sum, self.filled as i64) as i32))
+    }
+
+    fn reset(&mut self) {
+        self.next = 0;
+        self.filled = 0;
+        self.sum = [0; 3];
+    }
+}
+
+/// ## exponential low-pass, y += alpha * (x - y)
+///
+/// alpha is Q16 (`alpha`/65536), state keeps 16 fraction bits
+pub struct Ema {
+    alpha: i64,
+    /// Q16, None before the first sample
+    state: Option<[i64; 3]>,
+}
+
+impl Ema {
+    /// None if alpha is 0
+    pub fn new(alpha: u16) -> Option<Self> {
+        (alpha > 0).then_some(Ema {alpha: alpha as i64, state: None})
+    }
+
+    /// ## alpha for a time constant of `samples` samples
+    pub fn alpha(samples: f32) -> u16 {
+        (65536.0 / samples.max(1.0)).clamp(1.0, 65535.0) as u16
+    }
+}
+
+impl Filter3 for Ema {
+    /// starts at the first sample
+    fn push(&mut self, sample: [i32; 3]) -> Option<[i32; 3]> {
+        let input = sample.map(|value| (value as i64) << 16);
+        let state = match self.state.as_mut() {
+            Some(state) => {
+                for axis in 0..3 {
+                    state[axis] += (self.alpha * (input[axis] - state[axis])) >> 16;
+                }
+                *state
+            },
+            None => *self.state.insert(input),
+        };
+        Some(state.map(|value| ((value + (1 << 15)) >> 16) as i32))
+    }
+
+    fn reset(&mut self) {
+        self.state = None;
+    }
+}
+
+/// ## biquad coefficients, Q3.28, a0 normalized to 1
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Coefficients {
+    pub b0: i32,
+    pub b1: i32,
+    pub b2: i32,
+    pub a1: i32,
+    pub a2: i32,
+}
+
+impl Coefficients {
+    /// ## from b0/b1/b2 and a1/a2 with a0 = 1, each within -8..8
+    pub fn from_float(b: [f32; 3], a: [f32; 2]) -> Self {
+        let fixed = |value: f32| libm::roundf(value * (1u32 << BIQUAD_FRAC_BITS) as f32) as i32;
+        Coefficients {b0: fixed(b[0]), b1: fixed(b[1]), b2: fixed(b[2]), a1: fixed(a[0]), a2: fixed(a[1])}
+    }
+
+    /// ## second order low-pass (RBJ cookbook), `q` `FRAC_1_SQRT_2` for Butterworth
+    pub fn lowpass(cutoff_hz: f32, sample_hz: f32, q: f32) -> Self {
+        let w0 = 2.0 * core::f32::consts::PI * cutoff_hz / sample_hz;
+        let (sin, cos) = (libm::sinf(w0), libm::cosf(w0));
+        let alpha = sin / (2.0 * q);
+        let a0 = 1.0 + alpha;
+        let b0 = (1.0 - cos) / 2.0 / a0;
+        Self::from_float([b0, 2.0 * b0, b0], [-2.0 * cos / a0, (1.0 - alpha) / a0])
+    }
+
+    /// ## second order high-pass (RBJ cookbook), removes offsets and drift
+    pub fn highpass(cutoff_hz: f32, sample_hz: f32, q: f32) -> Self {
+        let w0 = 2.0 * core::f32::consts::PI * cutoff_hz / sample_hz;
+        let (sin, cos) = (libm::sinf(w0), libm::cosf(w0));
+        let alpha = sin / (2.0 * q);
+        let a0 = 1.0 + alpha;
+        let b0 = (1.0 + cos) / 2.0 / a0;
+        Self::from_float([b0, -2.0 * b0, b0], [-2.0 * cos / a0, (1.0 - alpha) / a0])
+    }
+}
+
+/// x[n-1], x[n-2], y[n-1], y[n-2] and the rounding error of one axis
+#[derive(Clone, Copy, Default)]
+struct BiquadState {
+    x: [i32; 2],
+    y: [i32; 2],
+    error: i64,
+}
+
+/// ## biquad, direct form I with error feedback
+///
+/// feeding the rounding error back keeps low cutoffs accurate with integer
+/// outputs
+pub struct Biquad {
+    coefficients: Coefficients,
+    state: [BiquadState; 3],
+}
+
+impl Biquad {
+    pub fn new(coefficients: Coefficients) -> Self {
+        Biquad {coefficients, state: [BiquadState::default(); 3]}
+    }
+
+    pub fn coefficients(&self) -> Coefficients {self.coefficients}
+}
+
+impl Filter3 for Biquad {
+    fn push(&mut self, sample: [i32; 3]) -> Option<[i32; 3]> {
+        let Coefficients {b0, b1, b2, a1, a2} = self.coefficients;
+        let mut out = [0; 3];
+        for (axis, state) in self.state.iter_mut().enumerate() {
+            let x = sample[axis];
+            let acc = b0 as i64 * x as i64
+                + b1 as i64 * state.x[0] as i64
+                + b2 as i64 * state.x[1] as i64
+                - a1 as i64 * state.y[0] as i64
+                - a2 as i64 * state.y[1] as i64
+                + state.error;
+            let y = (acc >> BIQUAD_FRAC_BITS) as i32;
+            state.error = acc - ((y as i64) << BIQUAD_FRAC_BITS);
+            state.x = [x, state.x[0]];
+            state.y = [y, state.y[0]];
+            out[axis] = y;
+        }
+        Some(out)
+    }
+
+    fn reset(&mut self) {
+        self.state = [BiquadState::default(); 3];
+    }
+}
+
+/// ## median of the last `len` samples, per axis
+///
+/// removes single spikes that averaging would smear, odd `len` recommended
+pub struct Median<const N: usize> {
+    window: [[i32; 3]; N],
+    len: usize,
+    next: usize,
+    filled: usize,
+}
+
+impl<const N: usize> Median<N> {
+    /// None unless 1 <= len <= N
+    pub fn new(len: usize) -> Option<Self> {
+        (1..=N).contains(&len).then_some(Median {window: [[0; 3]; N], len, next: 0, filled: 0})
+    }
+}
+
+impl<const N: usize> Filter3 for Median<N> {
+    /// median of the samples so far until the window is filled, the lower
+    /// middle one for an even count
+    fn push(&mut self, sample: [i32; 3]) -> Option<[i32; 3]> {
+        self.window[self.next] = sample;
+        self.next = (self.next + 1) % self.len;
+        self.filled = (self.filled + 1).min(self.len);
+        let mut out = [0; 3];
+        for (axis, out) in out.iter_mut().enumerate() {
+            let mut sorted = [0i32; N];
+            let sorted = &mut sorted[..self.filled];
+            for (value, sample) in sorted.iter_mut().zip(&self.window) {
+                *value = sample[axis];
+            }
+            // insertion sort, windows are short
+            for i in 1..sorted.len() {
+                let mut j = i;
+                while j > 0 && sorted[j - 1] > sorted[j] {
+                    sorted.swap(j - 1, j);
+                    j -= 1;
+                }
+            }
+            *out = sorted[(sorted.len() - 1) / 2];
+        }
+        Some(out)
+    }
+
+    fn reset(&mut self) {
+        self.next = 0;
+        self.filled = 0;
+    }
+}
+
+/// ## cascaded integrator-comb decimator
+///
+/// `order` integrators at the input rate, one output every `decimation`
+/// samples through `order` combs, divided by the gain decimation^order so
+/// the output stays in counts. Integrators wrap, the combs undo it
+pub struct Cic {
+    order: usize,
+    decimation: u32,
+    gain: i64,
+    integrators: [[i64; 3]; MAX_CIC_ORDER],
+    combs: [[i64; 3]; MAX_CIC_ORDER],
+    count: u32,
+}
+
+impl Cic {
+    /// None unless 1 <= order <= MAX_CIC_ORDER and 1 <= decimation <= 256
+    pub fn new(order: usize, decimation: u32) -> Option<Self> {
+        if !(1..=MAX_CIC_ORDER).contains(&order) || !(1..=256).contains(&decimation) {
+            return None;
+        }
+        Some(Cic {
+            order, decimation,
+            gain: (decimation as i64).pow(order as u32),
+            integrators: [[0; 3]; MAX_CIC_ORDER],
+            combs: [[0; 3]; MAX_CIC_ORDER],
+            count: 0,
+        })
+    }
+}
+
+impl Filter3 for Cic {
+    /// the first `order` outputs still settle
+    fn push(&mut self, sample: [i32; 3]) -> Option<[i32; 3]> {
+        for axis in 0..3 {
+            let mut value = sample[axis] as i64;
+            for integrator in &mut self.integrators[..self.order] {
+                integrator[axis] = integrator[axis].wrapping_add(value);
+                value = integrator[axis];
+            }
+        }
+        self.count += 1;
+        if self.count < self.decimation {
+            return None;
+        }
+        self.count = 0;
+        let mut out = [0; 3];
+        for (axis, out) in out.iter_mut().enumerate() {
+            let mut value = self.integrators[self.order - 1][axis];
+            for comb in &mut self.combs[..self.order] {
+                let delayed = core::mem::replace(&mut comb[axis], value);
+                value = value.wrapping_sub(delayed);
+            }
+            *out = div_round(value, self.gain) as i32;
+        }
+        Some(out)
+    }
+
+    fn reset(&mut self) {
+        self.integrators = [[0; 3]; MAX_CIC_ORDER];
+        self.combs = [[0; 3]; MAX_CIC_ORDER];
+        self.count = 0;
+    }
+}
+
+/// ## filter choice, e.g. sent by a host
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub enum FilterConfig {
+    /// samples pass unchanged
+    #[default]
+    None,
+    MovingAverage { len: u8 },
+    /// alpha Q16
+    Ema { alpha: u16 },
+    Biquad(Coefficients),
+    Median { len: u8 },
+    Cic { order: u8, decimation: u16 },
+}
+
+impl FilterConfig {
+    /// parameters in range, see `Filter::new`
+    pub fn is_valid(&self) -> bool {
+        match *self {
+            FilterConfig::None | FilterConfig::Biquad(_) => true,
+            FilterConfig::MovingAverage { len } | FilterConfig::Median { len } => {
+                (1..=MAX_WINDOW).contains(&(len as usize))
+            },
+            FilterConfig::Ema { alpha } => alpha > 0,
+            FilterConfig::Cic { order, decimation } => {
+                (1..=MAX_CIC_ORDER).contains(&(order as usize)) && (1..=256).contains(&decimation)
+            },
+        }
+    }
+}
+
+/// ## one filter chosen at runtime
+#[derive(Default)]
+pub enum Filter {
+    #[default]
+    None,
+    MovingAverage(MovingAverage<MAX_WINDOW>),
+    Ema(Ema),
+    Biquad(Biquad),
+    Median(Median<MAX_WINDOW>),
+    Cic(Cic),
+}
+
+impl Filter {
+    /// ## filter of config, None if a parameter is out of range
+    pub fn new(config: FilterConfig) -> Option<Self> {
+        Some(match config {
+            FilterConfig::None => Filter::None,
+            FilterConfig::MovingAverage { len } => Filter::MovingAverage(MovingAverage::new(len as usize)?),
+            FilterConfig::Ema { alpha } => Filter::Ema(Ema::new(alpha)?),
+            FilterConfig::Biquad(coefficients) => Filter::Biquad(Biquad::new(coefficients)),
+            FilterConfig::Median { len } => Filter::Median(Median::new(len as usize)?),
+            FilterConfig::Cic { order, decimation } => Filter::Cic(Cic::new(order as usize, decimation as u32)?),
+        })
+    }
+
+    /// ## replace the filter, state starts over; false (and unchanged) if invalid
+    pub fn configure(&mut self, config: FilterConfig) -> bool {
+        match Filter::new(config) {
+            Some(filter) => {*self = filter; true},
+            None => false,
+        }
+    }
+
+    pub fn config(&self) -> FilterConfig {
+        match self {
+            Filter::None => FilterConfig::None,
+            Filter::MovingAverage(filter) => FilterConfig::MovingAverage { len: filter.len as u8 },
+            Filter::Ema(filter) => FilterConfig::Ema { alpha: filter.alpha as u16 },
+            Filter::Biquad(filter) => FilterConfig::Biquad(filter.coefficients),
+            Filter::Median(filter) => FilterConfig::Median { len: filter.len as u8 },
+            Filter::Cic(filter) => FilterConfig::Cic { order: filter.order as u8, decimation: filter.decimation as u16 },
+        }
+    }
+}
+
+impl Filter3 for Filter {
+    fn push(&mut self, sample: [i32; 3]) -> Option<[i32; 3]> {
+        match self {
+            Filter::None => Some(sample),
+            Filter::MovingAverage(filter) => filter.push(sample),
+            Filter::Ema(filter) => filter.push(sample),
+            Filter::Biquad(filter) => filter.push(sample),
+            Filter::Median(filter) => filter.push(sample),
+            Filter::Cic(filter) => filter.push(sample),
+        }
+    }
+
+    fn reset(&mut self) {
+        match self {
+            Filter::None => {},
+            Filter::MovingAverage(filter) => filter.reset(),
+            Filter::Ema(filter) => filter.reset(),
+            Filter::Biquad(filter) => filter.reset(),
+            Filter::Median(filter) => filter.reset(),
+            Filter::Cic(filter) => filter.reset(),
+        }
+    }
+}
+
+/// n / d rounded half away from 0, d > 0
+fn div_round(n: i64, d: i64) -> i64 {
+    if n >= 0 {(n + d / 2) / d} else {(n - d / 2) / d}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::f32::consts::FRAC_1_SQRT_2;
+
+    fn run(filter: &mut impl Filter3, input: &[i32]) -> ([i32; 32], usize) {
+        let mut out = [0; 32];
+        let mut len = 0;
+        for value in input {
+            if let Some(sample) = filter.push([*value, *value, *value]) {
+                assert!(sample[1] == sample[0] && sample[2] == sample[0]);
+                out[len] = sample[0];
+                len += 1;
+            }
+        }
+        (out, len)
+    }
+
+    #[test]
+    fn moving_average() {
+        let mut filter = MovingAverage::<8>::new(4).unwrap();
+        let (out, len) = run(&mut filter, &[4, 8, 0, 4, 100, 100, 100, 100]);
+        assert_eq!(out[..len], [4, 6, 4, 4, 28, 51, 76, 100]);
+        filter.reset();
+        assert_eq!(filter.push([7, 7, 7]), Some([7, 7, 7]));
+        assert!(MovingAverage::<8>::new(9).is_none());
+        assert!(MovingAverage::<8>::new(0).is_none());
+    }
+
+    #[test]
+    fn ema_step() {
+        let mut filter = Ema::new(32768).unwrap();
+        let (out, len) = run(&mut filter, &[0, 1000, 1000, 1000, 1000]);
+        assert_eq!(out[..len], [0, 500, 750, 875, 938]);
+        assert_eq!(Ema::alpha(4.0), 16384);
+        assert!(Ema::new(0).is_none());
+    }
+
+    #[test]
+    fn biquad_lowpass() {
+        let mut filter = Biquad::new(Coefficients::lowpass(5.0, 100.0, FRAC_1_SQRT_2));
+        // settles on a step, exactly thanks to error feedback
+        let mut last = [0; 3];
+        for _ in 0..200 {
+            last = filter.push([123_457, -123_457, 0]).unwrap();
+        }
+        assert_eq!(last, [123_457, -123_457, 0]);
+        // nyquist is blocked
+        filter.reset();
+        let alternating: [i32; 32] = core::array::from_fn(|i| if i % 2 == 0 {10_000} else {-10_000});
+        let (out, _) = run(&mut filter, &alternating);
+        assert!(out[20..].iter().all(|value| value.abs() < 100), "{:?}", out);
+    }
+
+    #[test]
+    fn biquad_highpass_removes_offset() {
+        let mut filter = Biquad::new(Coefficients::highpass(1.0, 100.0, FRAC_1_SQRT_2));
+        let mut last = [0; 3];
+        for _ in 0..2000 {
+            last = filter.push([50_000, 50_000, 50_000]).unwrap();
+        }
+        assert!(last.iter().all(|value| value.abs() <= 1), "{:?}", last);
+    }
+
+    #[test]
+    fn median_removes_spikes() {
+        let mut filter = Median::<5>::new(3).unwrap();
+        let (out, len) = run(&mut filter, &[10, 9000, 11, 12, -9000, 13]);
+        assert_eq!(out[..len], [10, 10, 11, 12, 11, 12]);
+    }
+
+    #[test]
+    fn cic_decimates() {
+        let mut filter = Cic::new(3, 4).unwrap();
+        let input: [i32; 32] = core::array::from_fn(|i| 800_000 - (i as i32 % 2) * 2);
+        let (out, len) = run(&mut filter, &input);
+        assert_eq!(len, 8);
+        // settled after `order` outputs, the mean
+        assert!(out[3..len].iter().all(|value| *value == 799_999), "{:?}", &out[..len]);
+        assert!(Cic::new(5, 4).is_none());
+        assert!(Cic::new(2, 257).is_none());
+    }
+
+    #[test]
+    fn runtime_choice() {
+        let mut filter = Filter::default();
+        assert_eq!(filter.push([1, 2, 3]), Some([1, 2, 3]));
+        let config = FilterConfig::Cic { order: 2, decimation: 2 };
+        assert!(filter.configure(config));
+        assert_eq!(filter.config(), config);
+        assert_eq!(filter.push([4, 4, 4]), None);
+        assert!(filter.push([4, 4, 4]).is_some());
+        assert!(!filter.configure(FilterConfig::Median { len: 0 }));
+        assert_eq!(filter.config(), config);
+        let biquad = FilterConfig::Biquad(Coefficients::from_float([1.0, 0.0, 0.0], [0.0, 0.0]));
+        assert!(filter.configure(biquad));
+        assert_eq!(filter.push([-5, 0, 5]), Some([-5, 0, 5]));
+    }
+}
@@ -0,0 +1,147 @@
+//! time-aligned frames from several RM3100s measured on one trigger
+//!
+//! hardware independent: start a frame when the sensors are triggered,
+//! feed each sensor's sample as its DRDY arrives, get the frame back once
+//! every sensor reported
+
+use crate::CycleCount;
+
+/// most sensors in one frame
+pub const MAX_SENSORS: usize = 4;
+
+/// per-axis differences of neighbouring sensors(nT), sensor i+1 - sensor i
+pub type Gradients = [[i32; 3]; MAX_SENSORS - 1];
+
+/// ## one measurement of all sensors on the same trigger
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct Frame {
+    /// sensors in this frame, the rest of the arrays is unused
+    pub sensors: u8,
+    pub trigger_tick: u32,
+    /// x/y/z counts per sensor
+    pub mag: [[i32; 3]; MAX_SENSORS],
+    pub drdy_tick: [u32; MAX_SENSORS],
+}
+
+impl Frame {
+    /// ## ticks between first and last DRDY
+    pub fn skew(&self) -> u32 {
+        let delays = self.drdy_tick[..self.sensors as usize]
+            .iter()
+            .map(|tick| tick.wrapping_sub(self.trigger_tick));
+        let min = delays.clone().min().unwrap_or(0);
+        let max = delays.max().unwrap_or(0);
+        max - min
+    }
+
+    /// ## per-axis differences(nT) of neighbouring sensors
+    ///
+    /// all sensors use cycle count `cc`, divide by the baseline for the gradient
+    pub fn gradients(&self, cc: CycleCount) -> Gradients {
+        let mut gradients = Gradients::default();
+        let sensors = self.sensors as usize;
+        for (i, gradient) in gradients.iter_mut().enumerate().take(sensors.saturating_sub(1)) {
+            let [a, b] = [cc.to_nanotesla(self.mag[i]), cc.to_nanotesla(self.mag[i + 1])];
+            for axis in 0..3 {
+                gradient[axis] = b[axis] - a[axis];
+            }
+        }
+        gradients
+    }
+}
+
+/// ## assembles frames from samples arriving in any order
+pub struct Collector {
+    sensors: u8,
+    /// bit per sensor still to report
+    pending: u8,
+    frame: Frame,
+}
+
+impl Collector {
+    /// ## collect frames of `sensors` sensors, at most MAX_SENSORS
+    pub fn new(sensors: usize) -> Self {
+        assert!(sensors > 0 && sensors <= MAX_SENSORS);
+        Collector {
+            sensors: sensors as u8,
+            pending: 0,
+            frame: Frame {sensors: sensors as u8, ..Frame::default()},
+        }
+    }
+
+    /// a frame is being collected
+    pub fn is_busy(&self) -> bool {self.pending != 0}
+
+    /// ## all sensors were started at `trigger_tick`
+    ///
+    /// return true if an incomplete frame was dropped
+    pub fn start(&mut self, trigger_tick: u32) -> bool {
+        let dropped = self.is_busy();
+        self.pending = (1 << self.sensors) - 1;
+        self.frame = Frame {sensors: self.sensors, trigger_tick, ..Frame::default()};
+        dropped
+    }
+
+    /// ## sample of sensor `index`
+    ///
+    /// return the frame once every sensor reported, samples outside a frame are ignored
+    pub fn on_sample(&mut self, index: usize, mag: [i32; 3], drdy_tick: u32) -> Option<Frame> {
+        if index >= self.sensors as usize || self.pending & (1 << index) == 0 {
+            return None;
+        }
+        self.frame.mag[index] = mag;
+        self.frame.drdy_tick[index] = drdy_tick;
+        self.pending &= !(1 << index);
+        if self.pending == 0 {Some(self.frame)} else {None}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn collects_frames_in_any_order() {
+        let mut collector = Collector::new(3);
+        assert!(!collector.start(100));
+        assert!(collector.is_busy());
+        assert_eq!(collector.on_sample(2, [750, 0, -750], 190), None);
+        assert_eq!(collector.on_sample(0, [0, 0, 0], 160), None);
+        // repeated sample of a sensor and unknown sensors are ignored
+        assert_eq!(collector.on_sample(0, [1, 1, 1], 170), None);
+        assert_eq!(collector.on_sample(3, [1, 1, 1], 170), None);
+        let frame = collector.on_sample(1, [75, 75, 75], 175).unwrap();
+        assert!(!collector.is_busy());
+        assert_eq!(frame.mag[..3], [[0, 0, 0], [75, 75, 75], [750, 0, -750]]);
+        assert_eq!(frame.drdy_tick[..3], [160, 175, 190]);
+        // samples outside a frame
+        assert_eq!(collector.on_sample(1, [75, 75, 75], 180), None);
+    }
+
+    #[test]
+    fn skew_and_gradients() {
+        let mut frame = Frame { sensors: 3, trigger_tick: u32::MAX - 10, ..Frame::default() };
+        frame.mag[..3].copy_from_slice(&[[0, 0, 0], [75, 75, 75], [750, 0, -750]]);
+        // DRDY ticks across the tick counter wrap
+        frame.drdy_tick[..3].copy_from_slice(&[50, 20, 35]);
+        assert_eq!(frame.skew(), 30);
+        // 75 LSB/uT at cc 200
+        let gradients = frame.gradients(CycleCount::default());
+        assert_eq!(gradients, [[1000, 1000, 1000], [9000, -1000, -11000], [0, 0, 0]]);
+        let single = Frame { sensors: 1, ..frame };
+        assert_eq!(single.skew(), 0);
+        assert_eq!(single.gradients(CycleCount::default()), Gradients::default());
+    }
+
+    #[test]
+    fn next_trigger_drops_incomplete_frame() {
+        let mut collector = Collector::new(2);
+        collector.start(200);
+        collector.on_sample(0, [0, 0, 0], 260);
+        assert!(collector.start(300));
+        // the new frame needs both sensors again
+        assert_eq!(collector.on_sample(1, [1, 2, 3], 360), None);
+        let frame = collector.on_sample(0, [4, 5, 6], 370).unwrap();
+        assert_eq!((frame.trigger_tick, frame.mag[0], frame.mag[1]), (300, [4, 5, 6], [1, 2, 3]));
+    }
+}
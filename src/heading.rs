@@ -0,0 +1,118 @@
+//! tilt-compensated compass heading
+//!
+//! hardware independent: field and accelerometer vectors in one body frame,
+//! x forward, y left, z up. The accelerometer vector is the reading at rest,
+//! pointing up (+z when level); any unit and magnitude will do for both.
+//! Rotate sensor axes into this frame first if they are mounted otherwise,
+//! and calibrate the field (see `calibration`) for a usable heading
+
+/// ## orientation of the body(degrees)
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Attitude {
+    /// 0..360, clockwise from north: magnetic, or true if a declination was given
+    pub heading: f32,
+    /// -90..90, nose up positive
+    pub pitch: f32,
+    /// -180..180, right side down positive
+    pub roll: f32,
+}
+
+/// ## attitude from a field and an accelerometer vector
+///
+/// `declination`(degrees, east positive) turns the magnetic heading into a
+/// true heading. None if the accelerometer reads 0 or the field is vertical
+pub fn attitude(mag: [f32; 3], accel: [f32; 3], declination: Option<f32>) -> Option<Attitude> {
+    let up = normalize(accel)?;
+    // horizontal directions in the body frame
+    let east = normalize(cross(mag, up))?;
+    let north = cross(up, east);
+    let heading = libm::atan2f(east[0], north[0]).to_degrees() + declination.unwrap_or(0.0);
+    Some(Attitude {
+        heading: wrap(heading),
+        pitch: libm::asinf(up[0].clamp(-1.0, 1.0)).to_degrees(),
+        roll: libm::atan2f(up[1], up[2]).to_degrees(),
+    })
+}
+
+/// degrees to 0..360
+fn wrap(degrees: f32) -> f32 {
+    let wrapped = degrees - 360.0 * libm::floorf(degrees / 360.0);
+    // -1e-6 wraps to 360.0 in f32
+    if wrapped >= 360.0 {0.0} else {wrapped}
+}
+
+fn cross(a: [f32; 3], b: [f32; 3]) -> [f32; 3] {
+    [a[1] * b[2] - a[2] * b[1], a[2] * b[0] - a[0] * b[2], a[0] * b[1] - a[1] * b[0]]
+}
+
+/// unit vector, None if too short to have a direction
+fn normalize(v: [f32; 3]) -> Option<[f32; 3]> {
+    let length = libm::sqrtf(v[0] * v[0] + v[1] * v[1] + v[2] * v[2]);
+    if length <= f32::EPSILON {
+        return None;
+    }
+    Some(v.map(|value| value / length))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// north, west, up components of a field dipping 60° down
+    const FIELD: [f32; 3] = [20_000.0, 0.0, -34_641.0];
+
+    /// world (north, west, up) vector seen from a body at heading, pitch and roll
+    fn body(world: [f32; 3], heading: f32, pitch: f32, roll: f32) -> [f32; 3] {
+        let (sy, cy) = (libm::sinf(heading.to_radians()), libm::cosf(heading.to_radians()));
+        let (sp, cp) = (libm::sinf(pitch.to_radians()), libm::cosf(pitch.to_radians()));
+        let (sr, cr) = (libm::sinf(roll.to_radians()), libm::cosf(roll.to_radians()));
+        let yawed = [cy * world[0] - sy * world[1], sy * world[0] + cy * world[1], world[2]];
+        let pitched = [cp * yawed[0] + sp * yawed[2], yawed[1], -sp * yawed[0] + cp * yawed[2]];
+        [pitched[0], cr * pitched[1] + sr * pitched[2], -sr * pitched[1] + cr * pitched[2]]
+    }
+
+    fn close(a: f32, b: f32) -> bool {
+        let difference = libm::fabsf(a - b);
+        difference < 0.01 || libm::fabsf(difference - 360.0) < 0.01
+    }
+
+    #[test]
+    fn tilt_does_not_change_heading() {
+        for heading in [0.0, 45.0, 170.0, 300.0] {
+            for pitch in [-30.0, 0.0, 20.0, 75.0] {
+                for roll in [-40.0, 0.0, 60.0, 150.0] {
+                    let mag = body(FIELD, heading, pitch, roll);
+                    let accel = body([0.0, 0.0, 9.81], heading, pitch, roll);
+                    let attitude = attitude(mag, accel, None).unwrap();
+                    assert!(close(attitude.heading, heading), "{} {} {}: {:?}", heading, pitch, roll, attitude);
+                    assert!(close(attitude.pitch, pitch), "{:?}", attitude);
+                    assert!(close(attitude.roll, roll), "{:?}", attitude);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn level_headings() {
+        // x pointing east sees north on the left (+y)
+        let east = attitude([0.0, 20_000.0, -34_641.0], [0.0, 0.0, 1.0], None).unwrap();
+        assert_eq!(east, Attitude { heading: 90.0, pitch: 0.0, roll: 0.0 });
+        let west = attitude([0.0, -20_000.0, -34_641.0], [0.0, 0.0, 1.0], None).unwrap();
+        assert_eq!(west.heading, 270.0);
+    }
+
+    #[test]
+    fn declination_gives_true_heading() {
+        let mag = body(FIELD, 350.0, 10.0, -5.0);
+        let accel = body([0.0, 0.0, 1.0], 350.0, 10.0, -5.0);
+        assert!(close(attitude(mag, accel, Some(15.0)).unwrap().heading, 5.0));
+        assert!(close(attitude(mag, accel, Some(-20.0)).unwrap().heading, 330.0));
+    }
+
+    #[test]
+    fn undefined_heading() {
+        assert_eq!(attitude(FIELD, [0.0; 3], None), None);
+        // field straight down, at a magnetic pole
+        assert_eq!(attitude([0.0, 0.0, -50_000.0], [0.0, 0.0, 1.0], None), None);
+    }
+}
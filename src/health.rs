@@ -0,0 +1,131 @@
+/// sensor fault found by the last health check
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub enum Fault {
+    #[default]
+    None = 0,
+    /// REVID does not match, sensor unplugged or not powered
+    Disconnected = 1,
+    /// registers differ from the last written config, e.g. after a brown-out
+    ConfigMismatch = 2,
+    /// no DRDY long after a single measurement was started
+    DrdyTimeout = 3,
+}
+
+impl TryFrom<u8> for Fault {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Fault::None),
+            1 => Ok(Fault::Disconnected),
+            2 => Ok(Fault::ConfigMismatch),
+            3 => Ok(Fault::DrdyTimeout),
+            _ => Err(value),
+        }
+    }
+}
+
+/// results of one periodic health check
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Check {
+    pub revid_ok: bool,
+    pub config_ok: bool,
+    /// a single measurement is in flight for longer than it can take
+    pub drdy_overdue: bool,
+}
+
+/// ## sensor health state
+///
+/// hardware independent: feed it periodic checks,
+/// re-initialize the sensor when `on_check` says so
+#[derive(Default)]
+pub struct Monitor {
+    fault: Fault,
+    /// healthy to faulty transitions
+    faults: u32,
+    /// faulty to healthy transitions
+    recoveries: u32,
+}
+
+impl Monitor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn fault(&self) -> Fault {self.fault}
+
+    pub fn is_faulty(&self) -> bool {self.fault != Fault::None}
+
+    pub fn faults(&self) -> u32 {self.faults}
+
+    pub fn recoveries(&self) -> u32 {self.recoveries}
+
+    /// ## apply one check
+    ///
+    /// return true if the sensor should be re-initialized with the last good config,
+    /// once per fault: again only after a passed check or a different fault
+    pub fn on_check(&mut self, check: Check) -> bool {
+        let fault = if !check.revid_ok {
+            Fault::Disconnected
+        } else if !check.config_ok {
+            Fault::ConfigMismatch
+        } else if check.drdy_overdue {
+            Fault::DrdyTimeout
+        } else {
+            Fault::None
+        };
+        match (self.is_faulty(), fault != Fault::None) {
+            (false, true) => self.faults = self.faults.wrapping_add(1),
+            (true, false) => self.recoveries = self.recoveries.wrapping_add(1),
+            _ => {},
+        }
+        let reinit = fault != Fault::None && fault != self.fault;
+        self.fault = fault;
+        reinit
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OK: Check = Check { revid_ok: true, config_ok: true, drdy_overdue: false };
+
+    #[test]
+    fn faults_by_priority() {
+        let cases = [
+            (Check { revid_ok: false, config_ok: false, drdy_overdue: true }, Fault::Disconnected),
+            (Check { config_ok: false, drdy_overdue: true, ..OK }, Fault::ConfigMismatch),
+            (Check { drdy_overdue: true, ..OK }, Fault::DrdyTimeout),
+            (OK, Fault::None),
+        ];
+        for (check, fault) in cases {
+            let mut monitor = Monitor::new();
+            assert_eq!(monitor.on_check(check), fault != Fault::None);
+            assert_eq!(monitor.fault(), fault);
+            assert_eq!(monitor.is_faulty(), fault != Fault::None);
+        }
+    }
+
+    #[test]
+    fn one_reinit_per_fault() {
+        let mut monitor = Monitor::new();
+        assert!(!monitor.on_check(OK));
+        let unplugged = Check { revid_ok: false, ..OK };
+        assert!(monitor.on_check(unplugged));
+        // still unplugged
+        assert!(!monitor.on_check(unplugged));
+        assert!(!monitor.on_check(unplugged));
+        // back with power-up registers
+        assert!(monitor.on_check(Check { config_ok: false, ..OK }));
+        assert!(!monitor.on_check(Check { config_ok: false, ..OK }));
+        assert_eq!((monitor.faults(), monitor.recoveries()), (1, 0));
+        assert!(!monitor.on_check(OK));
+        assert_eq!((monitor.faults(), monitor.recoveries()), (1, 1));
+        assert!(!monitor.is_faulty());
+        // the same fault again after recovery
+        assert!(monitor.on_check(unplugged));
+        assert!(!monitor.on_check(OK));
+        assert_eq!((monitor.faults(), monitor.recoveries()), (2, 2));
+    }
+}
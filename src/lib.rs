@@ -2,6 +2,17 @@
 
 pub mod packet;
 pub mod mincircularbuffer;
+pub mod protocol;
+pub mod console;
+pub mod health;
+pub mod power;
+pub mod gradiometer;
+pub mod trigger;
+pub mod vendor;
+pub mod calibration;
+pub mod heading;
+pub mod filter;
+pub mod server;
 use packet::Packet;
 
 use embedded_hal::{self, digital::v2::OutputPin};
@@ -38,9 +49,12 @@ const CMY_SHIFT: u8 = 5;
 const CMZ_SHIFT: u8 = 6;
 const STATUS_SHIFT: u8 = 7;
 
+/// STATUS reads before `measure_averaged` gives up waiting for DRDY
+const DRDY_POLLS: u32 = 1_000_000;
 
 
-#[derive(Clone, Copy)]
+
+#[derive(Clone, Copy, PartialEq, Debug)]
 pub struct CycleCount {
     pub x: u16,
     pub y: u16,
@@ -53,6 +67,32 @@ impl Default for CycleCount {
     }
 }
 
+impl CycleCount {
+    /// ## estimated single measurement time
+    /// 
+    /// linear fit to datasheet max data rates (~535Hz at cc 50, ~147Hz at cc 200, 3 axes),
+    /// axes are measured one after another
+    pub fn measure_time_us(&self, axes: Axes) -> u32 {
+        let axis = |cc: u16, measured: bool| if measured {75 + 11 * cc as u32} else {0};
+        axis(self.x, axes.x) + axis(self.y, axes.y) + axis(self.z, axes.z)
+    }
+
+    /// ## gain(LSB/uT) * 30 of one axis
+    /// 
+    /// linear fit to datasheet gains (20 at cc 50, 38 at cc 100, 75 at cc 200)
+    pub fn gain_x30(cc: u16) -> i64 {
+        11 * cc as i64 + 50
+    }
+
+    /// ## convert x/y/z counts to nT
+    pub fn to_nanotesla(&self, mag: [i32; 3]) -> [i32; 3] {
+        let convert = |counts: i32, cc: u16| {
+            (counts as i64 * 30_000 / Self::gain_x30(cc)) as i32
+        };
+        [convert(mag[0], self.x), convert(mag[1], self.y), convert(mag[2], self.z)]
+    }
+}
+
 #[derive(PartialEq)]
 pub enum Status {
     Available,
@@ -66,7 +106,7 @@ impl From<bool> for Status {
     }
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, PartialEq, Debug)]
 pub enum UpdateRate {
     Hz600 = 0x92,
     Hz300 = 0x93,
@@ -106,6 +146,31 @@ impl From<f32> for UpdateRate {
     }
 }
 
+impl TryFrom<u8> for UpdateRate {
+    type Error = u8;
+
+    /// convert raw TMRC value back to UpdateRate
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0x92 => Ok(UpdateRate::Hz600),
+            0x93 => Ok(UpdateRate::Hz300),
+            0x94 => Ok(UpdateRate::Hz150),
+            0x95 => Ok(UpdateRate::Hz75),
+            0x96 => Ok(UpdateRate::Hz37),
+            0x97 => Ok(UpdateRate::Hz18),
+            0x98 => Ok(UpdateRate::Hz9),
+            0x99 => Ok(UpdateRate::Hz4_5),
+            0x9A => Ok(UpdateRate::Hz2_3),
+            0x9B => Ok(UpdateRate::Hz1_2),
+            0x9C => Ok(UpdateRate::Hz0_6),
+            0x9D => Ok(UpdateRate::Hz0_3),
+            0x9E => Ok(UpdateRate::Hz0_15),
+            0x9F => Ok(UpdateRate::Hz0_075),
+            _ => Err(value),
+        }
+    }
+}
+
 impl From<UpdateRate> for f32 {
     fn from(rate: UpdateRate) -> Self {
         600 as f32 / (1 << ((0xF & rate as u8) - 2)) as f32
@@ -118,7 +183,7 @@ impl Default for UpdateRate {
     }
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, PartialEq, Debug)]
 pub enum DRDM {
     AlarmFull = 0b0000,
     Any = 0b0100,
@@ -132,6 +197,46 @@ impl Default for DRDM {
     }
 }
 
+impl From<u8> for DRDM {
+    /// convert CMM bit 3&2 back to DRDM, other bits are ignored
+    fn from(value: u8) -> Self {
+        match value & 0b1100 {
+            0b0000 => DRDM::AlarmFull,
+            0b0100 => DRDM::Any,
+            0b1000 => DRDM::Full,
+            _ => DRDM::Alarm,
+        }
+    }
+}
+
+/// axes to be measured
+/// 
+/// bitmask form: bit 0 x, bit 1 y, bit 2 z
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Axes {
+    pub x: bool,
+    pub y: bool,
+    pub z: bool,
+}
+
+impl Axes {
+    pub const X: Axes = Axes { x: true, y: false, z: false };
+    pub const XYZ: Axes = Axes { x: true, y: true, z: true };
+}
+
+impl From<u8> for Axes {
+    fn from(mask: u8) -> Self {
+        Axes { x: mask & 0b001 != 0, y: mask & 0b010 != 0, z: mask & 0b100 != 0 }
+    }
+}
+
+impl From<Axes> for u8 {
+    fn from(axes: Axes) -> Self {
+        (axes.x as u8) | ((axes.y as u8) << 1) | ((axes.z as u8) << 2)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
 pub struct  Config {
     pub cc: CycleCount,
     pub rate: UpdateRate,
@@ -148,10 +253,25 @@ impl Default for Config {
     }
 }
 
+/// ## statistics of repeated single measurements(counts)
+///
+/// axes not measured read 0
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Averaged {
+    pub count: u32,
+    pub mean: [f32; 3],
+    /// sample standard deviation, 0 for one measurement
+    pub std_dev: [f32; 3],
+    pub min: [i32; 3],
+    pub max: [i32; 3],
+}
+
 pub struct RM3100<Spi, CsPin> {
     spi: Spi,
     cs: CsPin,
-    config: Config
+    config: Config,
+    /// CMM axis and START bits of a running continuous measurement, 0 if stopped
+    continuous: u8,
 }
 
 impl<Spi, SpiError, CsPin, PinError> RM3100<Spi, CsPin> 
@@ -165,6 +285,7 @@ where
             spi,
             cs,
             config,
+            continuous: 0,
         };
         rm3100.cs.set_high().ok();
         rm3100
@@ -271,9 +392,51 @@ where
     /// ## Set DRDY Mode (CMM bit 3&2)
     /// 
     /// Alarm is omitted currently
+    /// 
+    /// a running continuous measurement keeps running
     pub fn set_drdm(&mut self, mode: DRDM) -> &mut Self {
         self.config.drdm = mode;
-        self.write_byte(CMM_REG, mode as u8)
+        self.write_byte(CMM_REG, mode as u8 | self.continuous)
+    }
+
+    /// ## Apply full configuration
+    /// 
+    /// write cycle counts, update rate and DRDY mode in order
+    pub fn set_config(&mut self, config: Config) -> &mut Self {
+        self.set_cycle_count_xyz(config.cc.x, config.cc.y, config.cc.z)
+            .set_update_rate(config.rate)
+            .set_drdm(config.drdm)
+    }
+
+    pub fn get_config(&mut self) -> Config {self.config}
+
+    /// ## read configuration back from the registers
+    /// 
+    /// None if TMRC holds no valid update rate
+    pub fn read_config(&mut self) -> Option<Config> {
+        Some(Config {
+            cc: CycleCount {
+                x: self.read_word(CCX_REG),
+                y: self.read_word(CCY_REG),
+                z: self.read_word(CCZ_REG),
+            },
+            rate: UpdateRate::try_from(self.read_byte(TMRC_REG)).ok()?,
+            drdm: DRDM::from(self.read_byte(CMM_REG)),
+        })
+    }
+
+    /// registers still hold the last written configuration
+    pub fn check_config(&mut self) -> bool {
+        self.read_config() == Some(self.config)
+    }
+
+    /// ## write the last configuration again
+    /// 
+    /// after a brown-out or reconnect, restarts a continuous measurement
+    /// that was running
+    pub fn reinit(&mut self) -> &mut Self {
+        let config = self.config;
+        self.set_config(config)
     }
 
 
@@ -293,25 +456,30 @@ where
     }
 
     /// ## start continuous measurement
+    /// 
+    /// kept running by later configuration changes until stopped
     pub fn start_continuous_measure(
         &mut self, x: bool, y: bool, z: bool
     ) {
-        self.write_byte(CMM_REG, 
-            self.config.drdm as u8 |
+        self.continuous =
             ((x as u8) << CMX_SHIFT) |
             ((y as u8) << CMY_SHIFT) |
             ((z as u8) << CMZ_SHIFT) |
-            true as u8 // Start bit
-        );
+            true as u8; // Start bit
+        self.write_byte(CMM_REG, self.config.drdm as u8 | self.continuous);
     }
 
     /// ## stop continuous measurement
     pub fn stop_continuous_measure(&mut self) -> &mut Self {
+        self.continuous = 0;
         self.write_byte(CMM_REG, 
             self.config.drdm as u8 | false as u8
         )
     }
 
+    /// continuous measurement started and not stopped
+    pub fn is_continuous(&self) -> bool {self.continuous != 0}
+
     /// ## check connect
     /// 
     /// compare revid (0x22 for rm3100 from wit)
@@ -319,6 +487,11 @@ where
         self.read_byte(REVID_REG) == revid
     }
 
+    /// ## read REVID register
+    pub fn read_revid(&mut self) -> u8 {
+        self.read_byte(REVID_REG)
+    }
+
     /// ## DRDY by spi
     pub fn get_status(&mut self) -> Status {
         ((self.read_byte(STATUS_REG) 
@@ -342,6 +515,66 @@ where
         self.read_bytes::<10, [i32;3]>(MX_REG)
     }
 
+    /// ## mean, spread and range of `n` single measurements
+    /// 
+    /// back to back, polling DRDY over SPI; sums are 64-bit and taken
+    /// relative to the first measurement, so the variance does not cancel.
+    /// None if `n` is 0, no axis is selected, a continuous measurement is
+    /// running (its results would mix in) or DRDY does not come
+    pub fn measure_averaged(&mut self, axes: Axes, n: u32) -> Option<Averaged> {
+        if n == 0 || u8::from(axes) == 0 || self.continuous != 0 {
+            return None;
+        }
+        let selected = [axes.x, axes.y, axes.z];
+        let mut first = [0i32; 3];
+        let (mut sum, mut sum_squares) = ([0i64; 3], [0i64; 3]);
+        let (mut min, mut max) = ([i32::MAX; 3], [i32::MIN; 3]);
+        for i in 0..n {
+            self.start_single_measure(axes.x, axes.y, axes.z);
+            if !(0..DRDY_POLLS).any(|_| self.get_status() == Status::Available) {
+                return None;
+            }
+            let mag = self.read_mag();
+            if i == 0 {
+                first = mag;
+            }
+            for axis in 0..3 {
+                let deviation = (mag[axis] - first[axis]) as i64;
+                sum[axis] += deviation;
+                sum_squares[axis] += deviation * deviation;
+                min[axis] = min[axis].min(mag[axis]);
+                max[axis] = max[axis].max(mag[axis]);
+            }
+        }
+        let mut averaged = Averaged { count: n, mean: [0.0; 3], std_dev: [0.0; 3], min: [0; 3], max: [0; 3] };
+        for axis in (0..3).filter(|axis| selected[*axis]) {
+            let mean = sum[axis] as f64 / n as f64;
+            averaged.mean[axis] = (first[axis] as f64 + mean) as f32;
+            if n > 1 {
+                let variance = (sum_squares[axis] as f64 - mean * sum[axis] as f64) / (n - 1) as f64;
+                averaged.std_dev[axis] = libm::sqrt(variance.max(0.0)) as f32;
+            }
+            averaged.min[axis] = min[axis];
+            averaged.max[axis] = max[axis];
+        }
+        Some(averaged)
+    }
+
+    /// ## select the sensor, return the packet reading x/y/z
+    /// 
+    /// clock the packet through SPI by other means (e.g. DMA) in place,
+    /// then pass it to `end_read_mag`, no other access in between
+    pub fn begin_read_mag(&mut self) -> Packet<10> {
+        self.cs.set_low().ok();
+        *Packet::<10>::default().address(READ_FLAG | MX_REG)
+    }
+
+    /// ## deselect the sensor, decode x/y/z from the clocked packet
+    pub fn end_read_mag(&mut self, packet: Packet<10>) -> [i32; 3] {
+        self.cs.set_high().ok();
+        packet.into()
+    }
+
     
     
 
@@ -39,6 +39,25 @@ where Data: Copy + Clone
         }
     }
 
+    /// number of buffered data
+    pub fn len(&self) -> usize {
+        (self.end_index + N - self.start_index) % N
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.start_index == self.end_index
+    }
+
+    /// one slot is kept free to tell full from empty
+    pub fn capacity(&self) -> usize {
+        N - 1
+    }
+
+    /// next push will overflow
+    pub fn is_full(&self) -> bool {
+        self.len() == self.capacity()
+    }
+
     /// clear buffer
     /// 
     /// simply set start/stop to 0
@@ -0,0 +1,93 @@
+use crate::{Axes, CycleCount};
+
+/// sensor current while measuring(uA), rough figure, measure real deployments
+pub const SENSOR_ACTIVE_UA: u32 = 1000;
+/// sensor current between measurements(uA)
+pub const SENSOR_STANDBY_UA: u32 = 1;
+
+/// ## MCU supply currents of a board
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Mcu {
+    /// running, peripherals in use clocked
+    pub run_ua: u32,
+    /// sleeping in WFI, same clocks
+    pub sleep_ua: u32,
+}
+
+impl Mcu {
+    /// ## average current(uA) when awake `awake_us` of every second
+    pub fn average_ua(&self, awake_us: u32) -> u32 {
+        let awake_us = awake_us.min(1_000_000) as u64;
+        let active = (self.run_ua - self.sleep_ua) as u64 * awake_us / 1_000_000;
+        self.sleep_ua + active as u32
+    }
+}
+
+/// ## average current estimate(uA)
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct Estimate {
+    pub sensor_ua: u32,
+    pub mcu_ua: u32,
+}
+
+impl Estimate {
+    /// ## estimate for measuring `axes` at `rate`(mHz)
+    ///
+    /// the MCU is awake `wake_us` per sample plus `idle_awake_us` of every second
+    pub fn new(mcu: Mcu, cc: CycleCount, axes: Axes, rate: u32, wake_us: u32, idle_awake_us: u32) -> Self {
+        Estimate {
+            sensor_ua: sensor_ua(cc, axes, rate),
+            mcu_ua: mcu.average_ua(per_second(wake_us, rate).saturating_add(idle_awake_us)),
+        }
+    }
+
+    pub fn total_ua(&self) -> u32 {
+        self.sensor_ua + self.mcu_ua
+    }
+}
+
+/// ## average sensor current(uA) measuring `axes` at `rate`(mHz)
+///
+/// measurements longer than the period run back to back
+pub fn sensor_ua(cc: CycleCount, axes: Axes, rate: u32) -> u32 {
+    let busy_us = per_second(cc.measure_time_us(axes), rate).min(1_000_000) as u64;
+    SENSOR_STANDBY_UA + ((SENSOR_ACTIVE_UA - SENSOR_STANDBY_UA) as u64 * busy_us / 1_000_000) as u32
+}
+
+/// time(us) spent per second on something taking `us` at `rate`(mHz)
+fn per_second(us: u32, rate: u32) -> u32 {
+    (us as u64 * rate as u64 / 1000).min(u32::MAX as u64) as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MCU: Mcu = Mcu { run_ua: 24_000, sleep_ua: 12_000 };
+
+    #[test]
+    fn mcu_current_by_awake_time() {
+        assert_eq!(MCU.average_ua(0), 12_000);
+        assert_eq!(MCU.average_ua(500_000), 18_000);
+        assert_eq!(MCU.average_ua(2_000_000), 24_000);
+    }
+
+    #[test]
+    fn sensor_current_by_rate() {
+        let cc = CycleCount::default();
+        // 2275us at 10Hz, 2.3% of the time
+        assert_eq!(sensor_ua(cc, Axes::X, 10_000), 1 + 22);
+        assert_eq!(sensor_ua(cc, Axes::X, 0), SENSOR_STANDBY_UA);
+        // back to back above ~440Hz
+        assert_eq!(sensor_ua(cc, Axes::X, 1_000_000), SENSOR_ACTIVE_UA);
+        assert!(sensor_ua(cc, Axes::XYZ, 10_000) > 3 * 22);
+    }
+
+    #[test]
+    fn estimate_adds_sample_and_idle_wakes() {
+        // 100us per sample at 100Hz plus 200us per second
+        let estimate = Estimate::new(MCU, CycleCount::default(), Axes::X, 100_000, 100, 200);
+        assert_eq!(estimate.mcu_ua, 12_000 + 12_000 * 10_200 / 1_000_000);
+        assert_eq!(estimate.total_ua(), estimate.sensor_ua + estimate.mcu_ua);
+    }
+}
@@ -0,0 +1,1442 @@
+//! framed binary protocol between firmware and host
+//!
+//! every request and response is one frame:
+//!
+//! | sync | version | command | status | length | payload | crc |
+//! | - | - | - | - | - | - | - |
+//! | 0xA5 0x5A | 1 byte | 1 byte | 1 byte | u16 (be) | length bytes | u16 (be) |
+//!
+//! crc: CRC-16/CCITT-FALSE over version..payload (sync excluded)
+//!
+//! requests carry status 0, responses echo the command id of the request
+//! and report the result in status. Multi-byte values are big endian.
+//!
+//! `Command` and `Response` are the typed form of requests and replies,
+//! shared by firmware and host tools
+
+use crate::{Axes, Config, CycleCount, UpdateRate, DRDM};
+use crate::trigger::{Edge, OutputConfig, OutputMode, TriggerConfig};
+use crate::health::Fault;
+use crate::power::Estimate;
+use crate::gradiometer::{self, Gradients, MAX_SENSORS};
+use crate::filter::{Coefficients, FilterConfig};
+
+pub const SYNC: [u8; 2] = [0xA5, 0x5A];
+pub const VERSION: u8 = 1;
+
+/// sync(2) + version(1) + command(1) + status(1) + length(2)
+pub const HEADER_LEN: usize = 7;
+pub const CRC_LEN: usize = 2;
+pub const MAX_PAYLOAD: usize = 248;
+pub const MAX_FRAME: usize = HEADER_LEN + MAX_PAYLOAD + CRC_LEN;
+
+/// command ids
+pub mod command {
+    /// device info(28), see `INFO_LEN`
+    pub const GET_INFO: u8 = 0x01;
+    /// read active config, return config(8)
+    pub const GET_CONFIG: u8 = 0x02;
+    /// write config(8), return applied config(8)
+    pub const SET_CONFIG: u8 = 0x03;
+    /// read measured axes, return axes bitmask(1)
+    pub const GET_AXES: u8 = 0x04;
+    /// write axes bitmask(1), return axes bitmask(1)
+    pub const SET_AXES: u8 = 0x05;
+    /// pop at most max(1) samples, return count(1) + count * sample(20)
+    pub const READ_SAMPLES: u8 = 0x06;
+    /// return overflow flag(1): 0 if not overflow
+    pub const GET_OVERFLOW: u8 = 0x07;
+    pub const CLEAR_OVERFLOW: u8 = 0x08;
+    pub const CLEAR_BUFFER: u8 = 0x09;
+    /// start pushing STREAM_DATA frames, sequence restarts at 0
+    pub const START_STREAM: u8 = 0x0A;
+    pub const STOP_STREAM: u8 = 0x0B;
+    /// return trigger config(5) + armed(1)
+    pub const GET_TRIGGER: u8 = 0x0C;
+    /// write trigger config(5), return trigger config(5) + armed(1)
+    pub const SET_TRIGGER: u8 = 0x0D;
+    pub const ARM_TRIGGER: u8 = 0x0E;
+    pub const DISARM_TRIGGER: u8 = 0x0F;
+    /// start internal timer trigger at rate(u32, mHz), return actual rate(u32, mHz)
+    pub const START_TIMER: u8 = 0x10;
+    pub const STOP_TIMER: u8 = 0x11;
+    /// return timer rate(u32, mHz), 0 if stopped
+    pub const GET_TIMER: u8 = 0x12;
+    /// return output config(5)
+    pub const GET_OUTPUT: u8 = 0x13;
+    /// write output config(5), return output config(5)
+    pub const SET_OUTPUT: u8 = 0x14;
+    /// one pulse on trigger output, only in software mode
+    pub const PULSE_OUTPUT: u8 = 0x15;
+    /// return statistics(28), see `STATS_LEN`
+    pub const GET_STATS: u8 = 0x16;
+    /// reset all statistics counters
+    pub const CLEAR_STATS: u8 = 0x17;
+    /// return health(10), see `HEALTH_LEN`
+    pub const GET_HEALTH: u8 = 0x18;
+    /// return average current estimate(8), see `POWER_LEN`
+    pub const GET_POWER: u8 = 0x19;
+    /// gradiometer firmware: include gradients in FRAME_DATA, on(1, 0/1)
+    pub const SET_GRADIENTS: u8 = 0x1A;
+    /// read count(1) registers from address(1), return count register values
+    pub const READ_REGISTERS: u8 = 0x1B;
+    /// return filter config(21), see `FILTER_LEN`
+    pub const GET_FILTER: u8 = 0x1C;
+    /// write filter config(21), return filter config(21); the filter starts over
+    pub const SET_FILTER: u8 = 0x1D;
+    /// unsolicited, device to host:
+    /// sequence(u32) + count(1) + count * sample(20)
+    pub const STREAM_DATA: u8 = 0x40;
+    /// unsolicited, gradiometer firmware: sequence(u32) + frame, see `FRAME_SENSOR_LEN`
+    pub const FRAME_DATA: u8 = 0x41;
+}
+
+/// wire length of `Config`: cc x/y/z(6) + TMRC(1) + DRDM(1)
+pub const CONFIG_LEN: usize = 8;
+/// wire length of `TriggerConfig`: edge(1) + per trigger(1) + decimation(u16) + gate(1)
+pub const TRIGGER_LEN: usize = 5;
+/// wire length of `OutputConfig`: mode(1) + every(u16) + width(u16)
+pub const OUTPUT_LEN: usize = 5;
+/// wire length of `FilterConfig`: kind(1) + parameters(20), unused bytes 0.
+/// kind 0 none, 1 moving average len(1), 2 EMA alpha(u16, Q16),
+/// 3 biquad b0/b1/b2/a1/a2(5 * i32, Q3.28), 4 median len(1),
+/// 5 CIC order(1) + decimation(u16)
+pub const FILTER_LEN: usize = 21;
+/// wire length of `DeviceInfo`: protocol version(1) + firmware version(3, major/minor/patch)
+/// + tick rate(u32, Hz) + revid(1) + buffer size(u16) + uptime(u64, ms) + config(8) + axes(1)
+pub const INFO_LEN: usize = 28;
+/// wire length of `Stats`: samples, dropped, triggers, ignored, usb stalls,
+/// latency min, latency max, each u32
+pub const STATS_LEN: usize = 28;
+/// wire length of `Health`: fault(1) + faults(u32) + recoveries(u32) + watchdog reset(1)
+pub const HEALTH_LEN: usize = 10;
+/// wire length of `Estimate`: sensor(u32, uA) + MCU(u32, uA)
+pub const POWER_LEN: usize = 8;
+/// wire length of one sample: mag x/y/z as i32 + trigger tick(u32) + drdy tick(u32)
+pub const SAMPLE_LEN: usize = 20;
+/// wire length of one sensor in a `Frame`: mag x/y/z as i32 + drdy tick(u32).
+/// frame: sensors(1) + trigger tick(u32) + sensors * sensor(16)
+/// + has gradients(1) [+ (sensors - 1) * gradient x/y/z(3 * i32, nT)]
+pub const FRAME_SENSOR_LEN: usize = 16;
+/// max samples in one READ_SAMPLES response or STREAM_DATA frame
+pub const MAX_SAMPLES: usize = (MAX_PAYLOAD - 5) / SAMPLE_LEN;
+/// max registers in one READ_REGISTERS, covers the whole register map
+pub const MAX_REGISTERS: usize = 64;
+/// registers are addressed by 7 bits
+const REGISTER_END: usize = 0x80;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum StatusCode {
+    Ok = 0x00,
+    UnknownCommand = 0x01,
+    InvalidLength = 0x02,
+    InvalidArgument = 0x03,
+    BadCrc = 0x04,
+    UnsupportedVersion = 0x05,
+    /// requested rate faster than a measurement takes
+    RateTooHigh = 0x06,
+}
+
+impl TryFrom<u8> for StatusCode {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0x00 => Ok(StatusCode::Ok),
+            0x01 => Ok(StatusCode::UnknownCommand),
+            0x02 => Ok(StatusCode::InvalidLength),
+            0x03 => Ok(StatusCode::InvalidArgument),
+            0x04 => Ok(StatusCode::BadCrc),
+            0x05 => Ok(StatusCode::UnsupportedVersion),
+            0x06 => Ok(StatusCode::RateTooHigh),
+            _ => Err(value),
+        }
+    }
+}
+
+/// ## CRC-16/CCITT-FALSE
+///
+/// poly 0x1021, init 0xFFFF, no reflection, no final xor
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0xFFFF;
+    for byte in data {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {(crc << 1) ^ 0x1021} else {crc << 1};
+        }
+    }
+    crc
+}
+
+/// one decoded frame, payload borrowed from decoder
+pub struct Frame<'a> {
+    pub version: u8,
+    pub command: u8,
+    pub status: u8,
+    pub payload: &'a [u8],
+}
+
+impl<'a> Frame<'a> {
+    /// ## parse one complete frame
+    ///
+    /// bytes must start with sync word, trailing bytes are ignored
+    pub fn parse(bytes: &'a [u8]) -> Result<Self, FrameError> {
+        let command = if bytes.len() > 3 {bytes[3]} else {0};
+        let error = |status| FrameError {command, status};
+        if bytes.len() < HEADER_LEN + CRC_LEN || bytes[0..2] != SYNC {
+            return Err(error(StatusCode::InvalidLength));
+        }
+        let payload_len = u16::from_be_bytes([bytes[5], bytes[6]]) as usize;
+        let crc_index = HEADER_LEN + payload_len;
+        if payload_len > MAX_PAYLOAD || bytes.len() < crc_index + CRC_LEN {
+            return Err(error(StatusCode::InvalidLength));
+        }
+        let crc = u16::from_be_bytes([bytes[crc_index], bytes[crc_index + 1]]);
+        if crc != crc16(&bytes[2..crc_index]) {
+            return Err(error(StatusCode::BadCrc));
+        }
+        Ok(Frame {
+            version: bytes[2],
+            command,
+            status: bytes[4],
+            payload: &bytes[HEADER_LEN..crc_index],
+        })
+    }
+}
+
+/// frame rejected by decoder
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct FrameError {
+    pub command: u8,
+    pub status: StatusCode,
+}
+
+/// ## encode frame into out
+///
+/// return frame length, None if payload too long or out too short
+pub fn encode_frame(
+    command: u8, status: u8, payload: &[u8], out: &mut [u8]
+) -> Option<usize> {
+    let len = HEADER_LEN + payload.len() + CRC_LEN;
+    if payload.len() > MAX_PAYLOAD || out.len() < len {
+        return None;
+    }
+    out[0..2].copy_from_slice(&SYNC);
+    out[2] = VERSION;
+    out[3] = command;
+    out[4] = status;
+    out[5..7].copy_from_slice(&(payload.len() as u16).to_be_bytes());
+    out[HEADER_LEN..HEADER_LEN + payload.len()].copy_from_slice(payload);
+    let crc = crc16(&out[2..HEADER_LEN + payload.len()]);
+    out[len - CRC_LEN..len].copy_from_slice(&crc.to_be_bytes());
+    Some(len)
+}
+
+/// ## streaming frame decoder
+///
+/// feed received bytes one by one, bytes before sync word are dropped
+pub struct FrameDecoder {
+    buf: [u8; MAX_FRAME],
+    len: usize,
+}
+
+impl Default for FrameDecoder {
+    fn default() -> Self {
+        Self {buf: [0; MAX_FRAME], len: 0}
+    }
+}
+
+impl FrameDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// drop partially received frame
+    pub fn reset(&mut self) {
+        self.len = 0;
+    }
+
+    /// ## no frame in progress
+    ///
+    /// between frames, bytes are not part of one until a sync byte
+    pub fn is_idle(&self) -> bool {
+        self.len == 0 || (self.len >= HEADER_LEN && self.len == self.frame_len())
+    }
+
+    /// push one byte
+    ///
+    /// return None if frame not complete yet
+    /// return Some(Ok(frame)) for a complete frame
+    /// return Some(Err(error)) for a corrupted frame
+    pub fn push(&mut self, byte: u8) -> Option<Result<Frame<'_>, FrameError>> {
+        // previous frame is finished, start over
+        if self.len >= HEADER_LEN && self.len == self.frame_len() {
+            self.len = 0;
+        }
+        // hunt for sync word
+        if self.len < SYNC.len() {
+            if byte == SYNC[self.len] {
+                self.buf[self.len] = byte;
+                self.len += 1;
+            } else {
+                self.len = if byte == SYNC[0] {1} else {0};
+            }
+            return None;
+        }
+        self.buf[self.len] = byte;
+        self.len += 1;
+        if self.len == HEADER_LEN && self.payload_len() > MAX_PAYLOAD {
+            self.len = 0;
+            return Some(Err(FrameError {
+                command: self.buf[3],
+                status: StatusCode::InvalidLength,
+            }));
+        }
+        if self.len < HEADER_LEN || self.len < self.frame_len() {
+            return None;
+        }
+        Some(Frame::parse(&self.buf[..self.len]))
+    }
+
+    fn payload_len(&self) -> usize {
+        u16::from_be_bytes([self.buf[5], self.buf[6]]) as usize
+    }
+
+    fn frame_len(&self) -> usize {
+        HEADER_LEN + self.payload_len() + CRC_LEN
+    }
+}
+
+// # typed messages
+
+/// device description returned by GET_INFO
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct DeviceInfo {
+    pub protocol_version: u8,
+    /// major, minor, patch
+    pub firmware_version: [u8; 3],
+    /// timestamp ticks per second
+    pub tick_rate: u32,
+    /// sensor REVID register
+    pub revid: u8,
+    /// samples the firmware buffer holds
+    pub buffer_size: u16,
+    /// time since power-up(ms)
+    pub uptime_ms: u64,
+    /// active sensor config
+    pub config: Config,
+    pub axes: Axes,
+}
+
+impl DeviceInfo {
+    /// wire form, also used outside frames (vendor control request)
+    pub fn to_bytes(&self) -> [u8; INFO_LEN] {
+        let mut bytes = [0u8; INFO_LEN];
+        write_info(self, &mut bytes);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, StatusCode> {
+        read_info(bytes)
+    }
+}
+
+/// ## wire form of `Config`, also used outside frames (host recordings)
+pub fn config_to_bytes(config: &Config) -> [u8; CONFIG_LEN] {
+    let mut bytes = [0u8; CONFIG_LEN];
+    write_config(config, &mut bytes);
+    bytes
+}
+
+pub fn config_from_bytes(bytes: &[u8]) -> Result<Config, StatusCode> {
+    read_config(bytes)
+}
+
+/// sensor and firmware health returned by GET_HEALTH
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Health {
+    /// result of the last periodic check
+    pub fault: Fault,
+    /// times the sensor became faulty
+    pub faults: u32,
+    /// times it worked again after re-initialization
+    pub recoveries: u32,
+    /// last reset was caused by the watchdog
+    pub watchdog_reset: bool,
+}
+
+/// health counters returned by GET_STATS, all wrap around
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Stats {
+    /// samples read from the sensor
+    pub samples: u32,
+    /// samples lost because the buffer was full
+    pub dropped: u32,
+    /// trigger edges and timer expiries received
+    pub triggers: u32,
+    /// triggers ignored because a measurement was in flight
+    pub ignored: u32,
+    /// times the host stopped taking data, or a reply did not fit the TX queue
+    pub usb_stalls: u32,
+    /// shortest trigger to DRDY latency(ticks), u32::MAX if none yet
+    pub latency_min: u32,
+    /// longest trigger to DRDY latency(ticks), 0 if none yet
+    pub latency_max: u32,
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Stats {
+            samples: 0,
+            dropped: 0,
+            triggers: 0,
+            ignored: 0,
+            usb_stalls: 0,
+            latency_min: u32::MAX,
+            latency_max: 0,
+        }
+    }
+}
+
+impl Stats {
+    /// record trigger to DRDY latency of one single measurement
+    pub fn record_latency(&mut self, ticks: u32) {
+        self.latency_min = self.latency_min.min(ticks);
+        self.latency_max = self.latency_max.max(ticks);
+    }
+}
+
+/// one measurement with timestamps
+///
+/// ticks come from a free-running 32 bit counter running at
+/// `DeviceInfo::tick_rate`, they wrap around
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct Sample {
+    /// x/y/z, 0 for axes not measured
+    pub mag: [i32; 3],
+    /// tick when the measurement was triggered
+    pub trigger_tick: u32,
+    /// tick when DRDY fired
+    pub drdy_tick: u32,
+}
+
+impl Sample {
+    /// wire form, also used outside frames (host recordings)
+    pub fn to_bytes(&self) -> [u8; SAMPLE_LEN] {
+        let mut bytes = [0u8; SAMPLE_LEN];
+        write_sample(self, &mut bytes);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, StatusCode> {
+        if bytes.len() != SAMPLE_LEN {
+            return Err(StatusCode::InvalidLength);
+        }
+        Ok(read_sample(bytes))
+    }
+}
+
+/// samples returned by READ_SAMPLES
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Samples {
+    len: usize,
+    data: [Sample; MAX_SAMPLES],
+}
+
+impl Default for Samples {
+    fn default() -> Self {
+        Self {len: 0, data: [Sample::default(); MAX_SAMPLES]}
+    }
+}
+
+impl Samples {
+    /// append one sample
+    ///
+    /// return false if full
+    pub fn push(&mut self, sample: Sample) -> bool {
+        if self.len == MAX_SAMPLES {
+            return false;
+        }
+        self.data[self.len] = sample;
+        self.len += 1;
+        true
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len == MAX_SAMPLES
+    }
+
+    pub fn as_slice(&self) -> &[Sample] {
+        &self.data[..self.len]
+    }
+}
+
+/// register values returned by READ_REGISTERS
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Registers {
+    len: usize,
+    data: [u8; MAX_REGISTERS],
+}
+
+impl Registers {
+    /// ## `len` registers, all 0
+    ///
+    /// at most MAX_REGISTERS
+    pub fn new(len: usize) -> Self {
+        Self {len: len.min(MAX_REGISTERS), data: [0; MAX_REGISTERS]}
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        &self.data[..self.len]
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        &mut self.data[..self.len]
+    }
+}
+
+/// request sent by host
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Command {
+    GetInfo,
+    GetConfig,
+    SetConfig(Config),
+    GetAxes,
+    SetAxes(Axes),
+    /// pop at most max samples
+    ReadSamples { max: u8 },
+    GetOverflow,
+    ClearOverflow,
+    ClearBuffer,
+    StartStream,
+    StopStream,
+    GetTrigger,
+    SetTrigger(TriggerConfig),
+    ArmTrigger,
+    DisarmTrigger,
+    /// rate in mHz
+    StartTimer { rate: u32 },
+    StopTimer,
+    GetTimer,
+    GetOutput,
+    SetOutput(OutputConfig),
+    PulseOutput,
+    GetStats,
+    ClearStats,
+    GetHealth,
+    GetPower,
+    SetGradients(bool),
+    /// count registers from address on, reading the measurement registers clears DRDY
+    ReadRegisters { address: u8, count: u8 },
+    GetFilter,
+    SetFilter(FilterConfig),
+}
+
+impl Command {
+    pub fn id(&self) -> u8 {
+        match self {
+            Command::GetInfo => command::GET_INFO,
+            Command::GetConfig => command::GET_CONFIG,
+            Command::SetConfig(_) => command::SET_CONFIG,
+            Command::GetAxes => command::GET_AXES,
+            Command::SetAxes(_) => command::SET_AXES,
+            Command::ReadSamples { .. } => command::READ_SAMPLES,
+            Command::GetOverflow => command::GET_OVERFLOW,
+            Command::ClearOverflow => command::CLEAR_OVERFLOW,
+            Command::ClearBuffer => command::CLEAR_BUFFER,
+            Command::StartStream => command::START_STREAM,
+            Command::StopStream => command::STOP_STREAM,
+            Command::GetTrigger => command::GET_TRIGGER,
+            Command::SetTrigger(_) => command::SET_TRIGGER,
+            Command::ArmTrigger => command::ARM_TRIGGER,
+            Command::DisarmTrigger => command::DISARM_TRIGGER,
+            Command::StartTimer { .. } => command::START_TIMER,
+            Command::StopTimer => command::STOP_TIMER,
+            Command::GetTimer => command::GET_TIMER,
+            Command::GetOutput => command::GET_OUTPUT,
+            Command::SetOutput(_) => command::SET_OUTPUT,
+            Command::PulseOutput => command::PULSE_OUTPUT,
+            Command::GetStats => command::GET_STATS,
+            Command::ClearStats => command::CLEAR_STATS,
+            Command::GetHealth => command::GET_HEALTH,
+            Command::GetPower => command::GET_POWER,
+            Command::SetGradients(_) => command::SET_GRADIENTS,
+            Command::ReadRegisters { .. } => command::READ_REGISTERS,
+            Command::GetFilter => command::GET_FILTER,
+            Command::SetFilter(_) => command::SET_FILTER,
+        }
+    }
+
+    /// ## encode request frame into out
+    ///
+    /// return frame length, None if out too short
+    pub fn encode(&self, out: &mut [u8]) -> Option<usize> {
+        let mut payload = [0u8; FILTER_LEN];
+        let len = match self {
+            Command::SetConfig(config) => write_config(config, &mut payload),
+            Command::SetAxes(axes) => {payload[0] = (*axes).into(); 1},
+            Command::SetTrigger(trigger) => write_trigger(trigger, &mut payload),
+            Command::StartTimer { rate } => {payload[0..4].copy_from_slice(&rate.to_be_bytes()); 4},
+            Command::SetOutput(output) => write_output(output, &mut payload),
+            Command::SetGradients(on) => {payload[0] = *on as u8; 1},
+            Command::ReadSamples { max } => {payload[0] = *max; 1},
+            Command::ReadRegisters { address, count } => {payload[0] = *address; payload[1] = *count; 2},
+            Command::SetFilter(filter) => write_filter(filter, &mut payload),
+            _ => 0,
+        };
+        encode_frame(self.id(), StatusCode::Ok as u8, &payload[..len], out)
+    }
+
+    /// ## decode request frame
+    ///
+    /// return status to be reported if frame is not a valid request
+    pub fn decode(frame: &Frame) -> Result<Self, StatusCode> {
+        if frame.version != VERSION {
+            return Err(StatusCode::UnsupportedVersion);
+        }
+        let payload = frame.payload;
+        let expect_len = |len: usize| {
+            if payload.len() == len {Ok(())} else {Err(StatusCode::InvalidLength)}
+        };
+        match frame.command {
+            command::GET_INFO => expect_len(0).map(|_| Command::GetInfo),
+            command::GET_CONFIG => expect_len(0).map(|_| Command::GetConfig),
+            command::SET_CONFIG => Ok(Command::SetConfig(read_config(payload)?)),
+            command::GET_AXES => expect_len(0).map(|_| Command::GetAxes),
+            command::SET_AXES => {
+                expect_len(1)?;
+                Ok(Command::SetAxes(read_axes(payload[0])?))
+            },
+            command::READ_SAMPLES => {
+                expect_len(1)?;
+                Ok(Command::ReadSamples { max: payload[0] })
+            },
+            command::GET_OVERFLOW => expect_len(0).map(|_| Command::GetOverflow),
+            command::CLEAR_OVERFLOW => expect_len(0).map(|_| Command::ClearOverflow),
+            command::CLEAR_BUFFER => expect_len(0).map(|_| Command::ClearBuffer),
+            command::START_STREAM => expect_len(0).map(|_| Command::StartStream),
+            command::STOP_STREAM => expect_len(0).map(|_| Command::StopStream),
+            command::GET_TRIGGER => expect_len(0).map(|_| Command::GetTrigger),
+            command::SET_TRIGGER => Ok(Command::SetTrigger(read_trigger(payload)?)),
+            command::ARM_TRIGGER => expect_len(0).map(|_| Command::ArmTrigger),
+            command::DISARM_TRIGGER => expect_len(0).map(|_| Command::DisarmTrigger),
+            command::START_TIMER => {
+                expect_len(4)?;
+                match read_u32(payload) {
+                    0 => Err(StatusCode::InvalidArgument),
+                    rate => Ok(Command::StartTimer { rate }),
+                }
+            },
+            command::STOP_TIMER => expect_len(0).map(|_| Command::StopTimer),
+            command::GET_TIMER => expect_len(0).map(|_| Command::GetTimer),
+            command::GET_OUTPUT => expect_len(0).map(|_| Command::GetOutput),
+            command::SET_OUTPUT => Ok(Command::SetOutput(read_output(payload)?)),
+            command::PULSE_OUTPUT => expect_len(0).map(|_| Command::PulseOutput),
+            command::GET_STATS => expect_len(0).map(|_| Command::GetStats),
+            command::CLEAR_STATS => expect_len(0).map(|_| Command::ClearStats),
+            command::GET_HEALTH => expect_len(0).map(|_| Command::GetHealth),
+            command::GET_POWER => expect_len(0).map(|_| Command::GetPower),
+            command::SET_GRADIENTS => {
+                expect_len(1)?;
+                Ok(Command::SetGradients(payload[0] != 0))
+            },
+            command::READ_REGISTERS => {
+                expect_len(2)?;
+                let (address, count) = (payload[0], payload[1]);
+                let end = address as usize + count as usize;
+                if count == 0 || count as usize > MAX_REGISTERS || end > REGISTER_END {
+                    return Err(StatusCode::InvalidArgument);
+                }
+                Ok(Command::ReadRegisters { address, count })
+            },
+            command::GET_FILTER => expect_len(0).map(|_| Command::GetFilter),
+            command::SET_FILTER => Ok(Command::SetFilter(read_filter(payload)?)),
+            _ => Err(StatusCode::UnknownCommand),
+        }
+    }
+}
+
+/// reply sent by firmware
+///
+/// which variant answers which command:
+/// - GET_INFO: Info
+/// - GET_CONFIG, SET_CONFIG: Config
+/// - GET_AXES, SET_AXES: Axes
+/// - READ_SAMPLES: Samples
+/// - GET_OVERFLOW: Overflow
+/// - GET_TRIGGER, SET_TRIGGER: Trigger
+/// - START_TIMER, GET_TIMER: Timer
+/// - GET_OUTPUT, SET_OUTPUT: Output
+/// - GET_STATS: Stats
+/// - GET_HEALTH: Health
+/// - GET_POWER: Power
+/// - READ_REGISTERS: Registers
+/// - GET_FILTER, SET_FILTER: Filter
+/// - CLEAR_OVERFLOW, CLEAR_BUFFER, START_STREAM, STOP_STREAM,
+///   ARM_TRIGGER, DISARM_TRIGGER, STOP_TIMER, PULSE_OUTPUT, CLEAR_STATS,
+///   SET_GRADIENTS: Done
+/// - STREAM_DATA (unsolicited): Stream
+/// - FRAME_DATA (unsolicited): Frame
+/// - any rejected request: Error
+// no heap in firmware, samples stay inline
+#[allow(clippy::large_enum_variant)]
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Response {
+    Info(DeviceInfo),
+    Config(Config),
+    Axes(Axes),
+    Samples(Samples),
+    Overflow(bool),
+    Trigger { config: TriggerConfig, armed: bool },
+    /// timer rate in mHz, 0 if stopped
+    Timer { rate: u32 },
+    Output(OutputConfig),
+    Stats(Stats),
+    Health(Health),
+    Power(Estimate),
+    Registers(Registers),
+    Filter(FilterConfig),
+    Done,
+    /// one batch of streamed samples, sequence increases by 1 per frame
+    Stream { sequence: u32, samples: Samples },
+    /// one frame of a gradiometer, sequence increases by 1 per frame
+    Frame { sequence: u32, frame: gradiometer::Frame, gradients: Option<Gradients> },
+    Error(StatusCode),
+}
+
+impl Response {
+    /// ## encode response frame for command into out
+    ///
+    /// return frame length, None if out too short
+    pub fn encode(&self, command: u8, out: &mut [u8]) -> Option<usize> {
+        let mut payload = [0u8; MAX_PAYLOAD];
+        let (status, len) = match self {
+            Response::Info(info) => (StatusCode::Ok, write_info(info, &mut payload)),
+            Response::Config(config) => (StatusCode::Ok, write_config(config, &mut payload)),
+            Response::Axes(axes) => {payload[0] = (*axes).into(); (StatusCode::Ok, 1)},
+            Response::Samples(samples) => (StatusCode::Ok, write_samples(samples, &mut payload)),
+            Response::Overflow(overflow) => {payload[0] = *overflow as u8; (StatusCode::Ok, 1)},
+            Response::Trigger { config, armed } => {
+                let len = write_trigger(config, &mut payload);
+                payload[len] = *armed as u8;
+                (StatusCode::Ok, len + 1)
+            },
+            Response::Timer { rate } => {
+                payload[0..4].copy_from_slice(&rate.to_be_bytes());
+                (StatusCode::Ok, 4)
+            },
+            Response::Output(output) => (StatusCode::Ok, write_output(output, &mut payload)),
+            Response::Stats(stats) => (StatusCode::Ok, write_stats(stats, &mut payload)),
+            Response::Health(health) => {
+                payload[0] = health.fault as u8;
+                payload[1..5].copy_from_slice(&health.faults.to_be_bytes());
+                payload[5..9].copy_from_slice(&health.recoveries.to_be_bytes());
+                payload[9] = health.watchdog_reset as u8;
+                (StatusCode::Ok, HEALTH_LEN)
+            },
+            Response::Power(power) => {
+                payload[0..4].copy_from_slice(&power.sensor_ua.to_be_bytes());
+                payload[4..8].copy_from_slice(&power.mcu_ua.to_be_bytes());
+                (StatusCode::Ok, POWER_LEN)
+            },
+            Response::Registers(registers) => {
+                let values = registers.as_slice();
+                payload[..values.len()].copy_from_slice(values);
+                (StatusCode::Ok, values.len())
+            },
+            Response::Filter(filter) => (StatusCode::Ok, write_filter(filter, &mut payload)),
+            Response::Done => (StatusCode::Ok, 0),
+            Response::Stream { sequence, samples } => {
+                payload[0..4].copy_from_slice(&sequence.to_be_bytes());
+                (StatusCode::Ok, 4 + write_samples(samples, &mut payload[4..]))
+            },
+            Response::Frame { sequence, frame, gradients } => {
+                payload[0..4].copy_from_slice(&sequence.to_be_bytes());
+                (StatusCode::Ok, 4 + write_frame(frame, gradients.as_ref(), &mut payload[4..]))
+            },
+            Response::Error(status) => (*status, 0),
+        };
+        encode_frame(command, status as u8, &payload[..len], out)
+    }
+
+    /// ## decode response frame
+    ///
+    /// error status reported by firmware is decoded as Response::Error,
+    /// Err is only returned for malformed frames
+    pub fn decode(frame: &Frame) -> Result<Self, StatusCode> {
+        if frame.version != VERSION {
+            return Err(StatusCode::UnsupportedVersion);
+        }
+        let status = StatusCode::try_from(frame.status)
+            .map_err(|_| StatusCode::InvalidArgument)?;
+        if status != StatusCode::Ok {
+            return Ok(Response::Error(status));
+        }
+        let payload = frame.payload;
+        let expect_len = |len: usize| {
+            if payload.len() == len {Ok(())} else {Err(StatusCode::InvalidLength)}
+        };
+        match frame.command {
+            command::GET_INFO => Ok(Response::Info(read_info(payload)?)),
+            command::GET_CONFIG | command::SET_CONFIG => {
+                Ok(Response::Config(read_config(payload)?))
+            },
+            command::GET_AXES | command::SET_AXES => {
+                expect_len(1)?;
+                Ok(Response::Axes(read_axes(payload[0])?))
+            },
+            command::READ_SAMPLES => Ok(Response::Samples(read_samples(payload)?)),
+            command::GET_OVERFLOW => {
+                expect_len(1)?;
+                Ok(Response::Overflow(payload[0] != 0))
+            },
+            command::GET_TRIGGER | command::SET_TRIGGER => {
+                expect_len(TRIGGER_LEN + 1)?;
+                Ok(Response::Trigger {
+                    config: read_trigger(&payload[..TRIGGER_LEN])?,
+                    armed: payload[TRIGGER_LEN] != 0,
+                })
+            },
+            command::START_TIMER | command::GET_TIMER => {
+                expect_len(4)?;
+                Ok(Response::Timer { rate: read_u32(payload) })
+            },
+            command::GET_OUTPUT | command::SET_OUTPUT => Ok(Response::Output(read_output(payload)?)),
+            command::GET_STATS => Ok(Response::Stats(read_stats(payload)?)),
+            command::GET_HEALTH => {
+                expect_len(HEALTH_LEN)?;
+                Ok(Response::Health(Health {
+                    fault: Fault::try_from(payload[0]).map_err(|_| StatusCode::InvalidArgument)?,
+                    faults: read_u32(&payload[1..5]),
+                    recoveries: read_u32(&payload[5..9]),
+                    watchdog_reset: payload[9] != 0,
+                }))
+            },
+            command::GET_POWER => {
+                expect_len(POWER_LEN)?;
+                Ok(Response::Power(Estimate {
+                    sensor_ua: read_u32(&payload[0..4]),
+                    mcu_ua: read_u32(&payload[4..8]),
+                }))
+            },
+            command::READ_REGISTERS => {
+                if payload.is_empty() || payload.len() > MAX_REGISTERS {
+                    return Err(StatusCode::InvalidLength);
+                }
+                let mut registers = Registers::new(payload.len());
+                registers.as_mut_slice().copy_from_slice(payload);
+                Ok(Response::Registers(registers))
+            },
+            command::GET_FILTER | command::SET_FILTER => Ok(Response::Filter(read_filter(payload)?)),
+            command::CLEAR_OVERFLOW | command::CLEAR_BUFFER
+            | command::START_STREAM | command::STOP_STREAM
+            | command::ARM_TRIGGER | command::DISARM_TRIGGER
+            | command::STOP_TIMER | command::PULSE_OUTPUT
+            | command::CLEAR_STATS | command::SET_GRADIENTS => {
+                expect_len(0).map(|_| Response::Done)
+            },
+            command::STREAM_DATA => {
+                if payload.len() < 4 {
+                    return Err(StatusCode::InvalidLength);
+                }
+                Ok(Response::Stream {
+                    sequence: read_u32(&payload[0..4]),
+                    samples: read_samples(&payload[4..])?,
+                })
+            },
+            command::FRAME_DATA => {
+                if payload.len() < 4 {
+                    return Err(StatusCode::InvalidLength);
+                }
+                let (frame, gradients) = read_frame(&payload[4..])?;
+                Ok(Response::Frame { sequence: read_u32(&payload[0..4]), frame, gradients })
+            },
+            _ => Err(StatusCode::UnknownCommand),
+        }
+    }
+}
+
+// # payload helpers
+
+fn write_info(info: &DeviceInfo, out: &mut [u8]) -> usize {
+    out[0] = info.protocol_version;
+    out[1..4].copy_from_slice(&info.firmware_version);
+    out[4..8].copy_from_slice(&info.tick_rate.to_be_bytes());
+    out[8] = info.revid;
+    out[9..11].copy_from_slice(&info.buffer_size.to_be_bytes());
+    out[11..19].copy_from_slice(&info.uptime_ms.to_be_bytes());
+    write_config(&info.config, &mut out[19..27]);
+    out[27] = info.axes.into();
+    INFO_LEN
+}
+
+fn read_info(bytes: &[u8]) -> Result<DeviceInfo, StatusCode> {
+    if bytes.len() != INFO_LEN {
+        return Err(StatusCode::InvalidLength);
+    }
+    let mut uptime = [0u8; 8];
+    uptime.copy_from_slice(&bytes[11..19]);
+    Ok(DeviceInfo {
+        protocol_version: bytes[0],
+        firmware_version: [bytes[1], bytes[2], bytes[3]],
+        tick_rate: read_u32(&bytes[4..8]),
+        revid: bytes[8],
+        buffer_size: u16::from_be_bytes([bytes[9], bytes[10]]),
+        uptime_ms: u64::from_be_bytes(uptime),
+        config: read_config(&bytes[19..27])?,
+        axes: read_axes(bytes[27])?,
+    })
+}
+
+fn write_stats(stats: &Stats, out: &mut [u8]) -> usize {
+    let values = [
+        stats.samples, stats.dropped, stats.triggers, stats.ignored,
+        stats.usb_stalls, stats.latency_min, stats.latency_max,
+    ];
+    for (chunk, value) in out[..STATS_LEN].chunks_exact_mut(4).zip(values.iter()) {
+        chunk.copy_from_slice(&value.to_be_bytes());
+    }
+    STATS_LEN
+}
+
+fn read_stats(bytes: &[u8]) -> Result<Stats, StatusCode> {
+    if bytes.len() != STATS_LEN {
+        return Err(StatusCode::InvalidLength);
+    }
+    let value = |index: usize| read_u32(&bytes[index * 4..]);
+    Ok(Stats {
+        samples: value(0),
+        dropped: value(1),
+        triggers: value(2),
+        ignored: value(3),
+        usb_stalls: value(4),
+        latency_min: value(5),
+        latency_max: value(6),
+    })
+}
+
+fn write_config(config: &Config, out: &mut [u8]) -> usize {
+    out[0..2].copy_from_slice(&config.cc.x.to_be_bytes());
+    out[2..4].copy_from_slice(&config.cc.y.to_be_bytes());
+    out[4..6].copy_from_slice(&config.cc.z.to_be_bytes());
+    out[6] = config.rate as u8;
+    out[7] = config.drdm as u8;
+    CONFIG_LEN
+}
+
+/// cycle counts above 0, a cc of 0 measures nothing
+fn read_config(bytes: &[u8]) -> Result<Config, StatusCode> {
+    if bytes.len() != CONFIG_LEN {
+        return Err(StatusCode::InvalidLength);
+    }
+    let cc = CycleCount {
+        x: u16::from_be_bytes([bytes[0], bytes[1]]),
+        y: u16::from_be_bytes([bytes[2], bytes[3]]),
+        z: u16::from_be_bytes([bytes[4], bytes[5]]),
+    };
+    if cc.x == 0 || cc.y == 0 || cc.z == 0 {
+        return Err(StatusCode::InvalidArgument);
+    }
+    Ok(Config {
+        cc,
+        rate: UpdateRate::try_from(bytes[6]).map_err(|_| StatusCode::InvalidArgument)?,
+        drdm: DRDM::from(bytes[7]),
+    })
+}
+
+fn write_trigger(config: &TriggerConfig, out: &mut [u8]) -> usize {
+    out[0] = config.edge as u8;
+    out[1] = config.per_trigger;
+    out[2..4].copy_from_slice(&config.decimation.to_be_bytes());
+    out[4] = config.gate as u8;
+    TRIGGER_LEN
+}
+
+fn read_trigger(bytes: &[u8]) -> Result<TriggerConfig, StatusCode> {
+    if bytes.len() != TRIGGER_LEN {
+        return Err(StatusCode::InvalidLength);
+    }
+    let config = TriggerConfig {
+        edge: Edge::try_from(bytes[0]).map_err(|_| StatusCode::InvalidArgument)?,
+        per_trigger: bytes[1],
+        decimation: u16::from_be_bytes([bytes[2], bytes[3]]),
+        gate: bytes[4] != 0,
+    };
+    if !config.is_valid() {
+        return Err(StatusCode::InvalidArgument);
+    }
+    Ok(config)
+}
+
+fn write_output(config: &OutputConfig, out: &mut [u8]) -> usize {
+    out[0] = config.mode as u8;
+    out[1..3].copy_from_slice(&config.every.to_be_bytes());
+    out[3..5].copy_from_slice(&config.width_us.to_be_bytes());
+    OUTPUT_LEN
+}
+
+fn read_output(bytes: &[u8]) -> Result<OutputConfig, StatusCode> {
+    if bytes.len() != OUTPUT_LEN {
+        return Err(StatusCode::InvalidLength);
+    }
+    let config = OutputConfig {
+        mode: OutputMode::try_from(bytes[0]).map_err(|_| StatusCode::InvalidArgument)?,
+        every: u16::from_be_bytes([bytes[1], bytes[2]]),
+        width_us: u16::from_be_bytes([bytes[3], bytes[4]]),
+    };
+    if !config.is_valid() {
+        return Err(StatusCode::InvalidArgument);
+    }
+    Ok(config)
+}
+
+fn write_filter(config: &FilterConfig, out: &mut [u8]) -> usize {
+    out[..FILTER_LEN].fill(0);
+    match *config {
+        FilterConfig::None => {},
+        FilterConfig::MovingAverage { len } => {out[0] = 1; out[1] = len;},
+        FilterConfig::Ema { alpha } => {out[0] = 2; out[1..3].copy_from_slice(&alpha.to_be_bytes());},
+        FilterConfig::Biquad(coefficients) => {
+            out[0] = 3;
+            let values = [coefficients.b0, coefficients.b1, coefficients.b2, coefficients.a1, coefficients.a2];
+            for (chunk, value) in out[1..FILTER_LEN].chunks_exact_mut(4).zip(values.iter()) {
+                chunk.copy_from_slice(&value.to_be_bytes());
+            }
+        },
+        FilterConfig::Median { len } => {out[0] = 4; out[1] = len;},
+        FilterConfig::Cic { order, decimation } => {
+            out[0] = 5;
+            out[1] = order;
+            out[2..4].copy_from_slice(&decimation.to_be_bytes());
+        },
+    }
+    FILTER_LEN
+}
+
+fn read_filter(bytes: &[u8]) -> Result<FilterConfig, StatusCode> {
+    if bytes.len() != FILTER_LEN {
+        return Err(StatusCode::InvalidLength);
+    }
+    let u16_at = |index: usize| u16::from_be_bytes([bytes[index], bytes[index + 1]]);
+    let i32_at = |index: usize| read_u32(&bytes[1 + index * 4..]) as i32;
+    let config = match bytes[0] {
+        0 => FilterConfig::None,
+        1 => FilterConfig::MovingAverage { len: bytes[1] },
+        2 => FilterConfig::Ema { alpha: u16_at(1) },
+        3 => FilterConfig::Biquad(Coefficients {
+            b0: i32_at(0), b1: i32_at(1), b2: i32_at(2), a1: i32_at(3), a2: i32_at(4),
+        }),
+        4 => FilterConfig::Median { len: bytes[1] },
+        5 => FilterConfig::Cic { order: bytes[1], decimation: u16_at(2) },
+        _ => return Err(StatusCode::InvalidArgument),
+    };
+    if !config.is_valid() {
+        return Err(StatusCode::InvalidArgument);
+    }
+    Ok(config)
+}
+
+/// at least one axis, no unknown bits
+fn read_axes(mask: u8) -> Result<Axes, StatusCode> {
+    if mask == 0 || mask & !0b111 != 0 {
+        return Err(StatusCode::InvalidArgument);
+    }
+    Ok(mask.into())
+}
+
+/// count(1) + count * sample(20)
+fn write_samples(samples: &Samples, out: &mut [u8]) -> usize {
+    out[0] = samples.len as u8;
+    for (chunk, sample) in out[1..].chunks_exact_mut(SAMPLE_LEN).zip(samples.as_slice()) {
+        write_sample(sample, chunk);
+    }
+    1 + samples.len * SAMPLE_LEN
+}
+
+fn read_samples(bytes: &[u8]) -> Result<Samples, StatusCode> {
+    let count = *bytes.first().ok_or(StatusCode::InvalidLength)? as usize;
+    if count > MAX_SAMPLES {
+        return Err(StatusCode::InvalidArgument);
+    }
+    if bytes.len() != 1 + count * SAMPLE_LEN {
+        return Err(StatusCode::InvalidLength);
+    }
+    let mut samples = Samples::default();
+    for chunk in bytes[1..].chunks_exact(SAMPLE_LEN) {
+        samples.push(read_sample(chunk));
+    }
+    Ok(samples)
+}
+
+fn write_sample(sample: &Sample, out: &mut [u8]) {
+    for (chunk, value) in out[0..12].chunks_exact_mut(4).zip(sample.mag.iter()) {
+        chunk.copy_from_slice(&value.to_be_bytes());
+    }
+    out[12..16].copy_from_slice(&sample.trigger_tick.to_be_bytes());
+    out[16..20].copy_from_slice(&sample.drdy_tick.to_be_bytes());
+}
+
+fn read_sample(bytes: &[u8]) -> Sample {
+    let mut mag = [0i32; 3];
+    for (value, chunk) in mag.iter_mut().zip(bytes[0..12].chunks_exact(4)) {
+        *value = read_u32(chunk) as i32;
+    }
+    Sample {
+        mag,
+        trigger_tick: read_u32(&bytes[12..16]),
+        drdy_tick: read_u32(&bytes[16..20]),
+    }
+}
+
+fn write_frame(frame: &gradiometer::Frame, gradients: Option<&Gradients>, out: &mut [u8]) -> usize {
+    let sensors = frame.sensors as usize;
+    out[0] = frame.sensors;
+    out[1..5].copy_from_slice(&frame.trigger_tick.to_be_bytes());
+    let mut len = 5;
+    for (mag, drdy_tick) in frame.mag.iter().zip(frame.drdy_tick.iter()).take(sensors) {
+        len += write_i32s(mag, &mut out[len..]);
+        out[len..len + 4].copy_from_slice(&drdy_tick.to_be_bytes());
+        len += 4;
+    }
+    out[len] = gradients.is_some() as u8;
+    len += 1;
+    for gradient in gradients.iter().flat_map(|gradients| gradients.iter().take(sensors - 1)) {
+        len += write_i32s(gradient, &mut out[len..]);
+    }
+    len
+}
+
+fn read_frame(bytes: &[u8]) -> Result<(gradiometer::Frame, Option<Gradients>), StatusCode> {
+    let sensors = *bytes.first().ok_or(StatusCode::InvalidLength)? as usize;
+    if sensors == 0 || sensors > MAX_SENSORS {
+        return Err(StatusCode::InvalidArgument);
+    }
+    let flag_at = 5 + sensors * FRAME_SENSOR_LEN;
+    let has_gradients = *bytes.get(flag_at).ok_or(StatusCode::InvalidLength)? != 0;
+    let gradients_len = if has_gradients {(sensors - 1) * 12} else {0};
+    if bytes.len() != flag_at + 1 + gradients_len {
+        return Err(StatusCode::InvalidLength);
+    }
+    let mut frame = gradiometer::Frame {
+        sensors: sensors as u8,
+        trigger_tick: read_u32(&bytes[1..5]),
+        ..gradiometer::Frame::default()
+    };
+    for (i, chunk) in bytes[5..flag_at].chunks_exact(FRAME_SENSOR_LEN).enumerate() {
+        frame.mag[i] = read_i32s(&chunk[0..12]);
+        frame.drdy_tick[i] = read_u32(&chunk[12..16]);
+    }
+    let gradients = has_gradients.then(|| {
+        let mut gradients = Gradients::default();
+        for (gradient, chunk) in gradients.iter_mut().zip(bytes[flag_at + 1..].chunks_exact(12)) {
+            *gradient = read_i32s(chunk);
+        }
+        gradients
+    });
+    Ok((frame, gradients))
+}
+
+/// x/y/z as i32, return 12
+fn write_i32s(values: &[i32; 3], out: &mut [u8]) -> usize {
+    for (chunk, value) in out[0..12].chunks_exact_mut(4).zip(values.iter()) {
+        chunk.copy_from_slice(&value.to_be_bytes());
+    }
+    12
+}
+
+fn read_i32s(bytes: &[u8]) -> [i32; 3] {
+    let mut values = [0i32; 3];
+    for (value, chunk) in values.iter_mut().zip(bytes[0..12].chunks_exact(4)) {
+        *value = read_u32(chunk) as i32;
+    }
+    values
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> Config {
+        Config {
+            cc: CycleCount { x: 100, y: 200, z: 400 },
+            rate: UpdateRate::Hz37,
+            drdm: DRDM::Any,
+        }
+    }
+
+    fn command_round_trip(command: Command) {
+        let mut buf = [0u8; MAX_FRAME];
+        let len = command.encode(&mut buf).unwrap();
+        let frame = Frame::parse(&buf[..len]).unwrap();
+        assert_eq!(frame.command, command.id());
+        assert_eq!(Command::decode(&frame), Ok(command));
+    }
+
+    fn response_round_trip(command: u8, response: Response) {
+        let mut buf = [0u8; MAX_FRAME];
+        let len = response.encode(command, &mut buf).unwrap();
+        let frame = Frame::parse(&buf[..len]).unwrap();
+        assert_eq!(frame.command, command);
+        assert_eq!(Response::decode(&frame), Ok(response));
+    }
+
+    #[test]
+    fn crc_check_value() {
+        assert_eq!(crc16(b"123456789"), 0x29B1);
+    }
+
+    #[test]
+    fn commands_round_trip() {
+        command_round_trip(Command::GetInfo);
+        command_round_trip(Command::GetConfig);
+        command_round_trip(Command::SetConfig(config()));
+        command_round_trip(Command::GetAxes);
+        command_round_trip(Command::SetAxes(Axes { x: false, y: true, z: true }));
+        command_round_trip(Command::ReadSamples { max: 7 });
+        command_round_trip(Command::GetOverflow);
+        command_round_trip(Command::ClearOverflow);
+        command_round_trip(Command::ClearBuffer);
+        command_round_trip(Command::StartStream);
+        command_round_trip(Command::StopStream);
+        command_round_trip(Command::GetTrigger);
+        let trigger = TriggerConfig { edge: Edge::Both, per_trigger: 3, decimation: 1000, gate: true };
+        command_round_trip(Command::SetTrigger(trigger));
+        command_round_trip(Command::ArmTrigger);
+        command_round_trip(Command::DisarmTrigger);
+        command_round_trip(Command::StartTimer { rate: 1_234_500 });
+        command_round_trip(Command::StopTimer);
+        command_round_trip(Command::GetTimer);
+        command_round_trip(Command::GetOutput);
+        let output = OutputConfig { mode: OutputMode::Sample, every: 10, width_us: 500 };
+        command_round_trip(Command::SetOutput(output));
+        command_round_trip(Command::PulseOutput);
+        command_round_trip(Command::GetStats);
+        command_round_trip(Command::ClearStats);
+        command_round_trip(Command::GetHealth);
+        command_round_trip(Command::GetPower);
+        command_round_trip(Command::SetGradients(true));
+        command_round_trip(Command::ReadRegisters { address: 0x00, count: MAX_REGISTERS as u8 });
+        command_round_trip(Command::GetFilter);
+        command_round_trip(Command::SetFilter(FilterConfig::Cic { order: 3, decimation: 256 }));
+    }
+
+    #[test]
+    fn responses_round_trip() {
+        let info = DeviceInfo {
+            protocol_version: VERSION,
+            firmware_version: [0, 1, 0],
+            tick_rate: 48_000_000,
+            revid: 0x22,
+            buffer_size: 31,
+            uptime_ms: u64::MAX - 1,
+            config: config(),
+            axes: Axes::X,
+        };
+        response_round_trip(command::GET_INFO, Response::Info(info));
+        response_round_trip(command::SET_CONFIG, Response::Config(config()));
+        response_round_trip(command::GET_AXES, Response::Axes(Axes::XYZ));
+        response_round_trip(command::GET_OVERFLOW, Response::Overflow(true));
+        let trigger = TriggerConfig { edge: Edge::Falling, ..TriggerConfig::default() };
+        response_round_trip(command::GET_TRIGGER, Response::Trigger { config: trigger, armed: false });
+        response_round_trip(command::CLEAR_BUFFER, Response::Done);
+        response_round_trip(command::START_TIMER, Response::Timer { rate: 99_998 });
+        response_round_trip(command::SET_OUTPUT, Response::Output(OutputConfig::default()));
+        response_round_trip(command::GET_STATS, Response::Stats(Stats::default()));
+        let mut stats = Stats { samples: 1, dropped: 2, triggers: 3, ignored: 4, usb_stalls: 5, ..Stats::default() };
+        stats.record_latency(700);
+        stats.record_latency(500);
+        assert_eq!((stats.latency_min, stats.latency_max), (500, 700));
+        response_round_trip(command::GET_STATS, Response::Stats(stats));
+        response_round_trip(command::CLEAR_STATS, Response::Done);
+        let health = Health { fault: Fault::DrdyTimeout, faults: 3, recoveries: 2, watchdog_reset: true };
+        response_round_trip(command::GET_HEALTH, Response::Health(health));
+        let power = Estimate { sensor_ua: 120, mcu_ua: 9_000 };
+        response_round_trip(command::GET_POWER, Response::Power(power));
+        let mut registers = Registers::new(3);
+        registers.as_mut_slice().copy_from_slice(&[0x00, 0xC8, 0x22]);
+        response_round_trip(command::READ_REGISTERS, Response::Registers(registers));
+        let lowpass = Coefficients::lowpass(5.0, 100.0, core::f32::consts::FRAC_1_SQRT_2);
+        for filter in [
+            FilterConfig::None,
+            FilterConfig::MovingAverage { len: 32 },
+            FilterConfig::Ema { alpha: 65535 },
+            FilterConfig::Biquad(lowpass),
+            FilterConfig::Median { len: 5 },
+            FilterConfig::Cic { order: 4, decimation: 10 },
+        ] {
+            response_round_trip(command::SET_FILTER, Response::Filter(filter));
+        }
+        response_round_trip(command::START_TIMER, Response::Error(StatusCode::RateTooHigh));
+        response_round_trip(command::SET_AXES, Response::Error(StatusCode::InvalidArgument));
+
+        let mut samples = Samples::default();
+        response_round_trip(command::READ_SAMPLES, Response::Samples(samples));
+        while samples.push(Sample {
+            mag: [-8_388_608, 8_388_607, samples.as_slice().len() as i32],
+            trigger_tick: u32::MAX,
+            drdy_tick: 1,
+        }) {}
+        response_round_trip(command::READ_SAMPLES, Response::Samples(samples));
+        let stream = Response::Stream { sequence: u32::MAX, samples };
+        response_round_trip(command::STREAM_DATA, stream);
+    }
+
+    #[test]
+    fn gradiometer_frames_round_trip() {
+        let mut frame = gradiometer::Frame { sensors: 3, trigger_tick: 100, ..Default::default() };
+        frame.mag[..3].copy_from_slice(&[[0, 0, 0], [75, 75, 75], [750, 0, -750]]);
+        frame.drdy_tick[..3].copy_from_slice(&[160, 175, 190]);
+        response_round_trip(command::FRAME_DATA, Response::Frame { sequence: 7, frame, gradients: None });
+        let gradients = [[1000, 1000, 1000], [9000, -1000, -11000], [0, 0, 0]];
+        let with_gradients = Response::Frame { sequence: 8, frame, gradients: Some(gradients) };
+        response_round_trip(command::FRAME_DATA, with_gradients);
+    }
+
+    #[test]
+    fn decoder_resyncs_and_splits_frames() {
+        let mut stream = [0u8; 64];
+        stream[..3].copy_from_slice(&[0x00, 0xA5, 0xA5]);
+        let first = Command::SetAxes(Axes::X).encode(&mut stream[3..]).unwrap();
+        let second = Command::GetInfo.encode(&mut stream[3 + first..]).unwrap();
+
+        let mut decoder = FrameDecoder::new();
+        let mut decoded = [None; 2];
+        let mut count = 0;
+        for byte in &stream[..3 + first + second] {
+            if let Some(result) = decoder.push(*byte) {
+                decoded[count] = Some(Command::decode(&result.unwrap()));
+                count += 1;
+            }
+        }
+        assert_eq!(decoded, [Some(Ok(Command::SetAxes(Axes::X))), Some(Ok(Command::GetInfo))]);
+    }
+
+    #[test]
+    fn decoder_idle_between_frames() {
+        let mut buf = [0u8; MAX_FRAME];
+        let len = Command::SetAxes(Axes::X).encode(&mut buf).unwrap();
+        let mut decoder = FrameDecoder::new();
+        assert!(decoder.is_idle());
+        decoder.push(b'c');
+        assert!(decoder.is_idle());
+        for byte in &buf[..len - 1] {
+            decoder.push(*byte);
+            assert!(!decoder.is_idle());
+        }
+        assert!(decoder.push(buf[len - 1]).is_some());
+        assert!(decoder.is_idle());
+    }
+
+    #[test]
+    fn decoder_rejects_bad_crc_then_recovers() {
+        let mut buf = [0u8; MAX_FRAME];
+        let len = Command::GetConfig.encode(&mut buf).unwrap();
+        let mut corrupted = buf;
+        corrupted[len - 1] ^= 0xFF;
+
+        let mut decoder = FrameDecoder::new();
+        let mut feed = |bytes: &[u8]| {
+            bytes.iter()
+                .filter_map(|byte| decoder.push(*byte).map(|r| r.map(|frame| frame.command)))
+                .last()
+        };
+        let error = FrameError { command: command::GET_CONFIG, status: StatusCode::BadCrc };
+        assert_eq!(feed(&corrupted[..len]), Some(Err(error)));
+        assert_eq!(feed(&buf[..len]), Some(Ok(command::GET_CONFIG)));
+    }
+
+    #[test]
+    fn decoder_rejects_oversized_length() {
+        let mut decoder = FrameDecoder::new();
+        let header = [0xA5, 0x5A, VERSION, command::GET_INFO, 0, 0xFF, 0xFF];
+        let results: [_; 7] = core::array::from_fn(|i| decoder.push(header[i]).map(|r| r.err()));
+        let error = FrameError { command: command::GET_INFO, status: StatusCode::InvalidLength };
+        assert_eq!(results[6], Some(Some(error)));
+        assert!(results[..6].iter().all(Option::is_none));
+    }
+
+    #[test]
+    fn parse_rejects_truncated_frame() {
+        let mut buf = [0u8; MAX_FRAME];
+        let len = Command::SetConfig(config()).encode(&mut buf).unwrap();
+        for cut in 0..len {
+            let status = Frame::parse(&buf[..cut]).err().map(|error| error.status);
+            assert_eq!(status, Some(StatusCode::InvalidLength));
+        }
+    }
+
+    #[test]
+    fn malformed_commands() {
+        let mut buf = [0u8; MAX_FRAME];
+        let mut decode = |command: u8, payload: &[u8]| {
+            let len = encode_frame(command, 0, payload, &mut buf).unwrap();
+            Command::decode(&Frame::parse(&buf[..len]).unwrap())
+        };
+        assert_eq!(decode(0x7F, &[]), Err(StatusCode::UnknownCommand));
+        assert_eq!(decode(command::GET_INFO, &[0]), Err(StatusCode::InvalidLength));
+        assert_eq!(decode(command::SET_CONFIG, &[0; 7]), Err(StatusCode::InvalidLength));
+        let bad_rate = [0, 200, 0, 200, 0, 200, 0x42, 0];
+        assert_eq!(decode(command::SET_CONFIG, &bad_rate), Err(StatusCode::InvalidArgument));
+        let zero_cc = [0, 200, 0, 0, 0, 200, 0x96, 0];
+        assert_eq!(decode(command::SET_CONFIG, &zero_cc), Err(StatusCode::InvalidArgument));
+        assert_eq!(decode(command::SET_AXES, &[0]), Err(StatusCode::InvalidArgument));
+        assert_eq!(decode(command::SET_AXES, &[0b1001]), Err(StatusCode::InvalidArgument));
+        assert_eq!(decode(command::READ_SAMPLES, &[]), Err(StatusCode::InvalidLength));
+        assert_eq!(decode(command::SET_TRIGGER, &[3, 1, 0, 1, 0]), Err(StatusCode::InvalidArgument));
+        assert_eq!(decode(command::SET_TRIGGER, &[0, 0, 0, 1, 0]), Err(StatusCode::InvalidArgument));
+        assert_eq!(decode(command::SET_TRIGGER, &[0, 1, 0, 0, 0]), Err(StatusCode::InvalidArgument));
+        assert_eq!(decode(command::START_TIMER, &[0; 4]), Err(StatusCode::InvalidArgument));
+        assert_eq!(decode(command::SET_OUTPUT, &[3, 0, 1, 0, 1]), Err(StatusCode::InvalidArgument));
+        assert_eq!(decode(command::SET_OUTPUT, &[1, 0, 1, 0, 0]), Err(StatusCode::InvalidArgument));
+        assert_eq!(decode(command::READ_REGISTERS, &[0x30, 0]), Err(StatusCode::InvalidArgument));
+        assert_eq!(decode(command::READ_REGISTERS, &[0x70, 0x11]), Err(StatusCode::InvalidArgument));
+        assert_eq!(decode(command::READ_REGISTERS, &[0x30]), Err(StatusCode::InvalidLength));
+        let mut filter = [0u8; FILTER_LEN];
+        filter[0] = 6;
+        assert_eq!(decode(command::SET_FILTER, &filter), Err(StatusCode::InvalidArgument));
+        filter[..2].copy_from_slice(&[1, 33]);
+        assert_eq!(decode(command::SET_FILTER, &filter), Err(StatusCode::InvalidArgument));
+        filter[..4].copy_from_slice(&[5, 1, 1, 1]);
+        assert_eq!(decode(command::SET_FILTER, &filter), Err(StatusCode::InvalidArgument));
+        assert_eq!(decode(command::SET_FILTER, &filter[..4]), Err(StatusCode::InvalidLength));
+
+        let len = encode_frame(command::GET_INFO, 0, &[], &mut buf).unwrap();
+        buf[2] = VERSION + 1;
+        let crc = crc16(&buf[2..len - CRC_LEN]).to_be_bytes();
+        buf[len - CRC_LEN..len].copy_from_slice(&crc);
+        let frame = Frame::parse(&buf[..len]).unwrap();
+        assert_eq!(Command::decode(&frame), Err(StatusCode::UnsupportedVersion));
+    }
+
+    #[test]
+    fn malformed_responses() {
+        let mut buf = [0u8; MAX_FRAME];
+        let mut decode = |command: u8, status: u8, payload: &[u8]| {
+            let len = encode_frame(command, status, payload, &mut buf).unwrap();
+            Response::decode(&Frame::parse(&buf[..len]).unwrap())
+        };
+        assert_eq!(decode(command::GET_INFO, 0xEE, &[]), Err(StatusCode::InvalidArgument));
+        assert_eq!(decode(command::GET_INFO, 0, &[1, 0, 1, 0]), Err(StatusCode::InvalidLength));
+        assert_eq!(decode(command::READ_SAMPLES, 0, &[]), Err(StatusCode::InvalidLength));
+        assert_eq!(decode(command::READ_SAMPLES, 0, &[1; 13]), Err(StatusCode::InvalidLength));
+        let too_many = [MAX_SAMPLES as u8 + 1];
+        assert_eq!(decode(command::READ_SAMPLES, 0, &too_many), Err(StatusCode::InvalidArgument));
+        assert_eq!(decode(command::GET_AXES, 0, &[0x80]), Err(StatusCode::InvalidArgument));
+        assert_eq!(decode(command::STREAM_DATA, 0, &[0; 4]), Err(StatusCode::InvalidLength));
+        assert_eq!(decode(command::GET_STATS, 0, &[0; STATS_LEN - 4]), Err(StatusCode::InvalidLength));
+        let unknown_fault = [9, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        assert_eq!(decode(command::GET_HEALTH, 0, &unknown_fault), Err(StatusCode::InvalidArgument));
+    }
+
+    #[test]
+    fn encode_rejects_short_output() {
+        let mut buf = [0u8; HEADER_LEN + CRC_LEN + CONFIG_LEN - 1];
+        assert_eq!(Command::SetConfig(config()).encode(&mut buf), None);
+        let payload = [0u8; MAX_PAYLOAD + 1];
+        assert_eq!(encode_frame(command::GET_INFO, 0, &payload, &mut [0; 2 * MAX_FRAME]), None);
+    }
+}
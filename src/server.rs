@@ -0,0 +1,850 @@
+//! firmware core shared by the app and the host emulator
+//!
+//! hardware independent: request handling, sampling bookkeeping (filter,
+//! buffer, overflow, stats, health, trigger and output state) and the TX queue.
+//! The firmware brings the sensor (`Sensor`) and its timers, pins and tick
+//! counter (`Hardware`), and calls in from its interrupts:
+//! DRDY `on_drdy` and `store_sample`, trigger input `on_edge`, timer
+//! `on_timer`, SysTick `on_systick`, USB `request`, `push_stream` and
+//! `TxQueue::flush`
+
+use core::fmt;
+
+use embedded_hal::digital::v2::OutputPin;
+
+use crate::filter::{Filter, Filter3};
+use crate::health::{Check, Monitor};
+use crate::mincircularbuffer::MinCircularBuffer;
+use crate::power::{Estimate, Mcu};
+use crate::protocol::{
+    self, Command, DeviceInfo, Frame, FrameError, Health, Registers, Response, Sample, Samples, Stats, StatusCode,
+};
+use crate::trigger::{self, Action, Level, Output, Trigger};
+use crate::{Axes, Config, UpdateRate, RM3100};
+
+/// REVID of the RM3100
+pub const REVID: u8 = 0x22;
+/// sample buffer slots, one is kept free
+pub const BUFFER_SIZE: usize = 32;
+/// ~3.5 minutes at `LOW_POWER_RATE`
+pub const LOW_POWER_BUFFER_SIZE: usize = 256;
+/// continuous update rate of low power deployments
+pub const LOW_POWER_RATE: UpdateRate = UpdateRate::Hz1_2;
+/// SysTick period(ms) for uptime
+pub const UPTIME_STEP_MS: u32 = 100;
+/// fewer wake-ups in low power deployments
+pub const LOW_POWER_UPTIME_STEP_MS: u32 = 250;
+/// margin for DRDY interrupt and SPI read after a measurement
+pub const READ_OVERHEAD_US: u32 = 100;
+/// rough CPU time(us) of one SysTick
+pub const SYSTICK_AWAKE_US: u32 = 20;
+pub const HEALTH_PERIOD_MS: u32 = 1000;
+/// TX queue size, a few frames
+pub const TX_SIZE: usize = 4 * protocol::MAX_FRAME;
+
+/// ## major, minor, patch reported by GET_INFO
+pub fn firmware_version() -> [u8; 3] {
+    [
+        env!("CARGO_PKG_VERSION_MAJOR").parse().unwrap_or(0),
+        env!("CARGO_PKG_VERSION_MINOR").parse().unwrap_or(0),
+        env!("CARGO_PKG_VERSION_PATCH").parse().unwrap_or(0),
+    ]
+}
+
+/// ## what to do with samples to stream while the host is not reading
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum TxPolicy {
+    /// leave them in the sample buffer, where overflow drops new ones
+    Retain,
+    /// drop them (counted as dropped), the stream resumes with fresh samples
+    Discard,
+}
+
+/// ## push-based streaming state, owned by USB handling
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct Stream {
+    pub active: bool,
+    /// sequence of next STREAM_DATA frame
+    pub sequence: u32,
+}
+
+/// ## outgoing bytes, only whole frames are queued
+pub struct TxQueue {
+    data: [u8; TX_SIZE],
+    start: usize,
+    len: usize,
+    /// host did not take data at last flush
+    stalled: bool,
+}
+
+impl Default for TxQueue {
+    fn default() -> Self {
+        TxQueue {data: [0; TX_SIZE], start: 0, len: 0, stalled: false}
+    }
+}
+
+impl TxQueue {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn free(&self) -> usize {
+        TX_SIZE - self.len
+    }
+
+    pub fn clear(&mut self) {
+        self.start = 0;
+        self.len = 0;
+        self.stalled = false;
+    }
+
+    /// queue frame, false if it does not fit
+    pub fn push(&mut self, frame: &[u8]) -> bool {
+        if frame.len() > self.free() {
+            return false;
+        }
+        for byte in frame {
+            self.data[(self.start + self.len) % TX_SIZE] = *byte;
+            self.len += 1;
+        }
+        true
+    }
+
+    /// ## hand queued bytes to `write` until it takes no more
+    ///
+    /// `write` returns the bytes it took, 0 if it would block, None if the
+    /// link is down (the next disconnect check drops the queue).
+    /// return true if a stall began
+    pub fn flush(&mut self, mut write: impl FnMut(&[u8]) -> Option<usize>) -> bool {
+        while self.len > 0 {
+            let end = TX_SIZE.min(self.start + self.len);
+            match write(&self.data[self.start..end]) {
+                Some(0) => {
+                    let began = !self.stalled;
+                    self.stalled = true;
+                    return began;
+                },
+                Some(len) => {
+                    self.start = (self.start + len) % TX_SIZE;
+                    self.len -= len;
+                },
+                None => return false,
+            }
+        }
+        self.stalled = false;
+        false
+    }
+}
+
+/// console text, each piece is queued whole or dropped
+impl fmt::Write for TxQueue {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if self.push(s.as_bytes()) {Ok(())} else {Err(fmt::Error)}
+    }
+}
+
+/// ## sensor access of the server, implemented by the driver
+pub trait Sensor {
+    fn config(&mut self) -> Config;
+    /// write config, return the config now in use
+    fn configure(&mut self, config: Config) -> Config;
+    fn start_single(&mut self, axes: Axes);
+    fn start_continuous(&mut self, axes: Axes);
+    fn stop_continuous(&mut self);
+    /// a continuous measurement runs
+    fn is_measuring_continuously(&self) -> bool;
+    fn read_register(&mut self, address: u8) -> u8;
+    /// REVID is `revid` and the registers hold the written config
+    fn check(&mut self, revid: u8) -> (bool, bool);
+    /// write the last config again
+    fn recover(&mut self);
+}
+
+impl<Spi, SpiError, CsPin, PinError> Sensor for RM3100<Spi, CsPin>
+where
+    Spi: embedded_hal::blocking::spi::Transfer<u8, Error = SpiError>
+        + embedded_hal::blocking::spi::Write<u8, Error = SpiError>,
+    CsPin: OutputPin<Error = PinError>,
+{
+    fn config(&mut self) -> Config {self.get_config()}
+
+    fn configure(&mut self, config: Config) -> Config {self.set_config(config).get_config()}
+
+    fn start_single(&mut self, axes: Axes) {
+        self.start_single_measure(axes.x, axes.y, axes.z);
+    }
+
+    fn start_continuous(&mut self, axes: Axes) {
+        self.start_continuous_measure(axes.x, axes.y, axes.z);
+    }
+
+    fn stop_continuous(&mut self) {
+        self.stop_continuous_measure();
+    }
+
+    fn is_measuring_continuously(&self) -> bool {self.is_continuous()}
+
+    fn read_register(&mut self, address: u8) -> u8 {self.read_byte(address)}
+
+    fn check(&mut self, revid: u8) -> (bool, bool) {
+        (self.check_connect(revid), self.check_config())
+    }
+
+    fn recover(&mut self) {
+        self.reinit();
+    }
+}
+
+/// ## timers, trigger pins and tick counter of the firmware
+pub trait Hardware {
+    /// tick counter, `Settings::tick_rate`
+    fn now(&mut self) -> u32;
+    /// drive the trigger output pin, a pulse lasts `width_us`
+    fn drive(&mut self, level: Level, width_us: u16);
+    /// ## start the internal trigger at `rate`(mHz)
+    ///
+    /// return the actual rate(mHz), None if it does not fit the timer
+    fn start_timer(&mut self, rate: u32) -> Option<u32>;
+    fn stop_timer(&mut self);
+    /// rate(mHz) of the internal trigger, 0 if stopped
+    fn timer_rate(&self) -> u32;
+    /// edges the trigger input interrupt listens to
+    fn set_input_edge(&mut self, edge: trigger::Edge);
+}
+
+/// ## fixed parameters of a firmware build
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Settings {
+    /// rate(Hz) of the tick counter stamping samples
+    pub tick_rate: u32,
+    /// SysTick period(ms)
+    pub uptime_step_ms: u32,
+    /// supply currents for GET_POWER
+    pub mcu: Mcu,
+}
+
+/// ## firmware state
+///
+/// `N` sample buffer slots
+pub struct Server<const N: usize> {
+    settings: Settings,
+    trigger: Trigger,
+    output: Output,
+    axes: Axes,
+    /// samples pass it before the buffer
+    filter: Filter,
+    /// tick of last trigger
+    trigger_tick: u32,
+    buffer: MinCircularBuffer<Sample, N>,
+    overflow: bool,
+    stats: Stats,
+    health: Monitor,
+    /// sensor REVID, read once at start
+    revid: u8,
+    /// time since power-up(ms)
+    uptime_ms: u64,
+    /// last reset was caused by the watchdog
+    watchdog_reset: bool,
+}
+
+impl<const N: usize> Server<N> {
+    /// measure `axes`, trigger and output in their default config
+    pub fn new(settings: Settings, axes: Axes, revid: u8, watchdog_reset: bool) -> Self {
+        Server {
+            settings,
+            trigger: Trigger::default(),
+            output: Output::default(),
+            axes,
+            filter: Filter::default(),
+            trigger_tick: 0,
+            buffer: MinCircularBuffer::new(Sample::default()),
+            overflow: false,
+            stats: Stats::default(),
+            health: Monitor::new(),
+            revid,
+            uptime_ms: 0,
+            watchdog_reset,
+        }
+    }
+
+    pub fn axes(&self) -> Axes {self.axes}
+
+    /// the filter starts over
+    pub fn set_axes(&mut self, axes: Axes) {
+        self.axes = axes;
+        self.filter.reset();
+    }
+
+    /// ## sensor config changed, past samples do not mix with new ones
+    pub fn reset_filter(&mut self) {
+        self.filter.reset();
+    }
+
+    pub fn stats(&self) -> Stats {self.stats}
+
+    /// the host stopped taking data, or a reply did not fit the TX queue
+    pub fn count_stall(&mut self) {
+        self.stats.usb_stalls = self.stats.usb_stalls.wrapping_add(1);
+    }
+
+    /// pop at most max samples from buffer
+    pub fn pop_samples(&mut self, max: usize) -> Samples {
+        let mut samples = Samples::default();
+        while samples.as_slice().len() < max && !samples.is_full() {
+            match self.buffer.pop() {
+                Some(sample) => {samples.push(sample);},
+                None => break,
+            }
+        }
+        samples
+    }
+
+    pub fn device_info(&mut self, sensor: &mut impl Sensor) -> DeviceInfo {
+        DeviceInfo {
+            protocol_version: protocol::VERSION,
+            firmware_version: firmware_version(),
+            tick_rate: self.settings.tick_rate,
+            revid: self.revid,
+            buffer_size: self.buffer.capacity() as u16,
+            uptime_ms: self.uptime_ms,
+            config: sensor.config(),
+            axes: self.axes,
+        }
+    }
+
+    /// ## average current estimate
+    ///
+    /// sampling continuously at the update rate, or at the timer rate while it runs
+    pub fn power_estimate(&mut self, sensor: &mut impl Sensor, hw: &impl Hardware) -> Estimate {
+        let config = sensor.config();
+        let rate = match hw.timer_rate() {
+            0 => (f32::from(config.rate) * 1000.0) as u32,
+            timer_rate => timer_rate,
+        };
+        let systick_us = 1000 / self.settings.uptime_step_ms * SYSTICK_AWAKE_US;
+        Estimate::new(self.settings.mcu, config.cc, self.axes, rate, READ_OVERHEAD_US, systick_us)
+    }
+
+    /// ## answer one decoded request frame into `tx`
+    ///
+    /// a reply that does not fit is dropped and counted as a stall
+    pub fn request(
+        &mut self,
+        sensor: &mut impl Sensor,
+        hw: &mut impl Hardware,
+        stream: &mut Stream,
+        frame: Result<Frame<'_>, FrameError>,
+        tx: &mut TxQueue,
+    ) {
+        let (cmd, response) = match frame {
+            Err(error) => (error.command, Response::Error(error.status)),
+            Ok(frame) => (
+                frame.command,
+                match Command::decode(&frame) {
+                    Ok(command) => self.handle_command(sensor, hw, stream, command),
+                    Err(status) => Response::Error(status),
+                },
+            ),
+        };
+        let mut outputbuf = [0u8; protocol::MAX_FRAME];
+        let outputlen = response.encode(cmd, &mut outputbuf).unwrap_or(0);
+        // several requests in one packet may still overrun the queue
+        if !tx.push(&outputbuf[..outputlen]) {
+            self.count_stall();
+        }
+    }
+
+    /// ## queue a STREAM_DATA frame of whatever arrived since the last one
+    ///
+    /// with `TxPolicy::Retain` samples wait in the buffer until a frame fits
+    pub fn push_stream(&mut self, stream: &mut Stream, tx: &mut TxQueue, policy: TxPolicy) {
+        if !stream.active || (policy == TxPolicy::Retain && tx.free() < protocol::MAX_FRAME) {
+            return;
+        }
+        let samples = self.pop_samples(protocol::MAX_SAMPLES);
+        if samples.as_slice().is_empty() {
+            return;
+        }
+        let response = Response::Stream {sequence: stream.sequence, samples};
+        stream.sequence = stream.sequence.wrapping_add(1);
+        let mut outputbuf = [0u8; protocol::MAX_FRAME];
+        let outputlen = response.encode(protocol::command::STREAM_DATA, &mut outputbuf).unwrap_or(0);
+        if !tx.push(&outputbuf[..outputlen]) {
+            let count = samples.as_slice().len() as u32;
+            self.stats.dropped = self.stats.dropped.wrapping_add(count);
+        }
+    }
+
+    /// execute one request
+    pub fn handle_command(
+        &mut self, sensor: &mut impl Sensor, hw: &mut impl Hardware, stream: &mut Stream, command: Command
+    ) -> Response {
+        match command {
+            Command::GetInfo => Response::Info(self.device_info(sensor)),
+            Command::GetConfig => Response::Config(sensor.config()),
+            Command::SetConfig(config) => {
+                self.filter.reset();
+                Response::Config(sensor.configure(config))
+            },
+            Command::GetAxes => Response::Axes(self.axes),
+            Command::SetAxes(axes) => {
+                self.set_axes(axes);
+                Response::Axes(axes)
+            },
+            Command::ReadSamples { max } => Response::Samples(self.pop_samples(max as usize)),
+            Command::GetOverflow => Response::Overflow(self.overflow),
+            Command::ClearOverflow => {
+                self.overflow = false;
+                Response::Done
+            },
+            Command::ClearBuffer => {
+                self.buffer.clear();
+                Response::Done
+            },
+            Command::StartStream => {
+                *stream = Stream {active: true, ..Stream::default()};
+                Response::Done
+            },
+            Command::StopStream => {
+                stream.active = false;
+                Response::Done
+            },
+            Command::GetTrigger => Response::Trigger {config: self.trigger.config(), armed: self.trigger.is_armed()},
+            Command::SetTrigger(config) => {
+                let action = self.trigger.configure(config);
+                hw.set_input_edge(config.input_edge());
+                self.stop_gate(sensor, hw, action);
+                Response::Trigger {config, armed: self.trigger.is_armed()}
+            },
+            Command::ArmTrigger => {
+                self.trigger.arm();
+                Response::Done
+            },
+            Command::DisarmTrigger => {
+                let action = self.trigger.disarm();
+                self.stop_gate(sensor, hw, action);
+                Response::Done
+            },
+            Command::StartTimer { rate } => {
+                // one period must hold a whole measurement, rate in mHz
+                let min_period_us = sensor.config().cc.measure_time_us(self.axes) + READ_OVERHEAD_US;
+                if rate as u64 * min_period_us as u64 > 1_000_000_000 {
+                    return Response::Error(StatusCode::RateTooHigh);
+                }
+                match hw.start_timer(rate) {
+                    Some(rate) => Response::Timer {rate},
+                    None => Response::Error(StatusCode::InvalidArgument),
+                }
+            },
+            Command::StopTimer => {
+                hw.stop_timer();
+                Response::Done
+            },
+            Command::GetTimer => Response::Timer {rate: hw.timer_rate()},
+            Command::GetOutput => Response::Output(self.output.config()),
+            Command::SetOutput(config) => {
+                let level = self.output.configure(config);
+                self.drive(hw, level);
+                Response::Output(config)
+            },
+            Command::PulseOutput => match self.output.on_request() {
+                Some(level) => {
+                    self.drive(hw, level);
+                    Response::Done
+                },
+                None => Response::Error(StatusCode::InvalidArgument),
+            },
+            Command::GetStats => Response::Stats(self.stats),
+            Command::GetHealth => Response::Health(Health {
+                fault: self.health.fault(),
+                faults: self.health.faults(),
+                recoveries: self.health.recoveries(),
+                watchdog_reset: self.watchdog_reset,
+            }),
+            Command::GetPower => Response::Power(self.power_estimate(sensor, hw)),
+            // gradiometer firmware only
+            Command::SetGradients(_) => Response::Error(StatusCode::UnknownCommand),
+            Command::ReadRegisters { address, count } => {
+                let mut registers = Registers::new(count as usize);
+                for (offset, value) in registers.as_mut_slice().iter_mut().enumerate() {
+                    *value = sensor.read_register(address + offset as u8);
+                }
+                Response::Registers(registers)
+            },
+            Command::ClearStats => {
+                self.stats = Stats::default();
+                Response::Done
+            },
+            Command::GetFilter => Response::Filter(self.filter.config()),
+            Command::SetFilter(config) => {
+                if !self.filter.configure(config) {
+                    return Response::Error(StatusCode::InvalidArgument);
+                }
+                Response::Filter(config)
+            },
+        }
+    }
+
+    /// ## start one single measurement now, as a timer tick would
+    ///
+    /// false if one is in flight
+    pub fn measure(&mut self, sensor: &mut impl Sensor, hw: &mut impl Hardware) -> bool {
+        if self.trigger.on_timer() == Action::Busy {
+            return false;
+        }
+        let tick = hw.now();
+        self.start(sensor, hw, Action::Measure, tick);
+        true
+    }
+
+    /// start or stop a continuous measurement of the selected axes
+    pub fn set_continuous(&mut self, sensor: &mut impl Sensor, hw: &mut impl Hardware, on: bool) {
+        let tick = hw.now();
+        self.start(sensor, hw, if on {Action::StartContinuous} else {Action::StopContinuous}, tick);
+    }
+
+    /// DRDY, before the sample is read
+    pub fn on_drdy(&mut self, hw: &mut impl Hardware) {
+        let level = self.output.on_drdy();
+        self.drive(hw, level);
+    }
+
+    /// ## filter and push a sample read at drdy_tick, start the next measurement of a burst
+    ///
+    /// samples read while faulty are dropped, the newest one when the buffer
+    /// is full; a decimating filter buffers only its outputs
+    pub fn store_sample(&mut self, sensor: &mut impl Sensor, hw: &mut impl Hardware, mut mag: [i32; 3], drdy_tick: u32) {
+        // axes not measured are reported as 0
+        for (value, measured) in mag.iter_mut().zip([self.axes.x, self.axes.y, self.axes.z]) {
+            if !measured {*value = 0;}
+        }
+        self.stats.samples = self.stats.samples.wrapping_add(1);
+        // continuous (gate) measurements have no trigger of their own
+        if self.trigger.is_busy() {
+            self.stats.record_latency(drdy_tick.wrapping_sub(self.trigger_tick));
+        }
+        if self.health.is_faulty() {
+            // not to be trusted until a check passes
+        } else if let Some(mag) = self.filter.push(mag) {
+            if self.buffer.is_full() {
+                // keep buffered samples, drop the new one
+                self.overflow = true;
+                self.stats.dropped = self.stats.dropped.wrapping_add(1);
+            } else {
+                self.buffer.push(Sample {mag, trigger_tick: self.trigger_tick, drdy_tick});
+            }
+        }
+        // next measurement of a burst
+        if self.trigger.on_drdy() == Action::Measure {
+            let tick = hw.now();
+            self.start(sensor, hw, Action::Measure, tick);
+        }
+    }
+
+    /// trigger input edge at `tick`, `high` the level after it
+    pub fn on_edge(&mut self, sensor: &mut impl Sensor, hw: &mut impl Hardware, high: bool, tick: u32) {
+        let action = self.trigger.on_edge(high);
+        self.count_trigger(action);
+        self.start(sensor, hw, action, tick);
+    }
+
+    /// internal trigger at `tick`
+    pub fn on_timer(&mut self, sensor: &mut impl Sensor, hw: &mut impl Hardware, tick: u32) {
+        let action = self.trigger.on_timer();
+        self.count_trigger(action);
+        self.start(sensor, hw, action, tick);
+    }
+
+    /// ## SysTick: count uptime, check the sensor every `HEALTH_PERIOD_MS`
+    ///
+    /// a new fault re-initializes the sensor and drops the running burst
+    pub fn on_systick(&mut self, sensor: &mut impl Sensor, hw: &mut impl Hardware) {
+        self.uptime_ms += self.settings.uptime_step_ms as u64;
+        if !self.uptime_ms.is_multiple_of(HEALTH_PERIOD_MS as u64) {
+            return;
+        }
+        // twice the estimate, DRDY is late by far then
+        let timeout_us = 2 * sensor.config().cc.measure_time_us(self.axes) + READ_OVERHEAD_US;
+        let (revid_ok, config_ok) = sensor.check(REVID);
+        let check = Check {
+            revid_ok,
+            config_ok,
+            drdy_overdue: self.trigger.is_busy()
+                && hw.now().wrapping_sub(self.trigger_tick) > timeout_us * (self.settings.tick_rate / 1_000_000),
+        };
+        if self.health.on_check(check) {
+            sensor.recover();
+            self.trigger.abort();
+            self.filter.reset();
+            // a continuous measurement runs on
+            if !sensor.is_measuring_continuously() {
+                let level = self.output.on_stop();
+                self.drive(hw, level);
+            }
+        }
+    }
+
+    /// do what the trigger asked for
+    fn start(&mut self, sensor: &mut impl Sensor, hw: &mut impl Hardware, action: Action, tick: u32) {
+        match action {
+            Action::None | Action::Busy => {},
+            Action::Measure => {
+                self.trigger_tick = tick;
+                let level = self.output.on_start();
+                self.drive(hw, level);
+                sensor.start_single(self.axes);
+            },
+            Action::StartContinuous => {
+                self.trigger_tick = tick;
+                let level = self.output.on_start_continuous();
+                self.drive(hw, level);
+                sensor.start_continuous(self.axes);
+            },
+            Action::StopContinuous => self.stop_gate(sensor, hw, action),
+        }
+    }
+
+    /// stop continuous measurement of a closed gate
+    fn stop_gate(&mut self, sensor: &mut impl Sensor, hw: &mut impl Hardware, action: Action) {
+        if action == Action::StopContinuous {
+            sensor.stop_continuous();
+            let level = self.output.on_stop();
+            self.drive(hw, level);
+        }
+    }
+
+    /// count one received trigger, and whether it was ignored
+    fn count_trigger(&mut self, action: Action) {
+        self.stats.triggers = self.stats.triggers.wrapping_add(1);
+        if action == Action::Busy {
+            self.stats.ignored = self.stats.ignored.wrapping_add(1);
+        }
+    }
+
+    fn drive(&self, hw: &mut impl Hardware, level: Level) {
+        hw.drive(level, self.output.config().width_us);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter::FilterConfig;
+    use crate::trigger::TriggerConfig;
+
+    const SETTINGS: Settings = Settings {
+        tick_rate: 48_000_000,
+        uptime_step_ms: UPTIME_STEP_MS,
+        mcu: Mcu {run_ua: 24_000, sleep_ua: 12_000},
+    };
+
+    struct FakeSensor {
+        config: Config,
+        singles: u32,
+        continuous: bool,
+        connected: bool,
+        recoveries: u32,
+    }
+
+    impl FakeSensor {
+        fn new() -> Self {
+            FakeSensor {config: Config::default(), singles: 0, continuous: false, connected: true, recoveries: 0}
+        }
+    }
+
+    impl Sensor for FakeSensor {
+        fn config(&mut self) -> Config {self.config}
+
+        fn configure(&mut self, config: Config) -> Config {
+            self.config = config;
+            config
+        }
+
+        fn start_single(&mut self, _axes: Axes) {
+            self.singles += 1;
+        }
+
+        fn start_continuous(&mut self, _axes: Axes) {
+            self.continuous = true;
+        }
+
+        fn stop_continuous(&mut self) {
+            self.continuous = false;
+        }
+
+        fn is_measuring_continuously(&self) -> bool {self.continuous}
+
+        fn read_register(&mut self, address: u8) -> u8 {address}
+
+        fn check(&mut self, revid: u8) -> (bool, bool) {
+            (self.connected && revid == REVID, self.connected)
+        }
+
+        fn recover(&mut self) {
+            self.recoveries += 1;
+        }
+    }
+
+    #[derive(Default)]
+    struct FakeHardware {
+        now: u32,
+        level: Option<Level>,
+        timer: u32,
+    }
+
+    impl Hardware for FakeHardware {
+        fn now(&mut self) -> u32 {self.now}
+
+        fn drive(&mut self, level: Level, _width_us: u16) {
+            if level != Level::Keep {self.level = Some(level);}
+        }
+
+        fn start_timer(&mut self, rate: u32) -> Option<u32> {
+            self.timer = rate;
+            Some(rate)
+        }
+
+        fn stop_timer(&mut self) {
+            self.timer = 0;
+        }
+
+        fn timer_rate(&self) -> u32 {self.timer}
+
+        fn set_input_edge(&mut self, _edge: trigger::Edge) {}
+    }
+
+    #[test]
+    fn tx_queue_wraps_and_stalls_once() {
+        let mut tx = TxQueue::new();
+        assert!(tx.push(&[1; TX_SIZE - 10]));
+        assert!(!tx.push(&[2; 11]));
+        // the host takes all but 20 bytes, then nothing
+        let mut taken = 0;
+        assert!(tx.flush(|data| {
+            let len = data.len().min(TX_SIZE - 30 - taken);
+            taken += len;
+            Some(len)
+        }));
+        assert!(!tx.flush(|_| Some(0)));
+        assert_eq!(tx.free(), TX_SIZE - 20);
+        // wraps around the end
+        assert!(tx.push(&[3; 40]));
+        let mut received = [0u8; 60];
+        let mut len = 0;
+        assert!(!tx.flush(|data| {
+            received[len..len + data.len()].copy_from_slice(data);
+            len += data.len();
+            Some(data.len())
+        }));
+        assert_eq!((len, received[19], received[20]), (60, 1, 3));
+        // a link that is down is no stall
+        tx.push(&[4; 5]);
+        assert!(!tx.flush(|_| None));
+    }
+
+    #[test]
+    fn bursts_and_overflow() {
+        let (mut sensor, mut hw) = (FakeSensor::new(), FakeHardware::default());
+        let mut server: Server<4> = Server::new(SETTINGS, Axes::X, REVID, false);
+        let mut stream = Stream::default();
+        let config = TriggerConfig {per_trigger: 2, ..TriggerConfig::default()};
+        server.handle_command(&mut sensor, &mut hw, &mut stream, Command::SetTrigger(config));
+        server.on_edge(&mut sensor, &mut hw, true, 100);
+        assert_eq!((sensor.singles, hw.level), (1, Some(Level::High)));
+        // busy until the burst is done
+        server.on_timer(&mut sensor, &mut hw, 150);
+        server.on_drdy(&mut hw);
+        hw.now = 200;
+        server.store_sample(&mut sensor, &mut hw, [1, 2, 3], 200);
+        assert_eq!((sensor.singles, hw.level), (2, Some(Level::High)));
+        server.on_drdy(&mut hw);
+        server.store_sample(&mut sensor, &mut hw, [4, 5, 6], 300);
+        assert_eq!((sensor.singles, hw.level), (2, Some(Level::Low)));
+        let samples = server.pop_samples(protocol::MAX_SAMPLES);
+        assert_eq!(samples.as_slice().len(), 2);
+        // unmeasured axes read 0, the second sample was triggered by the first
+        assert_eq!(samples.as_slice()[0], Sample {mag: [1, 0, 0], trigger_tick: 100, drdy_tick: 200});
+        assert_eq!(samples.as_slice()[1].trigger_tick, 200);
+        for tick in 0..4 {
+            server.store_sample(&mut sensor, &mut hw, [tick, 0, 0], tick as u32);
+        }
+        let stats = server.stats();
+        assert_eq!((stats.samples, stats.dropped, stats.triggers, stats.ignored), (6, 1, 2, 1));
+        assert_eq!(stats.latency_max, 100);
+        let response = server.handle_command(&mut sensor, &mut hw, &mut stream, Command::GetOverflow);
+        assert_eq!(response, Response::Overflow(true));
+        assert_eq!(server.pop_samples(protocol::MAX_SAMPLES).as_slice().len(), 3);
+    }
+
+    #[test]
+    fn stream_frames_by_policy() {
+        let (mut sensor, mut hw) = (FakeSensor::new(), FakeHardware::default());
+        let mut server: Server<4> = Server::new(SETTINGS, Axes::X, REVID, false);
+        let mut stream = Stream::default();
+        let mut tx = TxQueue::new();
+        server.store_sample(&mut sensor, &mut hw, [1, 0, 0], 0);
+        // nothing streams until started
+        server.push_stream(&mut stream, &mut tx, TxPolicy::Retain);
+        assert_eq!(tx.free(), TX_SIZE);
+        server.handle_command(&mut sensor, &mut hw, &mut stream, Command::StartStream);
+        server.push_stream(&mut stream, &mut tx, TxPolicy::Retain);
+        assert_eq!((tx.free(), stream.sequence), (TX_SIZE - protocol::HEADER_LEN - 5 - protocol::SAMPLE_LEN - 2, 1));
+        // a full queue keeps samples buffered, or drops them
+        let filler = [0; TX_SIZE];
+        tx.push(&filler[..tx.free() - 10]);
+        server.store_sample(&mut sensor, &mut hw, [2, 0, 0], 0);
+        server.push_stream(&mut stream, &mut tx, TxPolicy::Retain);
+        assert_eq!((stream.sequence, server.stats().dropped), (1, 0));
+        server.push_stream(&mut stream, &mut tx, TxPolicy::Discard);
+        assert_eq!((stream.sequence, server.stats().dropped), (2, 1));
+    }
+
+    #[test]
+    fn filter_decimates_and_starts_over() {
+        let (mut sensor, mut hw) = (FakeSensor::new(), FakeHardware::default());
+        let mut server: Server<4> = Server::new(SETTINGS, Axes::XYZ, REVID, false);
+        let mut stream = Stream::default();
+        let cic = FilterConfig::Cic {order: 1, decimation: 2};
+        let response = server.handle_command(&mut sensor, &mut hw, &mut stream, Command::SetFilter(cic));
+        assert_eq!(response, Response::Filter(cic));
+        for (tick, value) in [10, 20, 30].into_iter().enumerate() {
+            server.store_sample(&mut sensor, &mut hw, [value, -value, 0], tick as u32);
+        }
+        // every sample is counted, only filter outputs are buffered
+        let samples = server.pop_samples(protocol::MAX_SAMPLES);
+        assert_eq!(samples.as_slice().len(), 1);
+        assert_eq!(samples.as_slice()[0].mag, [15, -15, 0]);
+        assert_eq!(server.stats().samples, 3);
+        // a config change drops the odd sample left in the filter
+        server.handle_command(&mut sensor, &mut hw, &mut stream, Command::SetConfig(Config::default()));
+        server.store_sample(&mut sensor, &mut hw, [40, 0, 0], 3);
+        assert!(server.pop_samples(protocol::MAX_SAMPLES).as_slice().is_empty());
+        server.store_sample(&mut sensor, &mut hw, [60, 0, 0], 4);
+        assert_eq!(server.pop_samples(protocol::MAX_SAMPLES).as_slice()[0].mag, [50, 0, 0]);
+        let response = server.handle_command(&mut sensor, &mut hw, &mut stream, Command::GetFilter);
+        assert_eq!(response, Response::Filter(cic));
+    }
+
+    #[test]
+    fn systick_recovers_once_per_fault() {
+        let (mut sensor, mut hw) = (FakeSensor::new(), FakeHardware::default());
+        let mut server: Server<4> = Server::new(SETTINGS, Axes::X, REVID, false);
+        server.set_continuous(&mut sensor, &mut hw, true);
+        assert_eq!((sensor.continuous, hw.level), (true, Some(Level::High)));
+        sensor.connected = false;
+        for _ in 0..3 * HEALTH_PERIOD_MS / UPTIME_STEP_MS {
+            server.on_systick(&mut sensor, &mut hw);
+        }
+        assert_eq!(sensor.recoveries, 1);
+        // the continuous measurement and its output run on
+        assert_eq!(hw.level, Some(Level::High));
+        // samples of a faulty sensor are not buffered
+        server.store_sample(&mut sensor, &mut hw, [1, 0, 0], 0);
+        assert!(server.pop_samples(1).as_slice().is_empty());
+        let mut stream = Stream::default();
+        let info = server.handle_command(&mut sensor, &mut hw, &mut stream, Command::GetInfo);
+        assert!(matches!(info, Response::Info(DeviceInfo {uptime_ms: 3000, buffer_size: 3, ..})));
+        server.set_continuous(&mut sensor, &mut hw, false);
+        assert_eq!((sensor.continuous, hw.level), (false, Some(Level::Low)));
+    }
+}
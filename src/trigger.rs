@@ -0,0 +1,429 @@
+/// input edge that counts as a trigger
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Edge {
+    Rising = 0,
+    Falling = 1,
+    Both = 2,
+}
+
+impl TryFrom<u8> for Edge {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Edge::Rising),
+            1 => Ok(Edge::Falling),
+            2 => Ok(Edge::Both),
+            _ => Err(value),
+        }
+    }
+}
+
+/// external trigger configuration
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct TriggerConfig {
+    pub edge: Edge,
+    /// single measurements started back to back per accepted edge, at least 1
+    pub per_trigger: u8,
+    /// accept every Kth edge, at least 1
+    pub decimation: u16,
+    /// continuous measurement while input is high, edge/per_trigger/decimation ignored
+    pub gate: bool,
+}
+
+impl Default for TriggerConfig {
+    fn default() -> Self {
+        TriggerConfig { edge: Edge::Rising, per_trigger: 1, decimation: 1, gate: false }
+    }
+}
+
+impl TriggerConfig {
+    pub fn is_valid(&self) -> bool {
+        self.per_trigger > 0 && self.decimation > 0
+    }
+
+    /// edges the input interrupt must listen to
+    pub fn input_edge(&self) -> Edge {
+        if self.gate {Edge::Both} else {self.edge}
+    }
+}
+
+/// what the firmware should do with the sensor
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Action {
+    None,
+    /// start one single measurement
+    Measure,
+    /// gate opened
+    StartContinuous,
+    /// gate closed
+    StopContinuous,
+    /// trigger ignored, a measurement is in flight
+    Busy,
+}
+
+/// ## trigger state machine
+///
+/// hardware independent: feed it input edges and DRDY events,
+/// do what the returned `Action` says
+pub struct Trigger {
+    config: TriggerConfig,
+    armed: bool,
+    /// accepted edges since configured, for decimation
+    edges: u16,
+    /// measurements left in current burst
+    remaining: u8,
+}
+
+impl Default for Trigger {
+    fn default() -> Self {
+        Self::new(TriggerConfig::default())
+    }
+}
+
+impl Trigger {
+    /// new trigger is armed
+    pub fn new(config: TriggerConfig) -> Self {
+        Trigger { config, armed: true, edges: 0, remaining: 0 }
+    }
+
+    pub fn config(&self) -> TriggerConfig {self.config}
+
+    pub fn is_armed(&self) -> bool {self.armed}
+
+    /// measurement of current burst not finished yet
+    pub fn is_busy(&self) -> bool {self.remaining > 0}
+
+    /// ## replace configuration
+    ///
+    /// drop running burst and decimation count,
+    /// return StopContinuous if a gate was open
+    pub fn configure(&mut self, config: TriggerConfig) -> Action {
+        let action = self.stop();
+        self.config = config;
+        action
+    }
+
+    pub fn arm(&mut self) {
+        self.armed = true;
+    }
+
+    /// ignore edges until armed again
+    pub fn disarm(&mut self) -> Action {
+        self.armed = false;
+        self.stop()
+    }
+
+    /// drop the running burst, e.g. after the sensor stopped answering
+    pub fn abort(&mut self) {
+        self.remaining = 0;
+    }
+
+    /// ## input edge
+    ///
+    /// high: input level after the edge
+    pub fn on_edge(&mut self, high: bool) -> Action {
+        if !self.armed {
+            return Action::None;
+        }
+        if self.config.gate {
+            return if high {Action::StartContinuous} else {Action::StopContinuous};
+        }
+        let wanted = match self.config.edge {
+            Edge::Rising => high,
+            Edge::Falling => !high,
+            Edge::Both => true,
+        };
+        if !wanted {
+            return Action::None;
+        }
+        self.edges = (self.edges + 1) % self.config.decimation;
+        if self.edges != 0 {
+            return Action::None;
+        }
+        if self.is_busy() {
+            return Action::Busy;
+        }
+        self.remaining = self.config.per_trigger;
+        Action::Measure
+    }
+
+    /// ## internal timer expired
+    ///
+    /// start one measurement unless one is in flight,
+    /// independent of arming and decimation
+    pub fn on_timer(&mut self) -> Action {
+        if self.is_busy() {
+            return Action::Busy;
+        }
+        self.remaining = 1;
+        Action::Measure
+    }
+
+    /// ## measurement finished (DRDY)
+    ///
+    /// return Measure if burst continues
+    pub fn on_drdy(&mut self) -> Action {
+        self.remaining = self.remaining.saturating_sub(1);
+        if self.remaining > 0 {Action::Measure} else {Action::None}
+    }
+
+    fn stop(&mut self) -> Action {
+        self.edges = 0;
+        self.remaining = 0;
+        if self.config.gate {Action::StopContinuous} else {Action::None}
+    }
+}
+
+/// trigger output (sync pulse) mode
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum OutputMode {
+    /// high from measurement start until DRDY, until stopped for continuous measurement
+    Measuring = 0,
+    /// pulse on every Nth sample
+    Sample = 1,
+    /// pulse only when the host asks
+    Software = 2,
+}
+
+impl TryFrom<u8> for OutputMode {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(OutputMode::Measuring),
+            1 => Ok(OutputMode::Sample),
+            2 => Ok(OutputMode::Software),
+            _ => Err(value),
+        }
+    }
+}
+
+/// trigger output configuration
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct OutputConfig {
+    pub mode: OutputMode,
+    /// pulse every Nth sample in Sample mode, at least 1
+    pub every: u16,
+    /// pulse width(us), at least 1
+    pub width_us: u16,
+}
+
+impl Default for OutputConfig {
+    fn default() -> Self {
+        OutputConfig { mode: OutputMode::Measuring, every: 1, width_us: 10 }
+    }
+}
+
+impl OutputConfig {
+    pub fn is_valid(&self) -> bool {
+        self.every > 0 && self.width_us > 0
+    }
+}
+
+/// what the firmware should do with the output pin
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Level {
+    Keep,
+    High,
+    Low,
+    /// high for `OutputConfig::width_us`, then low
+    Pulse,
+}
+
+/// ## trigger output state machine
+///
+/// feed it measurement start/stop and DRDY events, drive the pin as returned
+pub struct Output {
+    config: OutputConfig,
+    /// samples since configured, for every Nth
+    samples: u16,
+    /// continuous measurement running, DRDY does not end it
+    continuous: bool,
+}
+
+impl Default for Output {
+    fn default() -> Self {
+        Self::new(OutputConfig::default())
+    }
+}
+
+impl Output {
+    pub fn new(config: OutputConfig) -> Self {
+        Output { config, samples: 0, continuous: false }
+    }
+
+    pub fn config(&self) -> OutputConfig {self.config}
+
+    /// replace configuration, pin should go low
+    /// (high while measuring continuously in Measuring mode)
+    pub fn configure(&mut self, config: OutputConfig) -> Level {
+        self.config = config;
+        self.samples = 0;
+        if self.continuous && config.mode == OutputMode::Measuring {Level::High} else {Level::Low}
+    }
+
+    /// single measurement started
+    pub fn on_start(&mut self) -> Level {
+        match self.config.mode {
+            OutputMode::Measuring => Level::High,
+            _ => Level::Keep,
+        }
+    }
+
+    /// continuous measurement started, stays high until `on_stop`
+    pub fn on_start_continuous(&mut self) -> Level {
+        self.continuous = true;
+        self.on_start()
+    }
+
+    /// continuous measurement stopped, or measurements aborted
+    pub fn on_stop(&mut self) -> Level {
+        self.continuous = false;
+        match self.config.mode {
+            OutputMode::Measuring => Level::Low,
+            _ => Level::Keep,
+        }
+    }
+
+    /// sample ready
+    pub fn on_drdy(&mut self) -> Level {
+        match self.config.mode {
+            OutputMode::Measuring if self.continuous => Level::Keep,
+            OutputMode::Measuring => Level::Low,
+            OutputMode::Sample => {
+                self.samples = (self.samples + 1) % self.config.every;
+                if self.samples == 0 {Level::Pulse} else {Level::Keep}
+            },
+            OutputMode::Software => Level::Keep,
+        }
+    }
+
+    /// host asked for a pulse
+    ///
+    /// None if not in Software mode
+    pub fn on_request(&mut self) -> Option<Level> {
+        match self.config.mode {
+            OutputMode::Software => Some(Level::Pulse),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trigger(edge: Edge, per_trigger: u8, decimation: u16) -> Trigger {
+        Trigger::new(TriggerConfig { edge, per_trigger, decimation, gate: false })
+    }
+
+    #[test]
+    fn decimation_counts_wanted_edges() {
+        let mut trigger = trigger(Edge::Rising, 1, 3);
+        let mut actions = [Action::None; 6];
+        for action in actions.iter_mut() {
+            // falling edges do not count
+            assert_eq!(trigger.on_edge(false), Action::None);
+            *action = trigger.on_edge(true);
+            trigger.on_drdy();
+        }
+        assert_eq!(actions, [
+            Action::None, Action::None, Action::Measure,
+            Action::None, Action::None, Action::Measure,
+        ]);
+        // both edges count
+        let mut trigger = self::trigger(Edge::Both, 1, 2);
+        assert_eq!(trigger.on_edge(true), Action::None);
+        assert_eq!(trigger.on_edge(false), Action::Measure);
+        // configure restarts the count
+        let mut trigger = self::trigger(Edge::Falling, 1, 2);
+        assert_eq!(trigger.on_edge(false), Action::None);
+        trigger.configure(trigger.config());
+        assert_eq!(trigger.on_edge(false), Action::None);
+        assert_eq!(trigger.on_edge(false), Action::Measure);
+    }
+
+    #[test]
+    fn burst_continues_on_drdy() {
+        let mut trigger = trigger(Edge::Rising, 3, 1);
+        assert_eq!(trigger.on_edge(true), Action::Measure);
+        assert!(trigger.is_busy());
+        assert_eq!(trigger.on_drdy(), Action::Measure);
+        assert_eq!(trigger.on_drdy(), Action::Measure);
+        assert_eq!(trigger.on_drdy(), Action::None);
+        assert!(!trigger.is_busy());
+        // a DRDY without measurement, e.g. continuous, changes nothing
+        assert_eq!(trigger.on_drdy(), Action::None);
+        assert_eq!(trigger.on_edge(true), Action::Measure);
+        trigger.abort();
+        assert!(!trigger.is_busy());
+    }
+
+    #[test]
+    fn busy_while_in_flight() {
+        let mut trigger = trigger(Edge::Rising, 2, 1);
+        assert_eq!(trigger.on_edge(true), Action::Measure);
+        assert_eq!(trigger.on_edge(true), Action::Busy);
+        assert_eq!(trigger.on_timer(), Action::Busy);
+        assert_eq!(trigger.on_drdy(), Action::Measure);
+        assert_eq!(trigger.on_drdy(), Action::None);
+        assert_eq!(trigger.on_timer(), Action::Measure);
+        assert_eq!(trigger.on_edge(true), Action::Busy);
+        assert_eq!(trigger.on_drdy(), Action::None);
+        // timer runs disarmed, edges do not
+        assert_eq!(trigger.disarm(), Action::None);
+        assert_eq!(trigger.on_edge(true), Action::None);
+        assert_eq!(trigger.on_timer(), Action::Measure);
+        trigger.on_drdy();
+        trigger.arm();
+        assert_eq!(trigger.on_edge(true), Action::Measure);
+    }
+
+    #[test]
+    fn gate_follows_the_input() {
+        let config = TriggerConfig { edge: Edge::Falling, per_trigger: 1, decimation: 4, gate: true };
+        let mut trigger = Trigger::new(config);
+        assert_eq!(config.input_edge(), Edge::Both);
+        // every edge, decimation ignored
+        assert_eq!(trigger.on_edge(true), Action::StartContinuous);
+        assert_eq!(trigger.on_edge(false), Action::StopContinuous);
+        assert_eq!(trigger.on_edge(true), Action::StartContinuous);
+        assert!(!trigger.is_busy());
+        // closing the gate on disarm and reconfiguration
+        assert_eq!(trigger.disarm(), Action::StopContinuous);
+        assert_eq!(trigger.on_edge(true), Action::None);
+        assert_eq!(trigger.on_edge(false), Action::None);
+        trigger.arm();
+        assert_eq!(trigger.on_edge(true), Action::StartContinuous);
+        assert_eq!(trigger.configure(TriggerConfig::default()), Action::StopContinuous);
+        assert_eq!(trigger.on_edge(true), Action::Measure);
+    }
+
+    #[test]
+    fn measuring_output() {
+        let mut output = Output::default();
+        assert_eq!(output.on_start(), Level::High);
+        assert_eq!(output.on_drdy(), Level::Low);
+        // continuous: high until stopped, not until the first DRDY
+        assert_eq!(output.on_start_continuous(), Level::High);
+        assert_eq!(output.on_drdy(), Level::Keep);
+        assert_eq!(output.on_drdy(), Level::Keep);
+        assert_eq!(output.configure(OutputConfig::default()), Level::High);
+        assert_eq!(output.on_stop(), Level::Low);
+        assert_eq!(output.on_start(), Level::High);
+        assert_eq!(output.on_drdy(), Level::Low);
+    }
+
+    #[test]
+    fn sample_and_software_pulses() {
+        let mut output = Output::new(OutputConfig { mode: OutputMode::Sample, every: 3, width_us: 5 });
+        assert_eq!(output.on_start(), Level::Keep);
+        let levels = [(); 6].map(|_| output.on_drdy());
+        assert_eq!(levels, [Level::Keep, Level::Keep, Level::Pulse, Level::Keep, Level::Keep, Level::Pulse]);
+        assert_eq!(output.on_request(), None);
+        assert_eq!(output.configure(OutputConfig { mode: OutputMode::Software, every: 1, width_us: 5 }), Level::Low);
+        assert_eq!(output.on_drdy(), Level::Keep);
+        assert_eq!(output.on_request(), Some(Level::Pulse));
+    }
+}
@@ -0,0 +1,117 @@
+//! vendor-specific USB class carrying the framed protocol
+//!
+//! one interface (class 0xFF) with a bulk OUT and a bulk IN endpoint,
+//! the byte stream on them is the same as on the CDC data interface.
+//! vendor control requests to the interface:
+//!
+//! | request | direction | value | data |
+//! | - | - | - | - |
+//! | `REQUEST_INFO` | in | 0 | `DeviceInfo` payload(`INFO_LEN`) |
+//! | `REQUEST_OPEN` | out | 1 open, 0 close | - |
+//!
+//! the port is closed after bus reset, a host opens it before talking
+//! (the vendor counterpart of DTR)
+
+use usb_device::class_prelude::*;
+use usb_device::Result;
+use crate::protocol::{DeviceInfo, INFO_LEN};
+
+/// interface class: vendor specific
+pub const CLASS_VENDOR: u8 = 0xFF;
+/// bulk packet size(full speed)
+pub const PACKET_SIZE: u16 = 64;
+/// return device info
+pub const REQUEST_INFO: u8 = 0x01;
+/// open/close the port
+pub const REQUEST_OPEN: u8 = 0x02;
+
+pub struct VendorClass<'a, B: UsbBus> {
+    interface: InterfaceNumber,
+    read_ep: EndpointOut<'a, B>,
+    write_ep: EndpointIn<'a, B>,
+    open: bool,
+    /// reply of REQUEST_INFO, kept up to date by the firmware
+    info: [u8; INFO_LEN],
+}
+
+impl<'a, B: UsbBus> VendorClass<'a, B> {
+    pub fn new(alloc: &'a UsbBusAllocator<B>) -> Self {
+        VendorClass {
+            interface: alloc.interface(),
+            read_ep: alloc.bulk(PACKET_SIZE),
+            write_ep: alloc.bulk(PACKET_SIZE),
+            open: false,
+            info: [0; INFO_LEN],
+        }
+    }
+
+    /// host has opened the port
+    pub fn dtr(&self) -> bool {
+        self.open
+    }
+
+    pub fn set_info(&mut self, info: &DeviceInfo) {
+        self.info = info.to_bytes();
+    }
+
+    /// ## write at most one packet
+    ///
+    /// packets are kept short so every transfer ends without a zero length packet,
+    /// WouldBlock if the last packet is not taken yet
+    pub fn write(&mut self, data: &[u8]) -> Result<usize> {
+        let len = data.len().min(PACKET_SIZE as usize - 1);
+        self.write_ep.write(&data[..len])
+    }
+
+    /// ## read one packet
+    ///
+    /// data must hold `PACKET_SIZE` bytes, WouldBlock if nothing arrived
+    pub fn read(&mut self, data: &mut [u8]) -> Result<usize> {
+        self.read_ep.read(data)
+    }
+
+    /// vendor request to our interface
+    fn is_ours(&self, req: &control::Request) -> bool {
+        req.request_type == control::RequestType::Vendor
+            && req.recipient == control::Recipient::Interface
+            && req.index == u8::from(self.interface) as u16
+    }
+}
+
+impl<B: UsbBus> UsbClass<B> for VendorClass<'_, B> {
+    fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> Result<()> {
+        writer.interface(self.interface, CLASS_VENDOR, 0x00, 0x00)?;
+        writer.endpoint(&self.read_ep)?;
+        writer.endpoint(&self.write_ep)?;
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        self.open = false;
+    }
+
+    fn control_in(&mut self, xfer: ControlIn<B>) {
+        let req = *xfer.request();
+        if !self.is_ours(&req) {
+            return;
+        }
+        match req.request {
+            REQUEST_INFO => {xfer.accept_with(&self.info).ok();},
+            _ => {xfer.reject().ok();},
+        }
+    }
+
+    fn control_out(&mut self, xfer: ControlOut<B>) {
+        let req = *xfer.request();
+        if !self.is_ours(&req) {
+            return;
+        }
+        match req.request {
+            REQUEST_OPEN => {
+                self.open = req.value != 0;
+                xfer.accept().ok();
+            },
+            _ => {xfer.reject().ok();},
+        }
+    }
+}